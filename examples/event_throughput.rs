@@ -0,0 +1,75 @@
+//! Measures how fast events are delivered to the application: clients flood the server with small
+//! text messages, and the number of messages received per second is reported.
+//!
+//! Compare `cargo run --release --example event_throughput` with the `lockfree` feature enabled.
+
+extern crate mio_websocket;
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio_websocket::interface::*;
+
+const ADDRESS: &'static str = "127.0.0.1:9010";
+const CLIENTS: usize = 8;
+const MESSAGES_PER_CLIENT: usize = 200000;
+
+fn run_client() {
+    let mut stream = TcpStream::connect(ADDRESS).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\
+                       Host: 127.0.0.1\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+    // Wait for the end of the handshake response.
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.ends_with(b"\r\n\r\n") {
+        let read_bytes = stream.read(&mut buf).unwrap();
+        assert!(read_bytes > 0, "server has closed the connection");
+        response.extend_from_slice(&buf[..read_bytes]);
+    }
+
+    // A masked text frame "ping" with a zero mask, so the payload stays as is.
+    let frame = [0x81, 0x84, 0, 0, 0, 0, b'p', b'i', b'n', b'g'];
+    let mut batch = Vec::new();
+    for _ in 0..1000 {
+        batch.extend_from_slice(&frame);
+    }
+    for _ in 0..MESSAGES_PER_CLIENT / 1000 {
+        stream.write_all(&batch).unwrap();
+    }
+
+    // Keep the connection open until the server has read everything.
+    thread::sleep(Duration::from_secs(60));
+}
+
+fn main() {
+    let clients = env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(CLIENTS);
+    let mut ws = WebSocket::new(ADDRESS.parse::<SocketAddr>().unwrap());
+
+    for _ in 0..clients {
+        thread::spawn(run_client);
+    }
+
+    let total = clients * MESSAGES_PER_CLIENT / 1000 * 1000;
+    let mut received = 0;
+    let mut started = None;
+    while received < total {
+        if let (_, WebSocketEvent::TextMessage(_)) = ws.next() {
+            if started.is_none() {
+                started = Some(Instant::now());
+            }
+            received += 1;
+        }
+    }
+
+    let elapsed = started.unwrap().elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{} messages from {} clients in {:.3} s: {:.0} messages/s", received, clients, secs, received as f64 / secs);
+}
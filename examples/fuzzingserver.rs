@@ -1,4 +1,3 @@
-
 extern crate mio_websocket;
 extern crate env_logger;
 
@@ -6,17 +5,19 @@ use std::net::SocketAddr;
 
 use mio_websocket::interface::*;
 
+/// Echo server for the Autobahn test suite. Run `wstest -m fuzzingclient -s examples/fuzzingclient.json`
+/// against it; `tests/autobahn.rs` does both and checks the results.
 fn main() {
     env_logger::init().unwrap();
-    let mut ws = WebSocket::new("127.0.0.1:9002".parse::<SocketAddr>().unwrap());
+    let mut ws = WebSocketBuilder::new("127.0.0.1:9002".parse::<SocketAddr>().unwrap())
+        .strict()
+        .build();
 
     loop {
+        // Echo back the message that we have received.
         match ws.next() {
-            event @ (_, WebSocketEvent::TextMessage(_)) |
-            event @ (_, WebSocketEvent::BinaryMessage(_)) => {
-                // Echo back the message that we have received.
-                ws.send(event);
-            },
+            (tkn, WebSocketEvent::TextMessage(text)) => ws.send_text(tkn, &text),
+            (tkn, WebSocketEvent::BinaryMessage(data)) => ws.send_binary(tkn, &data),
             _ => {}
         }
     }
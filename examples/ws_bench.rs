@@ -0,0 +1,260 @@
+//! Load generator: opens a number of connections, sends messages of the given size at the given rate
+//! on each of them, and reports the round-trip latency percentiles of the echoed messages.
+//!
+//!     cargo run --release --example ws_bench -- [--connections N] [--rate MESSAGES_PER_SEC] [--size BYTES]
+//!                                               [--duration SECS] [--target HOST:PORT]
+//!
+//! The rate is per connection. Without `--target`, an echo server is started in the process.
+//! Latency is measured from the time a message has been due to be sent, so stalls of the server
+//! aren't hidden by the clients sending less.
+
+extern crate mio_websocket;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio_websocket::interface::*;
+
+const ECHO_ADDRESS: &'static str = "127.0.0.1:9011";
+// How long to wait for an echo before giving up on the rest.
+const ECHO_TIMEOUT_SECS: u64 = 5;
+
+struct Options {
+    connections: usize,
+    rate: u64,
+    size: usize,
+    duration: Duration,
+    target: Option<String>
+}
+
+fn usage() -> ! {
+    println!("usage: ws_bench [--connections N] [--rate MESSAGES_PER_SEC] [--size BYTES] \
+              [--duration SECS] [--target HOST:PORT]");
+    process::exit(1);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        connections: 10,
+        rate: 100,
+        size: 64,
+        duration: Duration::from_secs(10),
+        target: None
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match &arg[..] {
+            "--connections" => options.connections = value.parse().unwrap_or_else(|_| usage()),
+            "--rate" => options.rate = value.parse().unwrap_or_else(|_| usage()),
+            "--size" => options.size = value.parse().unwrap_or_else(|_| usage()),
+            "--duration" => options.duration = Duration::from_secs(value.parse().unwrap_or_else(|_| usage())),
+            "--target" => options.target = Some(value),
+            _ => usage()
+        }
+    }
+    if options.connections == 0 || options.rate == 0 {
+        usage();
+    }
+    options
+}
+
+fn run_echo_server() {
+    let mut ws = WebSocket::new(ECHO_ADDRESS.parse::<SocketAddr>().unwrap());
+    thread::spawn(move || {
+        loop {
+            match ws.next() {
+                (id, WebSocketEvent::TextMessage(text)) => ws.send_text(id, &text),
+                (id, WebSocketEvent::BinaryMessage(data)) => ws.send_binary(id, &data),
+                _ => {}
+            }
+        }
+    });
+}
+
+fn connect(target: &str) -> io::Result<TcpStream> {
+    let mut stream = try!(TcpStream::connect(target));
+    try!(stream.set_nodelay(true));
+    try!(write!(stream, "GET / HTTP/1.1\r\n\
+                         Host: {}\r\n\
+                         Upgrade: websocket\r\n\
+                         Connection: Upgrade\r\n\
+                         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                         Sec-WebSocket-Version: 13\r\n\r\n", target));
+
+    // Read the response byte by byte, so that no frames following it are consumed.
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if try!(stream.read(&mut byte)) == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server has closed the connection"));
+        }
+        response.push(byte[0]);
+    }
+    if !response.starts_with(b"HTTP/1.1 101") {
+        return Err(io::Error::new(io::ErrorKind::Other, String::from_utf8_lossy(&response).into_owned()));
+    }
+    Ok(stream)
+}
+
+/// A masked binary frame with a zero mask, so the payload stays as is.
+fn client_frame(size: usize) -> Vec<u8> {
+    let mut frame = vec![0x82];
+    if size < 126 {
+        frame.push(0x80 | size as u8);
+    } else if size <= 0xFFFF {
+        frame.extend_from_slice(&[0x80 | 126, (size >> 8) as u8, size as u8]);
+    } else {
+        frame.push(0x80 | 127);
+        for i in (0..8).rev() {
+            frame.push((size as u64 >> (i * 8)) as u8);
+        }
+    }
+    frame.extend_from_slice(&[0, 0, 0, 0]);
+    frame.resize(frame.len() + size, b'x');
+    frame
+}
+
+/// Reads a frame sent by the server and returns its opcode.
+fn read_frame(stream: &mut TcpStream) -> io::Result<u8> {
+    let mut header = [0; 2];
+    try!(stream.read_exact(&mut header));
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            try!(stream.read_exact(&mut len));
+            (len[0] as u64) << 8 | len[1] as u64
+        },
+        127 => {
+            let mut len = [0; 8];
+            try!(stream.read_exact(&mut len));
+            len.iter().fold(0, |len, &byte| len << 8 | byte as u64)
+        },
+        len => len as u64
+    };
+    try!(io::copy(&mut (&*stream).take(len), &mut io::sink()));
+    Ok(header[0] & 0x0F)
+}
+
+/// Sends messages at the rate and collects the latencies of their echoes until the deadline.
+fn run_client(target: String, options: Arc<Options>, deadline: Instant) -> io::Result<Vec<Duration>> {
+    let stream = try!(connect(&target));
+    let mut reader = try!(stream.try_clone());
+    try!(reader.set_read_timeout(Some(Duration::from_secs(ECHO_TIMEOUT_SECS))));
+    // Times the messages that haven't been echoed yet have been due to be sent at, in order,
+    // and whether the writer is done.
+    let in_flight = Arc::new(Mutex::new((VecDeque::new(), false)));
+
+    let writer = {
+        let mut stream = stream;
+        let in_flight = in_flight.clone();
+        let interval = Duration::from_secs(1) / options.rate as u32;
+        let frame = client_frame(options.size);
+        thread::spawn(move || -> io::Result<()> {
+            let mut due = Instant::now();
+            while due < deadline {
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+                in_flight.lock().unwrap().0.push_back(due);
+                try!(stream.write_all(&frame));
+                due += interval;
+            }
+            in_flight.lock().unwrap().1 = true;
+            Ok(())
+        })
+    };
+
+    let mut latencies = Vec::new();
+    loop {
+        {
+            let in_flight = in_flight.lock().unwrap();
+            if in_flight.1 && in_flight.0.is_empty() {
+                break;
+            }
+        }
+        match read_frame(&mut reader) {
+            Ok(0x2) => {
+                if let Some(due) = in_flight.lock().unwrap().0.pop_front() {
+                    latencies.push(due.elapsed());
+                }
+            },
+            Ok(_) => {},
+            Err(ref e) if (e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut)
+                          && Instant::now() > deadline => {
+                println!("{} messages haven't been echoed", in_flight.lock().unwrap().0.len());
+                break;
+            },
+            Err(e) => return Err(e)
+        }
+    }
+
+    match writer.join() {
+        Ok(result) => try!(result),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "writer has panicked"))
+    }
+    Ok(latencies)
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1000000 + (duration.subsec_nanos() / 1000) as u64
+}
+
+fn main() {
+    let options = Arc::new(parse_options());
+    let target = match options.target {
+        Some(ref target) => target.clone(),
+        None => {
+            run_echo_server();
+            ECHO_ADDRESS.to_string()
+        }
+    };
+
+    println!("{} connections to {}, {} messages/s of {} bytes each, for {} s",
+             options.connections, target, options.rate, options.size, options.duration.as_secs());
+
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let clients = (0..options.connections).map(|_| {
+        let target = target.clone();
+        let options = options.clone();
+        thread::spawn(move || run_client(target, options, deadline))
+    }).collect::<Vec<_>>();
+
+    let mut latencies = Vec::new();
+    let mut failed = 0;
+    for client in clients {
+        match client.join() {
+            Ok(Ok(client_latencies)) => latencies.extend(client_latencies),
+            Ok(Err(e)) => {
+                println!("connection has failed: {}", e);
+                failed += 1;
+            },
+            Err(_) => failed += 1
+        }
+    }
+
+    if latencies.is_empty() {
+        println!("no messages have been echoed");
+        process::exit(1);
+    }
+    latencies.sort();
+    let elapsed = started.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{} messages echoed in {:.3} s: {:.0} messages/s; {} connections failed",
+             latencies.len(), secs, latencies.len() as f64 / secs, failed);
+    for &(name, percentile) in &[("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9)] {
+        let index = cmp::min((latencies.len() as f64 * percentile / 100.0) as usize, latencies.len() - 1);
+        println!("{:>6}: {} us", name, micros(latencies[index]));
+    }
+    println!("{:>6}: {} us", "max", micros(latencies[latencies.len() - 1]));
+}
@@ -0,0 +1,185 @@
+/// JSON admin endpoint listing live connections and server totals
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::time::UNIX_EPOCH;
+
+use http;
+use mio::Token;
+
+use interface::{ConnectionId, ConnectionInfo, ConnectionStats, ServerStats};
+
+pub const ADMIN_PATH: &'static str = "/_ws/admin";
+// Marks a connection with a DSCP value: `?connection=<id>&value=<dscp>`.
+pub const DSCP_PATH: &'static str = "/_ws/admin/dscp";
+
+/// The subset of JSON the report is made of.
+enum Json {
+    Null,
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>)
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => f.write_str("null"),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref items) => {
+                try!(f.write_char('['));
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        try!(f.write_char(','));
+                    }
+                    try!(write!(f, "{}", item));
+                }
+                f.write_char(']')
+            },
+            Json::Object(ref obj) => {
+                try!(f.write_char('{'));
+                for (i, (key, value)) in obj.iter().enumerate() {
+                    if i > 0 {
+                        try!(f.write_char(','));
+                    }
+                    try!(write_string(f, key));
+                    try!(write!(f, ":{}", value));
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(f.write_char('"'));
+    for c in s.chars() {
+        match c {
+            '"' => try!(f.write_str("\\\"")),
+            '\\' => try!(f.write_str("\\\\")),
+            '\n' => try!(f.write_str("\\n")),
+            '\r' => try!(f.write_str("\\r")),
+            '\t' => try!(f.write_str("\\t")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(f.write_char(c))
+        }
+    }
+    f.write_char('"')
+}
+
+trait ToJson {
+    fn to_json(&self) -> Json;
+}
+
+impl ToJson for u64 {
+    fn to_json(&self) -> Json {
+        Json::Number(*self)
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> Json {
+        Json::String(self.clone())
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> Json {
+        match *self {
+            Some(ref value) => value.to_json(),
+            None => Json::Null
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> Json {
+        Json::Array(self.iter().map(|item| item.to_json()).collect())
+    }
+}
+
+fn stats_json(stats: &ConnectionStats, obj: &mut BTreeMap<String, Json>) {
+    obj.insert("messages_received".to_string(), stats.messages_received.to_json());
+    obj.insert("messages_sent".to_string(), stats.messages_sent.to_json());
+    obj.insert("bytes_received".to_string(), stats.bytes_received.to_json());
+    obj.insert("bytes_sent".to_string(), stats.bytes_sent.to_json());
+    obj.insert("compressed_bytes_received".to_string(), stats.compressed_bytes_received.to_json());
+    obj.insert("uncompressed_bytes_received".to_string(), stats.uncompressed_bytes_received.to_json());
+    obj.insert("compressed_bytes_sent".to_string(), stats.compressed_bytes_sent.to_json());
+    obj.insert("uncompressed_bytes_sent".to_string(), stats.uncompressed_bytes_sent.to_json());
+    if let Some(rtt) = stats.rtt {
+        let rtt_ms = rtt.as_secs() * 1000 + (rtt.subsec_nanos() / 1000000) as u64;
+        obj.insert("rtt_ms".to_string(), rtt_ms.to_json());
+    }
+}
+
+fn connection_json(info: &ConnectionInfo) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("id".to_string(), info.id.to_string().to_json());
+    obj.insert("peer_addr".to_string(), info.peer_addr.to_string().to_json());
+    obj.insert("connected_since".to_string(),
+               info.connected_since.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0).to_json());
+    obj.insert("protocol".to_string(), info.protocol.to_json());
+    obj.insert("extensions".to_string(),
+               info.extensions.iter().map(|ext| ext.to_string()).collect::<Vec<_>>().to_json());
+    obj.insert("session".to_string(), info.session.to_json());
+    obj.insert("affinity".to_string(), info.affinity.to_json());
+    obj.insert("route".to_string(), info.route.to_json());
+    let mut tags = info.tags.iter().cloned().collect::<Vec<_>>();
+    tags.sort();
+    obj.insert("tags".to_string(), tags.to_json());
+    obj.insert("delivered_sequence".to_string(), info.delivered_sequence.to_json());
+    stats_json(&info.stats, &mut obj);
+    Json::Object(obj)
+}
+
+/// Renders the admin report.
+pub fn render(connections: &[ConnectionInfo], totals: &ServerStats) -> String {
+    let mut totals_obj = BTreeMap::new();
+    totals_obj.insert("connections_accepted".to_string(), totals.connections_accepted.to_json());
+    totals_obj.insert("connections_open".to_string(), totals.connections_open.to_json());
+    stats_json(&totals.traffic, &mut totals_obj);
+
+    let mut report = BTreeMap::new();
+    report.insert("connections".to_string(), Json::Array(connections.iter().map(connection_json).collect()));
+    report.insert("totals".to_string(), Json::Object(totals_obj));
+    Json::Object(report).to_string()
+}
+
+/// Checks the admin token passed either as `Authorization: Bearer <token>` or in the `token` query parameter.
+pub fn is_authorized(token: &str, authorization: Option<&str>, url: &str) -> bool {
+    let presented = match authorization {
+        Some(auth) if auth.starts_with("Bearer ") => Some(auth["Bearer ".len()..].trim().to_string()),
+        _ => http::query_param(url, "token")
+    };
+    presented.map(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes())).unwrap_or(false)
+}
+
+/// Compares byte strings in time that depends only on their length, so the time taken
+/// doesn't tell how much of the token has been guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Parses the parameters of a DSCP request.
+pub fn dscp_params(url: &str) -> Option<(ConnectionId, u8)> {
+    let connection = match http::query_param(url, "connection") {
+        Some(connection) => connection,
+        None => return None
+    };
+    // Connection IDs are formatted as `<token>.<generation>`.
+    let mut parts = connection.splitn(2, '.');
+    let id = match (parts.next().and_then(|token| token.parse().ok()), parts.next().and_then(|gen| gen.parse().ok())) {
+        (Some(token), Some(generation)) => ConnectionId::new(Token(token), generation),
+        _ => return None
+    };
+    match http::query_param(url, "value").and_then(|value| value.parse::<u8>().ok()) {
+        Some(dscp) if dscp < 64 => Some((id, dscp)),
+        _ => None
+    }
+}
@@ -0,0 +1,141 @@
+//! Echo and chat server configured with command line flags, for trying out deployments and
+//! interoperability without writing any code. Built with the `bin` feature:
+//!
+//!     cargo run --features bin --bin mio-ws-server -- --port 9001 --mode chat
+//!
+//! Run with `--help` for the list of flags. Set `RUST_LOG=mio_websocket=debug` to see what the server does.
+
+extern crate env_logger;
+extern crate getopts;
+extern crate mio_websocket;
+
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::str::FromStr;
+
+use getopts::{Matches, Options};
+
+use mio_websocket::interface::*;
+
+const CHAT_ROOM: &'static str = "chat";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    // Sends messages back to their senders.
+    Echo,
+    // Sends messages to all connected clients.
+    Chat
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("a", "address", "address to listen on, 0.0.0.0 by default", "ADDRESS");
+    opts.optopt("p", "port", "port to listen on, 9001 by default", "PORT");
+    opts.optopt("m", "mode", "echo (default) or chat", "MODE");
+    opts.optopt("", "protocol", "subprotocol to accept", "NAME");
+    opts.optopt("", "strictness", "strict, lenient (default) or permissive", "PROFILE");
+    opts.optopt("", "max-message-size", "limit of the size of received messages", "BYTES");
+    opts.optopt("", "max-handshake-size", "limit of the size of handshake requests", "BYTES");
+    opts.optopt("", "max-connection-memory", "limit of the memory taken by a connection", "BYTES");
+    opts.optopt("", "max-send-rate", "limit of the bytes per second sent to a connection", "BYTES");
+    opts.optopt("", "max-accept-rate", "limit of the connections accepted per second", "CONNECTIONS");
+    opts.optmulti("", "allow-host", "accept handshakes only with the Host header; can be repeated", "HOST");
+    opts.optopt("", "admin-token", "enable the admin endpoint at /_ws/admin with the token", "TOKEN");
+    opts.optflag("h", "help", "print this help");
+    opts
+}
+
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    process::exit(1);
+}
+
+/// Parses the value of a flag, if it's given.
+fn parse<T: FromStr>(matches: &Matches, name: &str) -> Option<T> {
+    matches.opt_str(name).map(|value| {
+        value.parse().unwrap_or_else(|_| fail(&format!("invalid value of --{}: {}", name, value)))
+    })
+}
+
+fn builder(matches: &Matches) -> WebSocketBuilder {
+    let address = parse::<IpAddr>(matches, "address").unwrap_or_else(|| IpAddr::from_str("0.0.0.0").unwrap());
+    let port = parse(matches, "port").unwrap_or(9001);
+    let mut builder = WebSocketBuilder::new(SocketAddr::new(address, port));
+
+    if let Some(protocol) = matches.opt_str("protocol") {
+        builder = builder.protocol(&protocol);
+    }
+    if let Some(strictness) = matches.opt_str("strictness") {
+        builder = builder.strictness(match &strictness[..] {
+            "strict" => Strictness::Strict,
+            "lenient" => Strictness::Lenient,
+            "permissive" => Strictness::Permissive,
+            _ => fail(&format!("invalid value of --strictness: {}", strictness))
+        });
+    }
+    if let Some(size) = parse(matches, "max-message-size") {
+        builder = builder.max_message_size(size);
+    }
+    if let Some(size) = parse(matches, "max-handshake-size") {
+        builder = builder.max_handshake_size(size);
+    }
+    if let Some(bytes) = parse(matches, "max-connection-memory") {
+        builder = builder.max_connection_memory(bytes);
+    }
+    if let Some(rate) = parse(matches, "max-send-rate") {
+        if rate == 0 {
+            fail("--max-send-rate must be positive");
+        }
+        builder = builder.max_send_rate(rate);
+    }
+    if let Some(rate) = parse(matches, "max-accept-rate") {
+        builder = builder.max_accept_rate(rate);
+    }
+    for host in matches.opt_strs("allow-host") {
+        builder = builder.allow_host(&host);
+    }
+    if let Some(token) = matches.opt_str("admin-token") {
+        builder = builder.admin_endpoint(&token);
+    }
+    builder
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let args: Vec<String> = env::args().collect();
+    let opts = options();
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|e| fail(&e.to_string()));
+    if matches.opt_present("help") {
+        println!("{}", opts.usage("Usage: mio-ws-server [options]"));
+        return;
+    }
+
+    let mode = match matches.opt_str("mode").as_ref().map(|mode| &mode[..]) {
+        None | Some("echo") => Mode::Echo,
+        Some("chat") => Mode::Chat,
+        Some(mode) => fail(&format!("invalid value of --mode: {}", mode))
+    };
+
+    let mut ws = builder(&matches).build();
+    loop {
+        match ws.next() {
+            (id, WebSocketEvent::Connect) => {
+                if mode == Mode::Chat {
+                    ws.join(id, CHAT_ROOM);
+                }
+            },
+            (id, WebSocketEvent::TextMessage(text)) => match mode {
+                Mode::Echo => ws.send_text(id, &text),
+                Mode::Chat => ws.publish(CHAT_ROOM, WebSocketMessage::Text(text))
+            },
+            (id, WebSocketEvent::BinaryMessage(data)) => match mode {
+                Mode::Echo => ws.send_binary(id, &data),
+                Mode::Chat => ws.publish(CHAT_ROOM, WebSocketMessage::Binary(data))
+            },
+            (_, WebSocketEvent::ServerError(error)) => fail(&format!("server has failed: {}", error)),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use channel::{EventSender, Delivery, ReadCredit};
+use config::Config;
+use interface::{ConnectionId, WebSocketEvent, EventFilter, OverflowPolicy};
+
+struct Subscriber {
+    filter: EventFilter,
+    // Pattern of the route the subscriber receives the events of connections from.
+    route: Option<String>,
+    tx: EventSender
+}
+
+/// Delivers events from the event loop to all subscribed receivers.
+pub struct EventBus {
+    subscribers: Vec<Subscriber>,
+    // High- and low-water marks applied to all subscribers.
+    watermarks: Option<(usize, usize)>,
+    capacity: Option<(usize, OverflowPolicy)>
+}
+
+impl EventBus {
+    pub fn new(config: &Config) -> EventBus {
+        let watermarks = match (config.backpressure, config.event_queue) {
+            (Some(watermarks), _) => Some(watermarks),
+            // Blocking on a full queue is implemented by suspending reads until the queue is half empty.
+            (None, Some((capacity, OverflowPolicy::Block))) => Some((capacity, capacity / 2)),
+            _ => None
+        };
+
+        EventBus {
+            subscribers: Vec::new(),
+            watermarks: watermarks,
+            capacity: config.event_queue
+        }
+    }
+
+    /// Subscribes to events of connections. With a route, the subscriber receives the events of connections
+    /// accepted on the route, which then aren't delivered to the subscribers without a route.
+    pub fn subscribe(&mut self, filter: EventFilter, route: Option<String>, mut tx: EventSender) {
+        if let Some((high_water, low_water)) = self.watermarks {
+            tx.set_watermarks(high_water, low_water);
+        }
+        if let Some((capacity, policy)) = self.capacity {
+            tx.set_capacity(capacity, policy);
+        }
+        self.subscribers.push(Subscriber {
+            filter: filter,
+            route: route,
+            tx: tx
+        });
+    }
+
+    /// Delivers an event to all interested subscribers.
+    /// Returns `false` if a subscriber's queue has overflowed and the connection should be closed.
+    pub fn publish(&mut self, id: ConnectionId, event: WebSocketEvent) -> bool {
+        self.publish_charged(id, event, None, None)
+    }
+
+    /// Delivers an event of a connection accepted on the route, charging its payload
+    /// to the connection's read credit while it's queued.
+    pub fn publish_charged(&mut self, id: ConnectionId, event: WebSocketEvent, route: Option<&str>,
+                           credit: Option<&Arc<ReadCredit>>) -> bool {
+        let mut overflow = false;
+
+        // Events of routes without subscribers of their own go to the subscribers without a route.
+        let route = route.and_then(|route| {
+            if self.subscribers.iter().any(|subscriber| subscriber.route.as_ref().map_or(false, |r| r == route)) {
+                Some(route)
+            } else {
+                None
+            }
+        });
+
+        // Subscribers that have dropped their receivers are removed.
+        self.subscribers.retain(|subscriber| {
+            if !subscriber.filter.matches(&event) || subscriber.route.as_ref().map(|r| &r[..]) != route {
+                return true;
+            }
+            match subscriber.tx.send(id, event.clone(), credit) {
+                Delivery::ReceiverDropped => false,
+                Delivery::Overflow => {
+                    overflow = true;
+                    true
+                },
+                Delivery::Queued | Delivery::Dropped => true
+            }
+        });
+
+        !overflow
+    }
+
+    /// Checks whether any of the subscribers lags behind by more than the high-water mark.
+    pub fn is_congested(&self) -> bool {
+        self.subscribers.iter().any(|subscriber| subscriber.tx.is_congested())
+    }
+}
@@ -0,0 +1,385 @@
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvError;
+#[cfg(not(feature = "lockfree"))]
+use std::collections::VecDeque;
+
+#[cfg(feature = "lockfree")]
+use crossbeam::sync::SegQueue;
+
+use interface::{ConnectionId, WebSocketEvent, OverflowPolicy};
+
+/// Outcome of queueing an event.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    Queued,
+    /// The queue is full and the event has been discarded.
+    Dropped,
+    /// The queue is full and the connection that has produced the event should be closed.
+    Overflow,
+    ReceiverDropped
+}
+
+/// Bytes of a connection's messages waiting in the queues. Reading from the connection is suspended
+/// once they reach the high-water mark, until the application takes them down to the low-water mark.
+pub struct ReadCredit {
+    queued: AtomicUsize,
+    high_water: usize,
+    low_water: usize,
+    suspended: AtomicBool
+}
+
+impl ReadCredit {
+    pub fn new(high_water: usize, low_water: usize) -> ReadCredit {
+        ReadCredit {
+            queued: AtomicUsize::new(0),
+            high_water: high_water,
+            low_water: low_water,
+            suspended: AtomicBool::new(false)
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::SeqCst)
+    }
+
+    fn add(&self, bytes: usize) {
+        if self.queued.fetch_add(bytes, Ordering::SeqCst) + bytes >= self.high_water {
+            self.suspended.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns `true` if reading should be resumed.
+    fn remove(&self, bytes: usize) -> bool {
+        let queued = self.queued.fetch_sub(bytes, Ordering::SeqCst) - bytes;
+        queued <= self.low_water && self.suspended.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Size of the payload an event holds.
+fn payload_len(event: &WebSocketEvent) -> usize {
+    match *event {
+        WebSocketEvent::TextMessage(ref text) => text.len(),
+        WebSocketEvent::BinaryMessage(ref data) => data.len(),
+        WebSocketEvent::SharedText(ref text) => text.len(),
+        WebSocketEvent::SharedBinary(ref data) => data.len(),
+        WebSocketEvent::Frame(ref frame) => frame.payload.len(),
+        WebSocketEvent::WithMetadata(_, ref event) => payload_len(event),
+        _ => 0
+    }
+}
+
+/// Queued event along with the read credit of the connection it's charged to.
+type Entry = (ConnectionId, WebSocketEvent, Option<(Arc<ReadCredit>, usize)>);
+
+/// Unbounded MPMC queue of events. With the `lockfree` feature it's a lock-free segmented queue,
+/// so the event loop never waits for the application to release a lock.
+#[cfg(feature = "lockfree")]
+struct Queue(SegQueue<Entry>);
+
+#[cfg(feature = "lockfree")]
+impl Queue {
+    fn new() -> Queue {
+        Queue(SegQueue::new())
+    }
+
+    fn push(&self, entry: Entry) {
+        self.0.push(entry);
+    }
+
+    fn pop(&self) -> Option<Entry> {
+        self.0.try_pop()
+    }
+}
+
+#[cfg(not(feature = "lockfree"))]
+struct Queue(Mutex<VecDeque<Entry>>);
+
+#[cfg(not(feature = "lockfree"))]
+impl Queue {
+    fn new() -> Queue {
+        Queue(Mutex::new(VecDeque::new()))
+    }
+
+    fn push(&self, entry: Entry) {
+        self.0.lock().unwrap().push_back(entry);
+    }
+
+    fn pop(&self) -> Option<Entry> {
+        self.0.lock().unwrap().pop_front()
+    }
+}
+
+struct Shared {
+    events: Queue,
+    // Number of queued events. It's updated before pushing and after popping,
+    // so it never drops below the number of events that can be popped.
+    len: AtomicUsize,
+    // Receiver lags behind; the event loop has stopped reading and waits to be notified
+    // when the queue is drained below the low-water mark.
+    congested: AtomicBool,
+    low_water: AtomicUsize,
+    // The event loop is gone and no more events will be queued.
+    disconnected: AtomicBool,
+    receiver_dropped: AtomicBool,
+    // A connection's messages have been taken down to its low-water mark.
+    credit_returned: AtomicBool,
+    // The receiver is about to sleep, waiting for events.
+    waiting: AtomicBool,
+    lock: Mutex<()>,
+    available: Condvar
+}
+
+impl Shared {
+    fn pop(&self) -> Option<(ConnectionId,WebSocketEvent)> {
+        let (id, event, credit) = match self.events.pop() {
+            Some(entry) => entry,
+            None => return None
+        };
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        if let Some((credit, bytes)) = credit {
+            if credit.remove(bytes) {
+                self.credit_returned.store(true, Ordering::SeqCst);
+            }
+        }
+        Some((id, event))
+    }
+
+    /// Checks whether the queue has just been drained below the low-water mark,
+    /// or a connection's messages have been taken down to its own low-water mark.
+    fn drained(&self) -> bool {
+        if self.credit_returned.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+        let low_water = self.low_water.load(Ordering::SeqCst);
+        if !self.congested.load(Ordering::SeqCst) || self.len.load(Ordering::SeqCst) > low_water {
+            return false;
+        }
+        self.congested.swap(false, Ordering::SeqCst)
+    }
+
+    fn wake(&self) {
+        if self.waiting.load(Ordering::SeqCst) {
+            let _guard = self.lock.lock().unwrap();
+            self.available.notify_all();
+        }
+    }
+
+    /// Blocks until there's an event to pop. Returns `false` if the sender has been dropped.
+    /// An event that's being pushed is counted before it can be popped, so callers should retry popping.
+    fn wait(&self) -> bool {
+        loop {
+            if self.len.load(Ordering::SeqCst) > 0 {
+                return true;
+            }
+            if self.disconnected.load(Ordering::SeqCst) {
+                // Events might have been queued right before the sender was dropped.
+                return self.len.load(Ordering::SeqCst) > 0;
+            }
+
+            let guard = self.lock.lock().unwrap();
+            self.waiting.store(true, Ordering::SeqCst);
+            // Senders check the flag after queueing, so the event can't slip in unnoticed after this check.
+            if self.len.load(Ordering::SeqCst) == 0 && !self.disconnected.load(Ordering::SeqCst) {
+                let _guard = self.available.wait(guard).unwrap();
+            }
+            self.waiting.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Creates a queue of events delivered from the event loop to the application.
+pub fn channel() -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        events: Queue::new(),
+        len: AtomicUsize::new(0),
+        congested: AtomicBool::new(false),
+        low_water: AtomicUsize::new(0),
+        disconnected: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+        credit_returned: AtomicBool::new(false),
+        waiting: AtomicBool::new(false),
+        lock: Mutex::new(()),
+        available: Condvar::new()
+    });
+
+    let tx = EventSender {
+        shared: shared.clone(),
+        capacity: None,
+        high_water: None
+    };
+    (tx, EventReceiver { shared: shared })
+}
+
+/// Event loop side of the queue.
+pub struct EventSender {
+    shared: Arc<Shared>,
+    capacity: Option<(usize, OverflowPolicy)>,
+    high_water: Option<usize>
+}
+
+impl EventSender {
+    /// Enables back-pressure: once the queue grows to `high_water` events it's reported as congested
+    /// until the receiver drains it down to `low_water` events.
+    pub fn set_watermarks(&mut self, high_water: usize, low_water: usize) {
+        self.high_water = Some(high_water);
+        self.shared.low_water.store(low_water, Ordering::SeqCst);
+    }
+
+    /// Limits the queue length. The policy determines what happens to events that don't fit.
+    pub fn set_capacity(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.capacity = Some((capacity, policy));
+    }
+
+    /// Queues an event. With a read credit, the payload is charged to it until the event is received.
+    pub fn send(&self, id: ConnectionId, event: WebSocketEvent, credit: Option<&Arc<ReadCredit>>) -> Delivery {
+        if self.shared.receiver_dropped.load(Ordering::SeqCst) {
+            return Delivery::ReceiverDropped;
+        }
+
+        if let Some((capacity, policy)) = self.capacity {
+            if self.shared.len.load(Ordering::SeqCst) >= capacity {
+                match policy {
+                    // The queue is allowed to grow past its capacity; the event loop stops reading
+                    // as soon as it notices congestion.
+                    OverflowPolicy::Block => {},
+                    OverflowPolicy::DropOldest => {
+                        self.shared.pop();
+                    },
+                    OverflowPolicy::DropNewest => return Delivery::Dropped,
+                    OverflowPolicy::CloseConnection => return Delivery::Overflow
+                }
+            }
+        }
+
+        let credit = match (credit, payload_len(&event)) {
+            (Some(credit), bytes) if bytes > 0 => {
+                credit.add(bytes);
+                Some((credit.clone(), bytes))
+            },
+            _ => None
+        };
+        // Counted before it becomes visible, so a receiver taking it right away can't wrap the length around.
+        self.shared.len.fetch_add(1, Ordering::SeqCst);
+        self.shared.events.push((id, event, credit));
+        self.shared.wake();
+        Delivery::Queued
+    }
+
+    /// Checks whether the receiver lags behind by more than the high-water mark.
+    pub fn is_congested(&self) -> bool {
+        if self.shared.receiver_dropped.load(Ordering::SeqCst) {
+            return false;
+        }
+        if let Some(high_water) = self.high_water {
+            if self.shared.len.load(Ordering::SeqCst) >= high_water {
+                self.shared.congested.store(true, Ordering::SeqCst);
+            }
+        }
+        self.shared.congested.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.shared.disconnected.store(true, Ordering::SeqCst);
+        let _guard = self.shared.lock.lock().unwrap();
+        self.shared.available.notify_all();
+    }
+}
+
+/// Application side of the queue.
+pub struct EventReceiver {
+    shared: Arc<Shared>
+}
+
+impl EventReceiver {
+    /// Blocks until an event is available. The returned flag is set when the queue
+    /// has just been drained below the low-water mark and the event loop should resume reading.
+    pub fn recv(&self) -> Result<((ConnectionId,WebSocketEvent), bool), RecvError> {
+        loop {
+            if !self.shared.wait() {
+                return Err(RecvError);
+            }
+            // The event loop may have dropped the oldest event in the meantime.
+            if let Some(event) = self.shared.pop() {
+                return Ok((event, self.shared.drained()));
+            }
+        }
+    }
+
+    /// Takes an event if there's one queued, without blocking.
+    #[cfg(feature = "testing")]
+    pub fn try_recv(&self) -> Option<(ConnectionId,WebSocketEvent)> {
+        self.shared.pop()
+    }
+
+    /// Blocks until at least one event is available and takes up to `max` queued events at once.
+    pub fn recv_many(&self, max: usize) -> Result<(Vec<(ConnectionId,WebSocketEvent)>, bool), RecvError> {
+        let mut events = Vec::new();
+        loop {
+            if !self.shared.wait() {
+                return Err(RecvError);
+            }
+            while events.len() < max {
+                match self.shared.pop() {
+                    Some(event) => events.push(event),
+                    None => break
+                }
+            }
+            if !events.is_empty() || max == 0 {
+                break;
+            }
+        }
+        Ok((events, self.shared.drained()))
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_dropped.store(true, Ordering::SeqCst);
+        while self.shared.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    use mio::Token;
+
+    use interface::{ConnectionId, WebSocketEvent};
+    use super::channel;
+
+    #[test]
+    fn drain_while_sending() {
+        const EVENTS: usize = 100000;
+        let (mut tx, rx) = channel();
+        tx.set_watermarks(64, 8);
+
+        let sender = thread::spawn(move || {
+            for i in 0..EVENTS {
+                tx.send(ConnectionId::new(Token(i), 0), WebSocketEvent::Ping(Vec::new().into_boxed_slice()), None);
+                tx.is_congested();
+            }
+            tx
+        });
+
+        let mut received = 0;
+        while received < EVENTS {
+            let (events, _) = rx.recv_many(16).unwrap();
+            received += events.len();
+            let len = rx.shared.len.load(Ordering::SeqCst);
+            assert!(len <= EVENTS - received, "queue length {} with {} events received", len, received);
+        }
+        let tx = sender.join().unwrap();
+
+        assert_eq!(rx.shared.len.load(Ordering::SeqCst), 0);
+        // Once everything has been taken, a congested queue must report that reading can resume.
+        if tx.is_congested() {
+            assert!(rx.shared.drained());
+        }
+        assert!(!tx.is_congested());
+    }
+}
@@ -1,190 +1,1344 @@
-use std::collections::HashMap;
-use std::io::Read;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read};
+use std::net::SocketAddr;
 use std::fmt;
-use std::error::Error;
-use std::sync::mpsc;
+use std::mem;
+use std::str;
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::Arc;
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant, SystemTime};
+use std::usize;
 
 use mio::*;
 use mio::tcp::*;
 use http_muncher::Parser;
-use rustc_serialize::base64::{ToBase64, STANDARD};
-use sha1::Sha1;
 use bytes::{Buf, ByteBuf};
 use byteorder::{ByteOrder, BigEndian};
 
-use http::HttpParser;
-use websocket_essentials::{Frame, OpCode, StatusCode, BufferedFrameReader, ParseError};
-use interface::{WebSocketEvent, WebSocketInternalMessage};
+use bus::EventBus;
+use channel::ReadCredit;
+use clock;
+use handshake;
+use http::{self, Headers, HttpParser};
+use config::Config;
+use record::Recorder;
+use throttle::Throttle;
+use transport::Transport;
+use extension::{self, Extension, ExtensionFrame, ExtensionOffer};
+use middleware::{self, Handshake, Rejection};
+use frame::{Frame, OpCode, StatusCode, BufferedFrameReader, ParseError};
+use interface::{WebSocketEvent, WebSocketMessage, ConnectionInfo, ConnectionRecord, WsError,
+                DisconnectReason, ConnectionId, ConnectionStats, RawFrame, CloseCause, Priority, MessageMetadata,
+                Strictness, Heartbeat};
 
-const WEBSOCKET_KEY: &'static [u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Number of unanswered pings remembered for measuring the round-trip time.
+const MAX_PENDING_PINGS: usize = 16;
 
-fn gen_key(key: &str) -> String {
-    let mut m = Sha1::new();
-    let mut buf = [0u8; 20];
+/// Packs RSV flags into the bit positions they occupy in the first byte of a frame header.
+fn rsv_bits(rsv: (bool, bool, bool)) -> u8 {
+    let (rsv1, rsv2, rsv3) = rsv;
+    ((rsv1 as u8) << 6) | ((rsv2 as u8) << 5) | ((rsv3 as u8) << 4)
+}
+
+/// Checks the status code and the reason of a Close frame (RFC 6455, sections 5.5.1 and 7.4).
+fn validate_close_payload(payload: &[u8]) -> Result<(), ClientError> {
+    if payload.is_empty() {
+        return Ok(());
+    }
+    if payload.len() == 1 {
+        return Err(ClientError::ProtocolError("Close frame with a truncated status code".to_string()));
+    }
+    match BigEndian::read_u16(&payload[..2]) {
+        // 1004-1006 and 1015 are reserved and must not be sent; 1012-1014 have been registered since.
+        1000..=1003 | 1007..=1014 | 3000..=4999 => {},
+        code => return Err(ClientError::ProtocolError(format!("invalid close status code {}", code)))
+    }
+    if let Err(e) = str::from_utf8(&payload[2..]) {
+        return Err(ClientError::ProtocolError(format!("Utf8 decode error in close reason: {}", e)));
+    }
+    Ok(())
+}
 
-    m.update(key.as_bytes());
-    m.update(WEBSOCKET_KEY);
+/// Contents of a queued frame.
+enum FrameData {
+    Frame(Frame),
+    // Payloads shared between the recipients of a message. They're copied straight into the output buffer.
+    SharedText(Arc<str>),
+    SharedBinary(Arc<[u8]>),
+    // Frames serialized by the application.
+    Serialized(Vec<u8>)
+}
 
-    m.output(&mut buf);
+impl FrameData {
+    fn len(&self) -> usize {
+        match *self {
+            FrameData::Frame(ref frame) => frame.payload().len(),
+            FrameData::SharedText(ref text) => text.len(),
+            FrameData::SharedBinary(ref data) => data.len(),
+            FrameData::Serialized(ref data) => data.len()
+        }
+    }
+}
 
-    return buf.to_base64(STANDARD);
+/// Binary payload following a sequence number prefix.
+fn prefixed(prefix: String, payload: &[u8]) -> Vec<u8> {
+    let mut data = prefix.into_bytes();
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Writes a frame with a shared payload. The first byte of the header is left for the caller to fill.
+fn write_shared_frame(out_buf: &mut Vec<u8>, payload: &[u8]) {
+    out_buf.push(0);
+    let mut len_buf = [0; 8];
+    if payload.len() < 126 {
+        out_buf.push(payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        out_buf.push(126);
+        BigEndian::write_u16(&mut len_buf, payload.len() as u16);
+        out_buf.extend_from_slice(&len_buf[..2]);
+    } else {
+        out_buf.push(127);
+        BigEndian::write_u64(&mut len_buf, payload.len() as u64);
+        out_buf.extend_from_slice(&len_buf);
+    }
+    out_buf.extend_from_slice(payload);
+}
+
+/// Frame queued for sending.
+struct OutgoingFrame {
+    data: FrameData,
+    // Overrides the first byte of the serialized frame header (FIN, RSV flags and opcode)
+    // for frames that have been transformed by extensions.
+    header: Option<u8>,
+    // Deadline of a message sent with a time-to-live, along with the message to report if it's dropped.
+    expiry: Option<(Instant, WebSocketMessage)>,
+    // Frames of higher priority are written before the frames queued earlier.
+    priority: Priority,
+    // Sequence number of a message sent with sequence numbers enabled.
+    sequence: Option<u64>
+}
+
+impl OutgoingFrame {
+    fn is_close(&self) -> bool {
+        match (self.header, &self.data) {
+            (Some(header), _) => header & 0x0F == OpCode::ConnectionClose.bits(),
+            (None, &FrameData::Frame(ref frame)) => frame.get_opcode() == OpCode::ConnectionClose,
+            (None, _) => false
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        match (self.header, &self.data) {
+            (Some(header), _) => header & 0x08 != 0,
+            (None, &FrameData::Frame(ref frame)) => frame.get_opcode().is_control(),
+            // Frames serialized by the application are counted as data.
+            (None, _) => false
+        }
+    }
+
+    fn shared(data: FrameData, opcode: OpCode) -> OutgoingFrame {
+        OutgoingFrame {
+            data: data,
+            header: Some(0x80 | opcode.bits()),
+            expiry: None,
+            priority: Priority::Normal,
+            sequence: None
+        }
+    }
+
+    /// Close frame. It's written after all frames queued before it, regardless of their priority.
+    fn closing(frame: Frame) -> OutgoingFrame {
+        OutgoingFrame {
+            data: FrameData::Frame(frame),
+            header: None,
+            expiry: None,
+            priority: Priority::Low,
+            sequence: None
+        }
+    }
+}
+
+impl From<Frame> for OutgoingFrame {
+    fn from(frame: Frame) -> OutgoingFrame {
+        OutgoingFrame {
+            data: FrameData::Frame(frame),
+            header: None,
+            expiry: None,
+            priority: Priority::Normal,
+            sequence: None
+        }
+    }
 }
 
 enum ClientState {
     AwaitingHandshake(RefCell<Parser<HttpParser>>),
     HandshakeResponse,
+    // A plain HTTP request with the given method has been received; the server is about to respond to it.
+    HttpRequest(String),
     Connected,
+    // The client has sent a Close frame, but the application hasn't responded to it yet.
+    // We can send frames, but do no reads.
+    CloseReceived,
     // In the closing state we do no reads, but send the queued frames.
     Closing
 }
 
+/// Reasons to fail a connection, each closing it with its own status code.
 enum ClientError {
-    ProtocolError
+    ProtocolError(String),
+    // A text message isn't valid UTF-8.
+    InvalidPayload(String),
+    MessageTooBig(String),
+    // The client doesn't read the messages sent to it and they take up too much memory.
+    PolicyViolation(String),
+    // The server couldn't process a message, e.g. an extension has failed to encode it.
+    InternalError(String)
+}
+
+impl ClientError {
+    /// Status code of the Close frame sent when failing the connection (RFC 6455, section 7.4.1).
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            ClientError::ProtocolError(_) => StatusCode::ProtocolError,
+            ClientError::InvalidPayload(_) => StatusCode::from(1007),
+            ClientError::MessageTooBig(_) => StatusCode::from(1009),
+            ClientError::PolicyViolation(_) => StatusCode::from(1008),
+            ClientError::InternalError(_) => StatusCode::from(1011)
+        }
+    }
+
+    fn into_ws_error(self) -> WsError {
+        match self {
+            ClientError::ProtocolError(reason) | ClientError::InvalidPayload(reason) |
+            ClientError::MessageTooBig(reason) | ClientError::PolicyViolation(reason) |
+            ClientError::InternalError(reason) => WsError::Protocol(reason)
+        }
+    }
 }
 
-pub struct WebSocketClient {
-    pub socket: TcpStream,
+/// Identifies a connection in log messages: its ID, the peer address and the negotiated subprotocol.
+pub struct LogContext {
+    id: ConnectionId,
+    peer_addr: SocketAddr,
+    protocol: Option<String>
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "[{} {}", self.id, self.peer_addr));
+        if let Some(ref protocol) = self.protocol {
+            try!(write!(f, " {}", protocol));
+        }
+        write!(f, "]")
+    }
+}
+
+/// Connection state machine, independent of the stream the connection runs on.
+pub struct WebSocketClient<T: Transport = TcpStream> {
+    pub socket: T,
     pub interest: EventSet,
-    headers: Rc<RefCell<HashMap<String, String>>>,
+    // Reading is suspended by the application; the readable interest is masked while registering.
+    pub paused: bool,
+    // Data frames are delivered to the application as they are.
+    pub raw_mode: bool,
+    // The peer has shut down its side of the connection. Nothing more is read; the pending output is written
+    // before closing the connection, since the peer may still be reading.
+    input_closed: bool,
+    // Data frames are forwarded to another connection by the server instead of being delivered to the application.
+    pub piped: bool,
+    forwarded: Vec<RawFrame>,
+    // Scheduling class of the connection.
+    pub priority: Priority,
+    // Tags attached by the application.
+    pub tags: HashSet<String>,
+    // Writing waits for the server-wide send budget to be renewed; the writable interest is masked while registering.
+    pub egress_blocked: bool,
+    peer_addr: SocketAddr,
+    log: LogContext,
+    connected_since: SystemTime,
+    // Time data has last been received from the peer.
+    last_received: Instant,
+    // Time of the last heartbeat of the client, or of the handshake.
+    last_heartbeat: Instant,
+    // Time of the last write to the socket, or of queueing output while there was nothing to write.
+    last_write_progress: Instant,
+    headers: Rc<RefCell<Headers>>,
+    // Pattern of the matched route and the parameters it has captured.
+    route: Option<(String, HashMap<String, String>)>,
+    // Headers added to the handshake response and attributes attached by the handshake middleware.
+    response_headers: Vec<(String, String)>,
+    attributes: HashMap<String, String>,
+    url: Rc<RefCell<String>>,
+    headers_complete: Rc<Cell<bool>>,
     state: ClientState,
-    outgoing: Vec<Frame>,
+    outgoing: Vec<OutgoingFrame>,
+    // Number of control frames in `outgoing`.
+    queued_control_frames: usize,
+    // A Close frame has been queued; the connection doesn't send another one.
+    close_queued: bool,
     outgoing_bytes: ByteBuf,
-    tx: mpsc::Sender<(Token,WebSocketEvent)>,
-    event_loop_tx: Sender<WebSocketInternalMessage>,
-    token: Token,
-    frame_reader: BufferedFrameReader
+    events: Rc<RefCell<EventBus>>,
+    // Connections whose interest has changed outside of their readiness events, reregistered by the server
+    // once it's done handling the current message or event loop iteration.
+    reregistrations: Rc<RefCell<Vec<ConnectionId>>>,
+    id: ConnectionId,
+    frame_reader: BufferedFrameReader,
+    // A data frame without the FIN bit has been received and the message hasn't been finished yet.
+    fragmented: bool,
+    // Metadata and payload of the fragmented message being received.
+    fragments: Option<(MessageMetadata, Vec<u8>)>,
+    // Frames the client has sent right after the handshake request, before getting the response.
+    pipelined: Vec<u8>,
+    // Bytes of the frame being received, buffered by the frame reader.
+    partial_bytes: usize,
+    config: Rc<Config>,
+    // Extensions negotiated during the handshake, in the order of negotiation.
+    extensions: Vec<Box<Extension>>,
+    // Negotiated extensions as they were reported to the client.
+    accepted_extensions: Vec<ExtensionOffer>,
+    protocol: Option<String>,
+    // Status code of the Close frame sent by the server.
+    close_status: Option<StatusCode>,
+    // The terminal event (Close or Error) has been delivered to the application.
+    terminated: bool,
+    // The first cause of the connection shutdown along with the closing handshake status code.
+    disconnect_reason: Option<(DisconnectReason, Option<StatusCode>)>,
+    // The request has been served as plain HTTP, there's no WebSocket connection to report on.
+    plain_http: bool,
+    // Payloads and send times of pings waiting for pongs, oldest first.
+    pending_pings: VecDeque<(Vec<u8>, Instant)>,
+    throttle: Option<Throttle>,
+    recorder: Option<Recorder>,
+    read_credit: Option<Arc<ReadCredit>>,
+    // Sequence number of the next message, if sequence numbers are enabled.
+    next_sequence: u64,
+    // Highest sequence number in the serialized frames being written, and in the ones that have been written.
+    pending_sequence: Option<u64>,
+    delivered_sequence: Option<u64>,
+    stats: ConnectionStats
 }
 
-impl WebSocketClient {
-    pub fn new(socket: TcpStream, token: Token, server_sink: mpsc::Sender<(Token,WebSocketEvent)>,
-               event_loop_sink: Sender<WebSocketInternalMessage>) -> WebSocketClient {
-        let headers = Rc::new(RefCell::new(HashMap::new()));
+impl<T: Transport> WebSocketClient<T> {
+    pub fn new(socket: T, peer_addr: SocketAddr, id: ConnectionId, events: Rc<RefCell<EventBus>>,
+               reregistrations: Rc<RefCell<Vec<ConnectionId>>>, config: Rc<Config>) -> WebSocketClient<T> {
+        let headers = Rc::new(RefCell::new(Headers::new()));
+        let url = Rc::new(RefCell::new(String::new()));
+        let headers_complete = Rc::new(Cell::new(false));
+        let throttle = config.max_send_rate.map(Throttle::new);
+        let read_credit = config.read_watermarks.map(|(high, low)| Arc::new(ReadCredit::new(high, low)));
+        // Frames that can't fit into the limits are rejected by their header, without buffering them.
+        let max_payload = match (config.max_message_size, config.max_connection_memory) {
+            (Some(message), Some(memory)) => Some(cmp::min(message, memory)),
+            (message, memory) => message.or(memory)
+        };
+        let recorder = config.record_dir.as_ref().and_then(|dir| match Recorder::create(dir, id) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                warn!("[{} {}] can't record traffic: {}", id, peer_addr, e);
+                None
+            }
+        });
 
         WebSocketClient {
             socket: socket,
+            peer_addr: peer_addr,
+            log: LogContext {
+                id: id,
+                peer_addr: peer_addr,
+                protocol: None
+            },
+            connected_since: SystemTime::now(),
+            last_received: clock::now(),
+            last_heartbeat: clock::now(),
+            last_write_progress: clock::now(),
             headers: headers.clone(),
+            route: None,
+            response_headers: Vec::new(),
+            attributes: HashMap::new(),
+            url: url.clone(),
+            headers_complete: headers_complete.clone(),
             interest: EventSet::readable(),
+            paused: false,
+            raw_mode: false,
+            input_closed: false,
+            piped: false,
+            forwarded: Vec::new(),
+            priority: Priority::Normal,
+            tags: HashSet::new(),
+            egress_blocked: false,
             state: ClientState::AwaitingHandshake(RefCell::new(Parser::request(HttpParser {
                 current_key: None,
-                headers: headers.clone()
+                value_started: false,
+                headers: headers.clone(),
+                url: url,
+                headers_complete: headers_complete
             }))),
             outgoing: Vec::new(),
+            queued_control_frames: 0,
+            close_queued: false,
             outgoing_bytes: ByteBuf::none(),
-            tx: server_sink,
-            event_loop_tx: event_loop_sink,
-            token: token,
-            frame_reader: BufferedFrameReader::new()
+            events: events,
+            reregistrations: reregistrations,
+            id: id,
+            frame_reader: BufferedFrameReader::new(max_payload),
+            fragmented: false,
+            fragments: None,
+            pipelined: Vec::new(),
+            partial_bytes: 0,
+            config: config,
+            extensions: Vec::new(),
+            accepted_extensions: Vec::new(),
+            protocol: None,
+            close_status: None,
+            terminated: false,
+            disconnect_reason: None,
+            plain_http: false,
+            pending_pings: VecDeque::new(),
+            throttle: throttle,
+            recorder: recorder,
+            read_credit: read_credit,
+            next_sequence: 1,
+            pending_sequence: None,
+            delivered_sequence: None,
+            stats: ConnectionStats::default()
+        }
+    }
+
+    /// Checks whether the application lags behind on the connection's messages.
+    fn is_read_suspended(&self) -> bool {
+        self.read_credit.as_ref().map(|credit| credit.is_suspended()).unwrap_or(false)
+    }
+
+    /// Interest to register the socket with, taking into account whether reading is paused.
+    pub fn effective_interest(&self) -> EventSet {
+        let mut interest = self.interest;
+        if self.paused || self.is_read_suspended() {
+            interest.remove(EventSet::readable());
+        }
+        if self.egress_blocked || self.throttle_delay_ms().is_some() {
+            interest.remove(EventSet::writable());
+        }
+        interest
+    }
+
+    /// Time to wait before writing more, if the connection has used up its send rate and has more to write.
+    pub fn throttle_delay_ms(&self) -> Option<u64> {
+        match self.throttle {
+            Some(ref throttle) if throttle.allowance() == 0 && self.has_pending_writes() => Some(throttle.delay_ms()),
+            _ => None
+        }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn last_received(&self) -> Instant {
+        self.last_received
+    }
+
+    /// The connection is done and the client can be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.interest.is_hup()
+    }
+
+    pub fn log_context(&self) -> &LogContext {
+        &self.log
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            peer_addr: self.peer_addr,
+            connected_since: self.connected_since,
+            protocol: self.protocol.clone(),
+            extensions: self.accepted_extensions.clone(),
+            session: self.session_token(),
+            affinity: self.affinity(),
+            route: self.route.as_ref().map(|&(ref pattern, _)| pattern.clone()),
+            route_params: self.route.as_ref().map(|&(_, ref params)| params.clone()).unwrap_or(HashMap::new()),
+            query: http::query_params(&self.url.borrow()),
+            attributes: self.attributes.clone(),
+            tags: self.tags.clone(),
+            delivered_sequence: self.delivered_sequence,
+            stats: self.stats
+        }
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    /// Returns the method and the URL of a plain HTTP request waiting for a response.
+    pub fn http_request(&self) -> Option<(String, String)> {
+        match self.state {
+            ClientState::HttpRequest(ref method) => Some((method.clone(), self.url.borrow().clone())),
+            _ => None
+        }
+    }
+
+    /// Returns the value of a request header. Names are case-insensitive; repeated headers are combined
+    /// into a comma-separated list.
+    pub fn request_header(&self, name: &str) -> Option<String> {
+        self.headers.borrow().get(name).map(|value| value.to_string())
+    }
+
+    /// Returns all values of a repeated request header.
+    pub fn request_header_values(&self, name: &str) -> Vec<String> {
+        self.headers.borrow().get_all(name).to_vec()
+    }
+
+    /// Responds to a plain HTTP request and closes the connection.
+    pub fn respond_http(&mut self, status: &str, content_type: &str, body: &[u8]) {
+        self.plain_http = true;
+        self.terminated = true;
+        self.queue_http_response(status, content_type, body);
+    }
+
+    /// Tells a client that has sent a plain HTTP request that only WebSocket connections are served.
+    pub fn require_upgrade(&mut self) {
+        self.reject_handshake("426 Upgrade Required", "This server only accepts WebSocket connections");
+    }
+
+    /// Refuses to upgrade the connection, responding with an HTTP error.
+    fn reject_handshake(&mut self, status: &str, reason: &str) {
+        let err = WsError::Handshake(reason.to_string());
+        error!("{} {}", self.log, err);
+        self.set_disconnect_reason(DisconnectReason::ProtocolError, None);
+        self.terminate(WebSocketEvent::Error(err));
+        self.queue_http_response(status, "text/plain", reason.as_bytes());
+    }
+
+    /// Sends an HTTP response and closes the connection once it has been written.
+    fn queue_http_response(&mut self, status: &str, content_type: &str, body: &[u8]) {
+        // 426 responses have to tell which protocols the server can switch to.
+        let upgrade = if status.starts_with("426") { "Upgrade: websocket\r\n" } else { "" };
+        let mut response = format!("HTTP/1.1 {}\r\n\
+                                    {}\
+                                    Content-Type: {}\r\n\
+                                    Content-Length: {}\r\n\
+                                    Connection: close\r\n\r\n", status, upgrade, content_type, body.len()).into_bytes();
+        response.extend_from_slice(body);
+
+        self.outgoing_bytes = ByteBuf::from_slice(&response);
+        self.state = ClientState::Closing;
+        self.interest.remove(EventSet::readable());
+        self.interest.insert(EventSet::writable());
+    }
+
+    /// The handshake has been completed and the connection is open.
+    pub fn is_connected(&self) -> bool {
+        match self.state {
+            ClientState::Connected => true,
+            _ => false
+        }
+    }
+
+    /// The handshake hasn't been completed yet: the request is being received or responded to.
+    pub fn is_handshaking(&self) -> bool {
+        match self.state {
+            ClientState::AwaitingHandshake(_) | ClientState::HandshakeResponse | ClientState::HttpRequest(_) => true,
+            _ => false
+        }
+    }
+
+    /// The connection is writing its last frames before being closed.
+    pub fn is_closing(&self) -> bool {
+        match self.state {
+            ClientState::Closing => true,
+            _ => false
+        }
+    }
+
+    /// Session token presented in the `session` query parameter of the request URL, if sessions are enabled.
+    pub fn session_token(&self) -> Option<String> {
+        if self.config.sessions.is_none() {
+            return None;
+        }
+        http::query_param(&self.url.borrow(), "session")
+    }
+
+    /// ID of the server instance presented by the client in the affinity header or the `instance` query parameter,
+    /// if session affinity is enabled.
+    pub fn affinity(&self) -> Option<String> {
+        match self.config.affinity {
+            Some((ref header, _)) => self.request_header(header)
+                .map(|id| id.trim().to_string())
+                .or_else(|| http::query_param(&self.url.borrow(), "instance")),
+            None => None
         }
     }
 
     fn notify(&mut self, msg: WebSocketEvent) {
-        self.tx.send((self.token, msg));
+        let route = self.route.as_ref().map(|&(ref pattern, _)| &pattern[..]);
+        let delivered = self.events.borrow_mut().publish_charged(self.id, msg, route, self.read_credit.as_ref());
+        if !delivered {
+            if let ClientState::Closing = self.state {
+                return;
+            }
+            error!("{} events queue overflow, closing connection", self.log);
+            self.close_for(CloseCause::Overload);
+        }
+    }
+
+    /// Delivers the terminal event of the connection unless one has already been delivered.
+    fn terminate(&mut self, event: WebSocketEvent) {
+        if !self.terminated {
+            self.terminated = true;
+            self.notify(event);
+        }
+    }
+
+    /// Records why the connection is going away. Only the first reason is kept.
+    fn set_disconnect_reason(&mut self, reason: DisconnectReason, code: Option<StatusCode>) {
+        if self.disconnect_reason.is_none() {
+            self.disconnect_reason = Some((reason, code));
+        }
     }
 
-    pub fn send_message(&mut self, msg: WebSocketEvent) -> Result<(), String> {
-        let frame = match msg {
-            WebSocketEvent::TextMessage(data) => Some(Frame::from(data)),
-            WebSocketEvent::BinaryMessage(data) => Some(Frame::from(data)),
-            WebSocketEvent::Close(status_code) => {
+    /// Starts closing the connection after a protocol violation.
+    fn fail_with_status(&mut self, status: StatusCode, err: WsError) {
+        error!("{} {}", self.log, err);
+        self.set_disconnect_reason(DisconnectReason::ProtocolError, Some(status.clone()));
+        self.terminate(WebSocketEvent::Error(err));
+        self.close_with_status(status);
+    }
+
+    /// Fails the connection, closing it with the status code matching the error.
+    fn fail_with_error(&mut self, err: ClientError) {
+        let status = err.status_code();
+        self.fail_with_status(status, err.into_ws_error());
+    }
+
+    /// Drops the connection immediately.
+    fn fail(&mut self, err: WsError) {
+        error!("{} {}", self.log, err);
+        self.set_disconnect_reason(DisconnectReason::Reset, None);
+        self.terminate(WebSocketEvent::Error(err));
+        self.interest.remove(EventSet::readable());
+        self.interest.remove(EventSet::writable());
+        self.interest.insert(EventSet::hup());
+    }
+
+    fn connection_record(&self, reason: DisconnectReason, code: Option<StatusCode>) -> ConnectionRecord {
+        ConnectionRecord {
+            id: self.id,
+            peer_addr: self.peer_addr,
+            connected_since: self.connected_since,
+            duration: SystemTime::now().duration_since(self.connected_since).unwrap_or(Duration::from_secs(0)),
+            route: self.route.as_ref().map(|&(ref pattern, _)| pattern.clone()),
+            reason: reason,
+            code: code,
+            stats: self.stats
+        }
+    }
+
+    /// Called by the server when the connection is torn down.
+    pub fn on_disconnect(&mut self) {
+        if self.plain_http {
+            return;
+        }
+
+        let event = match self.close_status.take() {
+            Some(status) => WebSocketEvent::Close(status),
+            None => WebSocketEvent::Error(WsError::Io(io::ErrorKind::ConnectionAborted,
+                                                      "connection closed without the closing handshake".to_string()))
+        };
+        self.terminate(event);
+
+        let (reason, code) = self.disconnect_reason.take().unwrap_or((DisconnectReason::Reset, None));
+        if let Some(ref audit_log) = self.config.audit_log {
+            audit_log(self.connection_record(reason, code.clone()));
+        }
+        self.notify(WebSocketEvent::Disconnected {
+            reason: reason,
+            code: code
+        });
+    }
+
+    pub fn send_message(&mut self, msg: WebSocketMessage) -> Result<(), String> {
+        self.queue_message(msg, None, Priority::Normal)
+    }
+
+    /// Sends a message that is dropped if it can't be written before the deadline.
+    pub fn send_message_expiring(&mut self, msg: WebSocketMessage, deadline: Instant) -> Result<(), String> {
+        self.queue_message(msg, Some(deadline), Priority::Normal)
+    }
+
+    /// Sends a message ahead of or after the frames of other priorities.
+    pub fn send_message_prioritized(&mut self, msg: WebSocketMessage, priority: Priority) -> Result<(), String> {
+        self.queue_message(msg, None, priority)
+    }
+
+    fn queue_message(&mut self, msg: WebSocketMessage, deadline: Option<Instant>,
+                     priority: Priority) -> Result<(), String> {
+        let expiry = deadline.map(|deadline| (deadline, msg.clone()));
+        let (msg, sequence) = self.number_message(msg);
+        if !self.has_pending_writes() {
+            self.last_write_progress = clock::now();
+        }
+        let mut frame = match msg {
+            WebSocketMessage::Text(data) => {
+                self.stats.messages_sent += 1;
+                if self.extensions.is_empty() {
+                    OutgoingFrame::from(Frame::from(data))
+                } else {
+                    try!(self.encode_or_fail(OpCode::TextFrame, data.into_bytes()))
+                }
+            },
+            WebSocketMessage::Binary(data) => {
+                self.stats.messages_sent += 1;
+                if self.extensions.is_empty() {
+                    OutgoingFrame::from(Frame::from(data))
+                } else {
+                    try!(self.encode_or_fail(OpCode::BinaryFrame, data))
+                }
+            },
+            WebSocketMessage::SharedText(text) => {
+                self.stats.messages_sent += 1;
+                if self.extensions.is_empty() {
+                    OutgoingFrame::shared(FrameData::SharedText(text), OpCode::TextFrame)
+                } else {
+                    try!(self.encode_or_fail(OpCode::TextFrame, text.as_bytes().to_vec()))
+                }
+            },
+            WebSocketMessage::SharedBinary(data) => {
+                self.stats.messages_sent += 1;
+                if self.extensions.is_empty() {
+                    OutgoingFrame::shared(FrameData::SharedBinary(data), OpCode::BinaryFrame)
+                } else {
+                    try!(self.encode_or_fail(OpCode::BinaryFrame, data.to_vec()))
+                }
+            },
+            WebSocketMessage::Serialized(data) => OutgoingFrame {
+                data: FrameData::Serialized(data),
+                header: None,
+                expiry: None,
+                priority: Priority::Normal,
+                sequence: None
+            },
+            WebSocketMessage::Close(status_code) => {
                 // Change the client's state to "closing" if we've received a "Close" message.
                 self.state = ClientState::Closing;
-                Some(Frame::close(status_code))
+                self.close_status = Some(status_code.clone());
+                self.set_disconnect_reason(DisconnectReason::ServerInitiated, Some(status_code.clone()));
+                OutgoingFrame::closing(Frame::close(status_code))
             },
-            WebSocketEvent::Ping(ref payload) => Some(Frame::ping(&*payload)),
-            _ => None
+            WebSocketMessage::Ping(ref payload) => {
+                if self.pending_pings.len() >= MAX_PENDING_PINGS {
+                    self.pending_pings.pop_front();
+                }
+                self.pending_pings.push_back((payload.to_vec(), clock::now()));
+                OutgoingFrame::from(Frame::ping(&*payload))
+            },
+            WebSocketMessage::Frame(raw) => {
+                let header = ((raw.fin as u8) << 7) | rsv_bits(raw.rsv) | raw.opcode.bits();
+                OutgoingFrame {
+                    data: FrameData::Frame(Frame::from(raw.payload)),
+                    header: Some(header),
+                    expiry: None,
+                    priority: Priority::Normal,
+                    sequence: None
+                }
+            }
+        };
+
+        frame.expiry = expiry;
+        frame.sequence = sequence;
+        if frame.priority != Priority::Low {
+            // Close frames keep their place at the end.
+            frame.priority = priority;
+        }
+        self.push_outgoing(frame);
+
+        if let Some(limit) = self.config.max_connection_memory {
+            if self.buffered_bytes() > limit {
+                // Nothing queued is going to be sent anyway.
+                self.clear_outgoing();
+                self.fail_with_error(ClientError::PolicyViolation(
+                    format!("queued messages take more than {} bytes", limit)));
+                self.schedule_write();
+                return Err("connection memory limit exceeded".to_string());
+            }
+        }
+        if self.check_queued_frames() {
+            self.schedule_write();
+            return Err("queued frames limit exceeded".to_string());
+        }
+        self.schedule_write();
+        Ok(())
+    }
+
+    fn push_outgoing(&mut self, frame: OutgoingFrame) {
+        if frame.is_control() {
+            self.queued_control_frames += 1;
+        }
+        if frame.is_close() {
+            self.close_queued = true;
+        }
+        self.outgoing.push(frame);
+    }
+
+    fn clear_outgoing(&mut self) {
+        // A discarded Close frame has to be queued again.
+        if self.outgoing.iter().any(|frame| frame.is_close()) {
+            self.close_queued = false;
+        }
+        self.outgoing.clear();
+        self.queued_control_frames = 0;
+    }
+
+    /// Fails the connection with status 1008 (Policy Violation), discarding the queued frames,
+    /// if it has more of them queued than allowed. Returns `true` if it's been failed.
+    fn check_queued_frames(&mut self) -> bool {
+        let (max_data, max_control) = match self.config.max_queued_frames {
+            Some(limits) => limits,
+            None => return false
         };
+        let reason = if self.outgoing.len() - self.queued_control_frames > max_data {
+            format!("more than {} data frames queued", max_data)
+        } else if self.queued_control_frames > max_control {
+            format!("more than {} control frames queued", max_control)
+        } else {
+            return false;
+        };
+        self.clear_outgoing();
+        self.fail_with_error(ClientError::PolicyViolation(reason));
+        true
+    }
 
-        if frame.is_none() {
-            return Err("Wrong message type to send".to_string());
+    /// Prefixes a text or binary message with its sequence number if sequence numbers are enabled.
+    fn number_message(&mut self, msg: WebSocketMessage) -> (WebSocketMessage, Option<u64>) {
+        if !self.config.sequence_numbers {
+            return (msg, None);
         }
+        let prefix = format!("{}:", self.next_sequence);
+        let msg = match msg {
+            WebSocketMessage::Text(text) => WebSocketMessage::Text(prefix + &text),
+            WebSocketMessage::SharedText(text) => WebSocketMessage::Text(prefix + &text),
+            WebSocketMessage::Binary(data) => WebSocketMessage::Binary(prefixed(prefix, &data)),
+            WebSocketMessage::SharedBinary(data) => WebSocketMessage::Binary(prefixed(prefix, &data)),
+            msg => return (msg, None)
+        };
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        (msg, Some(sequence))
+    }
+
+    /// Sequence number the next message is going to get. Used to carry the numbering over to a resumed session.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
 
-        self.outgoing.push(frame.unwrap());
+    pub fn set_next_sequence(&mut self, sequence: u64) {
+        self.next_sequence = sequence;
+    }
 
+    /// Switches the connection to writing the outgoing frames.
+    fn schedule_write(&mut self) {
         if self.interest.is_readable() {
-            trace!("{:?} sending {} frames, switching to write", self.token, self.outgoing.len());
+            trace!("{} sending {} frames, switching to write", self.log, self.outgoing.len());
 
             self.interest.insert(EventSet::writable());
             self.interest.remove(EventSet::readable());
 
-            try!(self.event_loop_tx.send(WebSocketInternalMessage::Reregister(self.token))
-                 .map_err(|e| e.description().to_string()));
+            self.reregistrations.borrow_mut().push(self.id);
         }
+    }
+
+    /// Encodes a data frame, failing the connection if an extension can't encode it.
+    fn encode_or_fail(&mut self, opcode: OpCode, payload: Vec<u8>) -> Result<OutgoingFrame, String> {
+        match self.encode_frame(opcode, payload) {
+            Ok(frame) => Ok(frame),
+            Err(e) => {
+                let reason = format!("failed to encode a frame: {}", e);
+                self.fail_with_error(ClientError::InternalError(reason.clone()));
+                // Send the Close frame queued by failing the connection.
+                self.schedule_write();
+                Err(reason)
+            }
+        }
+    }
+
+    /// Passes a data frame through the negotiated extensions.
+    fn encode_frame(&mut self, opcode: OpCode, payload: Vec<u8>) -> Result<OutgoingFrame, String> {
+        self.stats.uncompressed_bytes_sent += payload.len() as u64;
+        let mut ext_frame = ExtensionFrame {
+            opcode: opcode,
+            rsv: (false, false, false),
+            payload: payload
+        };
+
+        for ext in self.extensions.iter_mut() {
+            try!(ext.encode(&mut ext_frame));
+        }
+
+        self.stats.compressed_bytes_sent += ext_frame.payload.len() as u64;
+        let header = 0x80 | rsv_bits(ext_frame.rsv) | ext_frame.opcode.bits();
+
+        Ok(OutgoingFrame {
+            data: FrameData::Frame(Frame::from(ext_frame.payload)),
+            header: Some(header),
+            expiry: None,
+            priority: Priority::Normal,
+            sequence: None
+        })
+    }
 
+    /// Restores the original payload of a data frame transformed by the negotiated extensions.
+    /// Delivers a complete message to the application.
+    fn deliver_message(&mut self, metadata: MessageMetadata, payload: Vec<u8>) -> Result<(), ClientError> {
+        let payload = try!(self.decode_payload(metadata.opcode, metadata.rsv, payload));
+        self.stats.messages_received += 1;
+        let event = match metadata.opcode {
+            OpCode::TextFrame => {
+                let text = match String::from_utf8(payload) {
+                    Ok(text) => text,
+                    Err(e) => if self.config.strictness == Strictness::Permissive {
+                        String::from_utf8_lossy(e.as_bytes()).into_owned()
+                    } else {
+                        // Couldn't decode UTF-8, close the connection
+                        return Err(ClientError::InvalidPayload(format!("Utf8 decode error: {}", e)));
+                    }
+                };
+                if let Some((_, Heartbeat::Message(ref heartbeat))) = self.config.heartbeat {
+                    if text == *heartbeat {
+                        self.last_heartbeat = clock::now();
+                    }
+                }
+                if self.config.shared_payloads {
+                    WebSocketEvent::SharedText(Arc::from(text))
+                } else {
+                    WebSocketEvent::TextMessage(text)
+                }
+            },
+            _ => {
+                if self.config.shared_payloads {
+                    WebSocketEvent::SharedBinary(Arc::from(payload))
+                } else {
+                    WebSocketEvent::BinaryMessage(payload)
+                }
+            }
+        };
+        if self.config.message_metadata {
+            self.notify(WebSocketEvent::WithMetadata(metadata, Box::new(event)));
+        } else {
+            self.notify(event);
+        }
         Ok(())
     }
 
+    /// Passes the payload of a message through the negotiated extensions.
+    fn decode_payload(&mut self, opcode: OpCode, rsv: (bool, bool, bool), payload: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+        if self.extensions.is_empty() {
+            return Ok(payload);
+        }
+
+        self.stats.compressed_bytes_received += payload.len() as u64;
+        let mut ext_frame = ExtensionFrame {
+            opcode: opcode,
+            rsv: rsv,
+            payload: payload
+        };
+
+        for ext in self.extensions.iter_mut().rev() {
+            if let Err(e) = ext.decode(&mut ext_frame) {
+                return Err(ClientError::ProtocolError(format!("extension {} failed to decode a frame: {}",
+                                                              ext.name(), e)));
+            }
+        }
+
+        self.stats.uncompressed_bytes_received += ext_frame.payload.len() as u64;
+        // Extensions may inflate the payload well beyond the size of the frame.
+        try!(self.check_message_size(ext_frame.payload.len()));
+        Ok(ext_frame.payload)
+    }
+
+    fn check_message_size(&self, size: usize) -> Result<(), ClientError> {
+        match self.config.max_message_size {
+            Some(max_size) if size > max_size =>
+                Err(ClientError::MessageTooBig(format!("message of {} bytes exceeds the limit of {} bytes", size, max_size))),
+            _ => Ok(())
+        }
+    }
+
+    /// RSV bits claimed by the negotiated extensions.
+    fn allowed_rsv_bits(&self) -> u8 {
+        self.extensions.iter().fold(0, |bits, ext| bits | rsv_bits(ext.rsv_flags()))
+    }
+
+    /// Picks the extensions to use from the client's offer.
+    /// Returns the value of the `Sec-WebSocket-Extensions` response header if any extensions have been accepted.
+    fn negotiate_extensions(&mut self) -> Option<String> {
+        // Offers may be split between several headers; they're listed in the order of preference all the same.
+        let offers = self.request_header_values("Sec-WebSocket-Extensions").iter()
+            .flat_map(|header| extension::parse_extensions(header))
+            .collect::<Vec<_>>();
+        if offers.is_empty() {
+            return None;
+        }
+
+        let mut accepted = Vec::new();
+
+        for offer in offers {
+            // A client can offer several alternative configurations of the same extension;
+            // only the first acceptable one is used.
+            if self.extensions.iter().any(|ext| ext.name() == offer.name) {
+                continue;
+            }
+
+            let mut ext = match self.config.extensions.iter().find(|ext| ext.name() == offer.name) {
+                Some(prototype) => prototype.box_clone(),
+                None => continue
+            };
+
+            if rsv_bits(ext.rsv_flags()) & self.allowed_rsv_bits() != 0 {
+                warn!("{} extension {} conflicts with negotiated extensions over RSV bits", self.log, offer.name);
+                continue;
+            }
+
+            if let Some(params) = ext.negotiate(&offer.params) {
+                accepted.push(ExtensionOffer {
+                    name: offer.name.clone(),
+                    params: params
+                });
+                self.extensions.push(ext);
+            }
+        }
+
+        self.accepted_extensions = accepted;
+
+        if self.accepted_extensions.is_empty() {
+            None
+        } else {
+            Some(extension::format_extensions(&self.accepted_extensions))
+        }
+    }
+
+    /// Selects the first subprotocol requested by the client that's supported by the server.
+    fn negotiate_protocol(&mut self) -> Option<String> {
+        let protocol = match self.request_header("Sec-WebSocket-Protocol") {
+            Some(header) => {
+                header.split(',')
+                    .map(|protocol| protocol.trim())
+                    .find(|protocol| self.config.protocols.iter().any(|supported| supported == protocol))
+                    .map(|protocol| protocol.to_string())
+            },
+            None => None
+        };
+        self.protocol = protocol.clone();
+        self.log.protocol = protocol.clone();
+        protocol
+    }
+
     fn close_with_status(&mut self, status: StatusCode) {
-        self.outgoing.push(Frame::close(status));
+        if self.close_queued {
+            return;
+        }
+        self.close_status = Some(status.clone());
+        self.set_disconnect_reason(DisconnectReason::ServerInitiated, Some(status.clone()));
+        self.push_outgoing(OutgoingFrame::closing(Frame::close(status)));
+        self.state = ClientState::Closing;
+    }
+
+    /// Closes the connection with the status code and reason configured for the cause.
+    fn close_for(&mut self, cause: CloseCause) {
+        if self.close_queued {
+            return;
+        }
+        let config = self.config.clone();
+        let (code, reason) = config.close_status(cause);
+        if reason.is_empty() {
+            self.close_with_status(StatusCode::from(code));
+            return;
+        }
+
+        let mut payload = vec![0; 2];
+        BigEndian::write_u16(&mut payload, code);
+        payload.extend_from_slice(reason.as_bytes());
+
+        self.close_status = Some(StatusCode::from(code));
+        self.set_disconnect_reason(DisconnectReason::ServerInitiated, Some(StatusCode::from(code)));
+        self.push_outgoing(OutgoingFrame {
+            data: FrameData::Frame(Frame::from(payload)),
+            header: Some(0x80 | OpCode::ConnectionClose.bits()),
+            expiry: None,
+            priority: Priority::Low,
+            sequence: None
+        });
         self.state = ClientState::Closing;
     }
 
-    pub fn write(&mut self) {
+    /// Approximate number of bytes held in the connection's buffers: the frame being received,
+    /// the queued frames and the output that hasn't been written yet.
+    pub fn buffered_bytes(&self) -> usize {
+        self.partial_bytes + self.pipelined.len() + self.outgoing_bytes.remaining() +
+            self.fragments.as_ref().map(|&(_, ref payload)| payload.len()).unwrap_or(0) +
+            self.outgoing.iter().map(|frame| frame.data.len()).sum::<usize>()
+    }
+
+    /// Discards the queued frames and closes the connection with the status of `CloseCause::Overload`
+    /// to free memory. Returns `false` if the connection should be dropped right away.
+    pub fn shed(&mut self) -> bool {
+        match self.state {
+            ClientState::Connected | ClientState::CloseReceived => {
+                self.clear_outgoing();
+                self.close_for(CloseCause::Overload);
+                self.interest.insert(EventSet::writable());
+                self.interest.remove(EventSet::readable());
+                true
+            },
+            ClientState::Closing => true,
+            _ => false
+        }
+    }
+
+    /// Closes the connection with status 1008 (Policy Violation) if the client hasn't sent a heartbeat
+    /// within the window. Returns `true` if the connection is being closed.
+    pub fn check_heartbeat(&mut self, window: Duration) -> bool {
+        if !self.is_connected() || clock::elapsed(self.last_heartbeat) < window {
+            return false;
+        }
+        let err = WsError::Protocol("the client has missed its heartbeat".to_string());
+        error!("{} {}", self.log, err);
+        self.set_disconnect_reason(DisconnectReason::Timeout, Some(StatusCode::PolicyViolation));
+        self.terminate(WebSocketEvent::Error(err));
+        self.close_with_status(StatusCode::PolicyViolation);
+        self.interest.insert(EventSet::writable());
+        self.interest.remove(EventSet::readable());
+        true
+    }
+
+    /// Drops the connection if it has had output pending without writing any of it for the timeout.
+    /// Returns `true` if the connection is being dropped.
+    pub fn check_write_progress(&mut self, timeout: Duration) -> bool {
+        if !self.has_pending_writes() {
+            self.last_write_progress = clock::now();
+            return false;
+        }
+        if self.interest.is_hup() || clock::elapsed(self.last_write_progress) < timeout {
+            return false;
+        }
+        let err = WsError::Io(io::ErrorKind::TimedOut, "the client doesn't read the data sent to it".to_string());
+        error!("{} {}", self.log, err);
+        self.set_disconnect_reason(DisconnectReason::SlowConsumer, None);
+        self.terminate(WebSocketEvent::Error(err));
+        self.interest.remove(EventSet::readable());
+        self.interest.remove(EventSet::writable());
+        self.interest.insert(EventSet::hup());
+        true
+    }
+
+    /// Starts the closing handshake because the server is shutting down.
+    /// Returns `false` if the handshake hasn't been completed and the connection should be dropped right away.
+    pub fn go_away(&mut self) -> bool {
+        match self.state {
+            ClientState::Connected | ClientState::CloseReceived => {
+                self.close_for(CloseCause::Shutdown);
+                self.interest.insert(EventSet::writable());
+                self.interest.remove(EventSet::readable());
+                true
+            },
+            ClientState::Closing => true,
+            _ => false
+        }
+    }
+
+    /// Writes to the socket. Frames take up at most `quota` bytes; the rest is written on the next writable event.
+    pub fn write(&mut self, quota: usize) {
         match self.state {
             ClientState::HandshakeResponse => self.write_handshake(),
-            ClientState::Connected | ClientState::Closing => self.write_frames(),
+            ClientState::Connected | ClientState::CloseReceived | ClientState::Closing => self.write_frames(quota),
             _ => {}
         }
     }
 
     fn write_handshake(&mut self) {
-        let response_key = gen_key(&*self.headers.borrow().get("Sec-WebSocket-Key").unwrap());
-        let response = fmt::format(format_args!("HTTP/1.1 101 Switching Protocols\r\n\
-                                                 Connection: Upgrade\r\n\
-                                                 Sec-WebSocket-Accept: {}\r\n\
-                                                 Upgrade: websocket\r\n\r\n", response_key));
-        self.socket.try_write(response.as_bytes()).unwrap();
+        // The key has been validated along with the rest of the upgrade request.
+        let response_key = match self.request_header("Sec-WebSocket-Key") {
+            Some(key) => handshake::gen_key(key.trim()),
+            None => {
+                self.reject_handshake("400 Bad Request", "Missing Sec-WebSocket-Key");
+                return;
+            }
+        };
+        let mut response = fmt::format(format_args!("HTTP/1.1 101 Switching Protocols\r\n\
+                                                     Connection: Upgrade\r\n\
+                                                     Sec-WebSocket-Accept: {}\r\n\
+                                                     Upgrade: websocket\r\n", response_key));
+
+        if let Some(protocol) = self.negotiate_protocol() {
+            response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+        }
+
+        if let Some(extensions) = self.negotiate_extensions() {
+            response.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", extensions));
+        }
+
+        if let Some((ref header, ref instance_id)) = self.config.affinity {
+            response.push_str(&format!("{}: {}\r\n", header, instance_id));
+        }
+
+        for &(ref name, ref value) in &self.response_headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        response.push_str("\r\n");
+        if let Err(e) = self.socket.try_write(response.as_bytes()) {
+            self.fail(WsError::from(e));
+            return;
+        }
 
         // Change the state
         self.state = ClientState::Connected;
+        self.last_heartbeat = clock::now();
 
         // Send the connection event
         self.notify(WebSocketEvent::Connect);
 
         self.interest.remove(EventSet::writable());
         self.interest.insert(EventSet::readable());
+
+        if !self.pipelined.is_empty() {
+            let mut read_buf = ByteBuf::from_slice(&self.pipelined);
+            self.pipelined = Vec::new();
+            if self.read_frames_from(&mut read_buf) && self.outgoing.len() > 0 {
+                self.interest.remove(EventSet::readable());
+                self.interest.insert(EventSet::writable());
+            }
+        }
     }
 
     fn serialize_frames(&mut self) -> Vec<u8> {
         // FIXME: calculate capacity
         let mut out_buf = Vec::new();
+        let mut expired = Vec::new();
         {
-            for frame in self.outgoing.iter() {
-                if let Err(e) = frame.write(&mut out_buf) {
-                    println!("error on write: {}", e);
+            let now = clock::now();
+            // The sort is stable, so frames of the same priority are written in the order they've been queued.
+            self.outgoing.sort_by_key(|outgoing| outgoing.priority);
+            self.queued_control_frames = 0;
+            for outgoing in self.outgoing.drain(..) {
+                if let Some((deadline, msg)) = outgoing.expiry {
+                    if deadline <= now {
+                        expired.push(msg);
+                        continue;
+                    }
+                }
+                if outgoing.sequence.is_some() {
+                    self.pending_sequence = cmp::max(self.pending_sequence, outgoing.sequence);
+                }
+                let header_pos = out_buf.len();
+                match outgoing.data {
+                    FrameData::Frame(ref frame) => {
+                        if let Err(e) = frame.write(&mut out_buf) {
+                            println!("error on write: {}", e);
+                            continue;
+                        }
+                    },
+                    FrameData::SharedText(ref text) => write_shared_frame(&mut out_buf, text.as_bytes()),
+                    FrameData::SharedBinary(ref data) => write_shared_frame(&mut out_buf, data),
+                    FrameData::Serialized(ref data) => out_buf.extend_from_slice(data)
                 }
+                if let Some(header) = outgoing.header {
+                    out_buf[header_pos] = header;
+                }
+            }
+        }
+        for msg in expired {
+            trace!("{} dropping expired message", self.log);
+            match msg {
+                WebSocketMessage::Text(_) | WebSocketMessage::Binary(_) |
+                WebSocketMessage::SharedText(_) | WebSocketMessage::SharedBinary(_) => self.stats.messages_sent -= 1,
+                _ => {}
             }
+            self.notify(WebSocketEvent::Dropped(msg));
         }
         out_buf
     }
 
-    fn write_frames(&mut self) {
+    /// Appends received bytes to the traffic recording. Recording stops on the first error.
+    fn record(&mut self, data: &[u8]) {
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.record(data).is_err(),
+            None => false
+        };
+        if failed {
+            warn!("{} failed to record traffic, recording stopped", self.log);
+            self.recorder = None;
+        }
+    }
+
+    /// Takes the data frames received while the connection is piped.
+    pub fn take_forwarded(&mut self) -> Vec<RawFrame> {
+        mem::replace(&mut self.forwarded, Vec::new())
+    }
+
+    /// Checks whether the connection has anything to write.
+    pub fn has_pending_writes(&self) -> bool {
+        self.outgoing_bytes.has_remaining() || self.outgoing.len() > 0
+    }
+
+    fn write_frames(&mut self, mut quota: usize) {
         loop {
             // First, we fill the byte buffer by serializing frames.
             if !self.outgoing_bytes.has_remaining() {
+                if let Some(sequence) = self.pending_sequence.take() {
+                    self.delivered_sequence = Some(sequence);
+                }
                 if self.outgoing.len() > 0 {
-                    trace!("{:?} has {} more frames to send in queue", self.token, self.outgoing.len());
+                    trace!("{} has {} more frames to send in queue", self.log, self.outgoing.len());
                     let out_buf = self.serialize_frames();
                     self.outgoing_bytes = ByteBuf::from_slice(&*out_buf);
-                    self.outgoing.clear();
+                    self.clear_outgoing();
                 } else {
                     // Buffer is exhausted and we have no more frames to send out.
-                    trace!("{:?} wrote all bytes; switching to reading", self.token);
+                    self.interest.remove(EventSet::writable());
+                    if self.input_closed {
+                        trace!("{} wrote all bytes after the peer has closed its side; closing connection", self.log);
+                        self.socket.shutdown(Shutdown::Write);
+                        self.interest.insert(EventSet::hup());
+                        break;
+                    }
+                    trace!("{} wrote all bytes; switching to reading", self.log);
                     if let ClientState::Closing = self.state {
-                        trace!("{:?} closing connection", self.token);
+                        trace!("{} closing connection", self.log);
                         self.socket.shutdown(Shutdown::Write);
                     }
-                    self.interest.remove(EventSet::writable());
                     self.interest.insert(EventSet::readable());
                     break;
                 }
             }
 
+            let allowance = cmp::min(quota, self.throttle.as_ref().map(|throttle| throttle.allowance()).unwrap_or(usize::MAX));
+            if allowance == 0 {
+                // The server resumes writing once the send rate allows it.
+                trace!("{} has used up its send quota", self.log);
+                break;
+            }
+
             // As long as we have something to send, we're writing bytes from the buffer to the socket.
-            match self.socket.try_write_buf(&mut self.outgoing_bytes) {
+            let write_result = {
+                let bytes = Buf::bytes(&self.outgoing_bytes);
+                self.socket.try_write(&bytes[..cmp::min(bytes.len(), allowance)])
+            };
+            match write_result {
                 Ok(Some(write_bytes)) => {
-                    trace!("{:?} wrote {} bytes, remaining: {}", self.token, write_bytes, self.outgoing_bytes.remaining());
+                    Buf::advance(&mut self.outgoing_bytes, write_bytes);
+                    quota -= write_bytes;
+                    if let Some(ref mut throttle) = self.throttle {
+                        throttle.consume(write_bytes);
+                    }
+                    self.stats.bytes_sent += write_bytes as u64;
+                    self.last_write_progress = clock::now();
+                    trace!("{} wrote {} bytes, remaining: {}", self.log, write_bytes, self.outgoing_bytes.remaining());
                 },
                 Ok(None) => {
                     // This write call would block
@@ -192,9 +1346,7 @@ impl WebSocketClient {
                 },
                 Err(e) => {
                     // Write error - close this connnection immediately
-                    error!("{:?} Error occured while writing bytes: {}", self.token, e);
-                    self.interest.remove(EventSet::writable());
-                    self.interest.insert(EventSet::hup());
+                    self.fail(WsError::from(e));
                     break;
                 }
             }
@@ -209,12 +1361,12 @@ impl WebSocketClient {
 
                 // Write any buffered outgoing frames
                 if self.outgoing.len() > 0 {
-                    trace!("{:?} read resulted in {} outgoing frames, switching to write", self.token, self.outgoing.len());
+                    trace!("{} read resulted in {} outgoing frames, switching to write", self.log, self.outgoing.len());
                     self.interest.remove(EventSet::readable());
                     self.interest.insert(EventSet::writable());
                 }
             }
-            ClientState::Closing => self.read_close(),
+            ClientState::CloseReceived | ClientState::Closing => self.read_close(),
             _ => {}
         }
     }
@@ -223,24 +1375,33 @@ impl WebSocketClient {
         let mut buf = ByteBuf::mut_with_capacity(2048);
 
         match self.socket.try_read_buf(&mut buf) {
-            Ok(Some(0)) => {
-                // Remote end has closed connection, we can close it now, too.
-                self.interest.remove(EventSet::readable());
-                self.interest.insert(EventSet::hup());
-                return;
-            },
+            Ok(Some(0)) => self.on_input_closed(),
             _  => {}
         }
     }
 
+    /// Handles the end of the input. The connection is closed once the pending output has been written.
+    fn on_input_closed(&mut self) {
+        self.input_closed = true;
+        self.interest.remove(EventSet::readable());
+        if self.has_pending_writes() {
+            trace!("{} peer has closed its side, writing the pending output first", self.log);
+            self.interest.insert(EventSet::writable());
+        } else {
+            self.interest.insert(EventSet::hup());
+        }
+    }
+
     fn read_frame(&mut self) {
         loop {
-            let mut buf = ByteBuf::mut_with_capacity(16384);
+            if self.is_read_suspended() {
+                // The rest is read once the application has caught up.
+                break;
+            }
+            let mut buf = ByteBuf::mut_with_capacity(self.config.read_buffer_size);
             match self.socket.try_read_buf(&mut buf) {
                 Err(e) => {
-                    error!("{:?} Error while reading socket: {:?}", self.token, e);
-                    self.interest.remove(EventSet::readable());
-                    self.interest.insert(EventSet::hup());
+                    self.fail(WsError::from(e));
                     return
                 },
                 Ok(None) =>
@@ -248,95 +1409,291 @@ impl WebSocketClient {
                     break,
                 Ok(Some(0)) => {
                     // Remote end has closed connection, we can close it now, too.
-                    self.interest.remove(EventSet::readable());
-                    self.interest.insert(EventSet::hup());
+                    self.on_input_closed();
                     return;
                 },
                 Ok(Some(read_bytes)) => {
-                    trace!("{:?} read {} bytes", self.token, read_bytes);
+                    trace!("{} read {} bytes", self.log, read_bytes);
+                    self.stats.bytes_received += read_bytes as u64;
+                    self.last_received = clock::now();
                     let mut read_buf = buf.flip();
-                    let mut frames_cnt = 0;
-                    loop {
-                        match self.frame_reader.read(&mut read_buf) {
-                            Err(err @ ParseError::InvalidOpCode(..)) => {
-                                error!("{:?} Invalid OpCode: {}", self.token, err);
-                                self.close_with_status(StatusCode::ProtocolError);
-                                break;
-                            },
-                            Err(e) => {
-                                error!("{:?} Error while reading frame: {}", self.token, e);
-                                self.interest.remove(EventSet::readable());
-                                self.interest.insert(EventSet::hup());
-                                return;
-                            },
-                            Ok(None) => break,
-                            Ok(Some(frame)) => {
-                                frames_cnt += 1;
-
-                                let (rsv1, rsv2, rsv3) = frame.get_rsv_flags();
-                                if rsv1 || rsv2 || rsv3 {
-                                    // Client is trying to negotiate extensions unknown to us - close
-                                    // the connection with the protocol error status.
-                                    error!("{:?} got an RSV flag while no extension were negotiated", self.token);
-                                    self.close_with_status(StatusCode::ProtocolError);
-                                    return;
-                                }
-
-                                if self.handle_frame(frame).is_err() {
-                                    self.close_with_status(StatusCode::ProtocolError);
-                                    return;
-                                }
-                            }
-                        }
+                    self.record(Buf::bytes(&read_buf));
+                    if !self.read_frames_from(&mut read_buf) {
+                        return;
                     }
-                    trace!("{:?} parsed {} frames", self.token, frames_cnt);
                     buf = read_buf.flip();
                 }
             }
         }
     }
 
+    /// Parses and handles the frames in the buffer. Returns `false` if the rest of the input shouldn't be read.
+    fn read_frames_from(&mut self, read_buf: &mut ByteBuf) -> bool {
+        let mut frames_cnt = 0;
+        self.partial_bytes += read_buf.remaining();
+        if let Some(limit) = self.config.max_connection_memory {
+            if self.buffered_bytes() > limit {
+                self.fail_with_error(ClientError::MessageTooBig(
+                    format!("incoming data takes more than {} bytes", limit)));
+                return false;
+            }
+        }
+        loop {
+            match self.frame_reader.read(read_buf) {
+                Err(err @ ParseError::InvalidOpCode(..)) | Err(err @ ParseError::InvalidLength(..)) => {
+                    self.fail_with_error(ClientError::ProtocolError(format!("{}", err)));
+                    return false;
+                },
+                Err(err @ ParseError::PayloadTooLarge(..)) => {
+                    self.fail_with_error(ClientError::MessageTooBig(format!("{}", err)));
+                    return false;
+                },
+                Err(e) => {
+                    self.fail(WsError::Protocol(format!("error while reading frame: {}", e)));
+                    return false;
+                },
+                Ok(None) => break,
+                Ok(Some(frame)) => {
+                    frames_cnt += 1;
+                    self.partial_bytes = read_buf.remaining();
+                    if let Some((_, Heartbeat::AnyFrame)) = self.config.heartbeat {
+                        self.last_heartbeat = clock::now();
+                    }
+
+                    let strictness = self.config.strictness;
+                    if strictness == Strictness::Strict && !frame.is_masked() {
+                        self.fail_with_error(ClientError::ProtocolError("got an unmasked frame".to_string()));
+                        return false;
+                    }
+
+                    if strictness != Strictness::Permissive &&
+                       rsv_bits(frame.get_rsv_flags()) & !self.allowed_rsv_bits() != 0 {
+                        // Client is trying to use extensions that weren't negotiated - close
+                        // the connection with the protocol error status.
+                        self.fail_with_error(ClientError::ProtocolError(
+                            "got an RSV flag not claimed by negotiated extensions".to_string()));
+                        return false;
+                    }
+
+                    if let Err(err) = self.handle_frame(frame) {
+                        self.fail_with_error(err);
+                        return false;
+                    }
+
+                    match self.state {
+                        ClientState::CloseReceived | ClientState::Closing => {
+                            // Either the client or the server has started closing the connection,
+                            // don't process the rest of the frames.
+                            return false;
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+        trace!("{} parsed {} frames", self.log, frames_cnt);
+        true
+    }
+
     fn handle_frame(&mut self, frame: Frame) -> Result<(), ClientError> {
+        let fin = frame.is_final();
+        let strictness = self.config.strictness;
+        if frame.get_opcode().is_control() {
+            // Control frames can't be fragmented and carry at most 125 bytes (RFC 6455, section 5.5).
+            // Permissive connections take them as they come; a control frame is complete on its own.
+            if strictness != Strictness::Permissive {
+                if !fin {
+                    return Err(ClientError::ProtocolError("Control frame is fragmented".to_string()));
+                }
+                if frame.payload().len() > 125 {
+                    return Err(ClientError::ProtocolError("Control frame length is > 125".to_string()));
+                }
+            }
+        } else {
+            try!(self.check_fragment(frame.get_opcode(), fin));
+        }
+
         match frame.get_opcode() {
-            OpCode::TextFrame => {
-                let payload = String::from_utf8(frame.into_vec());
-                if let Err(e) = payload {
-                    // Couldn't decode UTF-8, close the connection
-                    error!("{:?} Utf8 decode error: {}", self.token, e);
-                    return Err(ClientError::ProtocolError);
+            opcode @ OpCode::TextFrame | opcode @ OpCode::BinaryFrame | opcode @ OpCode::ContinuationFrame
+                if self.raw_mode || self.piped => {
+                try!(self.check_message_size(frame.payload().len()));
+                let raw = RawFrame {
+                    fin: fin,
+                    opcode: opcode,
+                    rsv: frame.get_rsv_flags(),
+                    payload: frame.into_vec()
+                };
+                if self.piped {
+                    self.forwarded.push(raw);
+                } else {
+                    self.notify(WebSocketEvent::Frame(raw));
                 }
-                self.notify(WebSocketEvent::TextMessage(payload.unwrap()));
             },
-            OpCode::BinaryFrame => {
-                self.notify(WebSocketEvent::BinaryMessage(frame.into_vec()));
+            opcode @ OpCode::TextFrame | opcode @ OpCode::BinaryFrame | opcode @ OpCode::ContinuationFrame => {
+                let (metadata, payload) = match (opcode, self.fragments.take()) {
+                    (OpCode::ContinuationFrame, Some((mut metadata, mut payload))) => {
+                        metadata.frames += 1;
+                        payload.extend_from_slice(frame.payload());
+                        (metadata, payload)
+                    },
+                    (opcode, _) => {
+                        let metadata = MessageMetadata {
+                            opcode: opcode,
+                            rsv: frame.get_rsv_flags(),
+                            frames: 1
+                        };
+                        (metadata, frame.into_vec())
+                    }
+                };
+                try!(self.check_message_size(payload.len()));
+                if fin {
+                    try!(self.deliver_message(metadata, payload));
+                } else {
+                    // Control frames may come in between the fragments.
+                    self.fragments = Some((metadata, payload));
+                }
             },
             OpCode::Ping => {
-                if frame.payload().len() > 125 {
-                    error!("{:?} Control frame length is > 125", self.token);
-                    return Err(ClientError::ProtocolError);
-                } else {
-                    self.outgoing.push(Frame::pong(&frame));
+                self.push_outgoing(OutgoingFrame::from(Frame::pong(&frame)));
+                self.check_queued_frames();
+            },
+            OpCode::Pong => {
+                // Clients may answer only the latest of several pings, so the older ones are forgotten.
+                let answered = self.pending_pings.iter().position(|&(ref payload, _)| payload == frame.payload());
+                if let Some(pos) = answered {
+                    let rtt = clock::elapsed(self.pending_pings[pos].1);
+                    self.pending_pings.drain(..pos + 1);
+                    self.stats.rtt = Some(rtt);
+                    self.notify(WebSocketEvent::Heartbeat(rtt));
                 }
             },
             OpCode::ConnectionClose => {
-                let close_ev = if frame.payload().len() >= 2 {
-                    let status_code = BigEndian::read_u16(&frame.payload()[0..2]);
-                    WebSocketEvent::Close(StatusCode::from(status_code))
+                if strictness != Strictness::Permissive {
+                    try!(validate_close_payload(frame.payload()));
+                }
+                let status_code = if frame.payload().len() >= 2 {
+                    StatusCode::from(BigEndian::read_u16(&frame.payload()[0..2]))
                 } else {
                     // No status code has been provided
-                    WebSocketEvent::Close(StatusCode::Custom(0))
+                    StatusCode::Custom(0)
                 };
-                self.notify(close_ev);
+                self.set_disconnect_reason(DisconnectReason::Clean, Some(status_code.clone()));
+                self.terminate(WebSocketEvent::Close(status_code));
 
                 if let Ok(response) = Frame::close_from(&frame) {
-                    self.state = ClientState::Closing;
-                    self.outgoing.push(response);
+                    if self.config.manual_close {
+                        // The application completes the handshake by closing the connection itself.
+                        self.state = ClientState::CloseReceived;
+                    } else {
+                        self.state = ClientState::Closing;
+                        self.push_outgoing(OutgoingFrame::closing(response));
+                    }
                 } else {
-                    return Err(ClientError::ProtocolError);
+                    return Err(ClientError::ProtocolError("invalid Close frame".to_string()));
                 }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the order of data frames (RFC 6455, section 5.4): a fragmented message is a text or binary frame
+    /// followed by continuation frames, the last of them with the FIN bit set. Control frames may come
+    /// in between the fragments and don't affect the order.
+    fn check_fragment(&mut self, opcode: OpCode, fin: bool) -> Result<(), ClientError> {
+        match (opcode, self.fragmented) {
+            (OpCode::ContinuationFrame, false) =>
+                Err(ClientError::ProtocolError("continuation frame without a message to continue".to_string())),
+            (OpCode::TextFrame, true) | (OpCode::BinaryFrame, true) =>
+                Err(ClientError::ProtocolError("new message started within a fragmented one".to_string())),
+            _ => {
+                self.fragmented = !fin;
+                Ok(())
+            }
+        }
+    }
+
+    fn requests_upgrade(&self) -> bool {
+        let connection = self.request_header("Connection").unwrap_or(String::new());
+        http::has_token(&connection, "upgrade") && self.request_header("Upgrade").is_some()
+    }
+
+    fn is_host_allowed(&self) -> bool {
+        if self.config.allowed_hosts.is_empty() {
+            return true;
+        }
+        let host = match self.request_header("Host") {
+            Some(host) => host.trim().to_lowercase(),
+            None => return false
+        };
+        self.config.allowed_hosts.iter().any(|allowed| *allowed == host || allowed == http::host_name(&host))
+    }
+
+    /// Checks that an upgrade request asks for a WebSocket connection.
+    fn validate_upgrade(&self, method: &str) -> Result<(), (&'static str, &'static str)> {
+        if method != "GET" {
+            return Err(("400 Bad Request", "WebSocket handshake must be a GET request"));
+        }
+
+        let upgrade = self.request_header("Upgrade").unwrap_or(String::new());
+        if !http::has_token(&upgrade, "websocket") {
+            return Err(("426 Upgrade Required", "Only upgrades to WebSocket are supported"));
+        }
+
+        if !self.is_host_allowed() {
+            return Err(("400 Bad Request", "Host is not allowed"));
+        }
+
+        // Upgrade requests have no body; the bytes following the request would be taken for frames otherwise.
+        if self.request_header("Transfer-Encoding").is_some() {
+            return Err(("400 Bad Request", "WebSocket handshake must not have a body"));
+        }
+        match self.request_header("Content-Length") {
+            Some(ref length) if length.trim() != "0" => {
+                return Err(("400 Bad Request", "WebSocket handshake must not have a body"));
             },
             _ => {}
         }
+
+        match self.request_header("Sec-WebSocket-Key") {
+            Some(ref key) if handshake::is_valid_key(key.trim()) => {},
+            Some(_) => return Err(("400 Bad Request", "Invalid Sec-WebSocket-Key")),
+            None => return Err(("400 Bad Request", "Missing Sec-WebSocket-Key"))
+        }
+
+        Ok(())
+    }
+
+    /// Finds the route matching the request path. Returns `false` if routes are configured and none matches.
+    fn match_route(&mut self) -> bool {
+        if self.config.routes.is_empty() {
+            return true;
+        }
+        let url = self.url.borrow().clone();
+        for route in &self.config.routes {
+            if let Some(params) = route.matches(&url) {
+                self.route = Some((route.pattern().to_string(), params));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Passes the upgrade request through the handshake middleware chain.
+    fn run_middleware(&mut self) -> Result<(), Rejection> {
+        if self.config.middleware.is_empty() {
+            return Ok(());
+        }
+        let (response_headers, attributes) = {
+            let url = self.url.borrow();
+            let headers = self.headers.borrow();
+            let no_params = HashMap::new();
+            let params = self.route.as_ref().map(|&(_, ref params)| params).unwrap_or(&no_params);
+            let mut handshake = Handshake::new(&url, &headers, params, self.peer_addr);
+            try!(middleware::run(&self.config.middleware, &mut handshake));
+            handshake.into_parts()
+        };
+        self.response_headers = response_headers;
+        self.attributes = attributes;
         Ok(())
     }
 
@@ -345,20 +1702,59 @@ impl WebSocketClient {
             let mut buf = [0; 2048];
             match self.socket.try_read(&mut buf) {
                 Err(e) => {
-                    println!("Error while reading socket: {:?}", e);
+                    self.fail(WsError::from(e));
                     return
                 },
                 Ok(None) =>
                     // Socket buffer has got no more bytes.
                     break,
-                Ok(Some(_)) => {
-                    let is_upgrade = if let ClientState::AwaitingHandshake(ref parser_state) = self.state {
+                Ok(Some(0)) => {
+                    // Remote end has closed connection before completing the handshake.
+                    self.interest.remove(EventSet::readable());
+                    self.interest.insert(EventSet::hup());
+                    return;
+                },
+                Ok(Some(read_bytes)) => {
+                    self.stats.bytes_received += read_bytes as u64;
+                    self.record(&buf[..read_bytes]);
+                    if self.stats.bytes_received > self.config.max_handshake_size as u64 {
+                        self.reject_handshake("431 Request Header Fields Too Large", "Handshake request is too large");
+                        return;
+                    }
+
+                    // The parser keeps its state between reads, so the request can arrive in any number of pieces.
+                    let (is_upgrade, method, parse_error, parsed) = if let ClientState::AwaitingHandshake(ref parser_state) = self.state {
                         let mut parser = parser_state.borrow_mut();
-                        parser.parse(&buf);
-                        parser.is_upgrade()
-                    } else { false };
+                        let parsed = parser.parse(&buf[..read_bytes]);
+                        let parse_error = if parser.has_error() { Some(parser.error().to_string()) } else { None };
+                        (parser.is_upgrade(), parser.http_method().to_string(), parse_error, parsed)
+                    } else { (false, String::new(), None, read_bytes) };
+
+                    if let Some(err) = parse_error {
+                        self.reject_handshake("400 Bad Request", &format!("Malformed HTTP request: {}", err));
+                        return;
+                    }
+
+                    // Don't rely on the parser alone: the Connection header may list other options along with Upgrade.
+                    let is_upgrade = is_upgrade || (self.headers_complete.get() && self.requests_upgrade());
 
                     if is_upgrade {
+                        if let Err((status, reason)) = self.validate_upgrade(&method) {
+                            self.reject_handshake(status, reason);
+                            return;
+                        }
+                        if !self.match_route() {
+                            self.reject_handshake("404 Not Found", "No route for the requested path");
+                            return;
+                        }
+                        if let Err(rejection) = self.run_middleware() {
+                            self.reject_handshake(&rejection.status, &rejection.reason);
+                            return;
+                        }
+
+                        // The parser stops at the end of an upgrade request; whatever follows it are the first frames.
+                        self.pipelined.extend_from_slice(&buf[parsed..read_bytes]);
+
                         // Change the current state
                         self.state = ClientState::HandshakeResponse;
 
@@ -366,6 +1762,11 @@ impl WebSocketClient {
                         self.interest.remove(EventSet::readable());
                         self.interest.insert(EventSet::writable());
                         break;
+                    } else if self.headers_complete.get() {
+                        // Not a WebSocket handshake, the server will respond to it as to a plain HTTP request.
+                        self.state = ClientState::HttpRequest(method);
+                        self.interest.remove(EventSet::readable());
+                        break;
                     }
                 }
             }
@@ -0,0 +1,30 @@
+/// Source of the current time for timeouts, rate limits and round-trip times
+
+use std::time::{Duration, Instant};
+#[cfg(feature = "testing")]
+use std::cell::Cell;
+
+// Time the clock is stopped at on this thread, set by `testing::Clock`.
+#[cfg(feature = "testing")]
+thread_local!(static STOPPED_AT: Cell<Option<Instant>> = Cell::new(None));
+
+#[cfg(not(feature = "testing"))]
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+#[cfg(feature = "testing")]
+pub fn now() -> Instant {
+    STOPPED_AT.with(|stopped_at| stopped_at.get()).unwrap_or_else(Instant::now)
+}
+
+#[cfg(feature = "testing")]
+pub fn stop_at(at: Option<Instant>) {
+    STOPPED_AT.with(|stopped_at| stopped_at.set(at));
+}
+
+/// Time passed since `since`, zero if `since` is in the future.
+pub fn elapsed(since: Instant) -> Duration {
+    let now = now();
+    if now > since { now.duration_since(since) } else { Duration::from_secs(0) }
+}
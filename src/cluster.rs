@@ -0,0 +1,179 @@
+/// Relay of room broadcasts between server instances.
+///
+/// Every node listens for connections from its peers and connects to each of them in turn.
+/// Messages published locally are forwarded to all peers, and messages received from peers are
+/// published to local clients only, so nodes should be connected in a full mesh.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use interface::{WebSocketMessage, WsSender};
+
+// Delay before reconnecting to a peer that has gone away.
+const RECONNECT_DELAY_MS: u64 = 1000;
+
+// Broadcasts waiting to be written to a peer. Further ones are dropped until the peer catches up.
+const PEER_QUEUE_LEN: usize = 1024;
+
+const KIND_TEXT: u8 = 1;
+const KIND_BINARY: u8 = 2;
+
+/// Serializes a broadcast as `[room length: u32][room][kind: u8][payload length: u32][payload]`.
+fn encode(room: &str, msg: &WebSocketMessage) -> Option<Vec<u8>> {
+    let (kind, payload) = match *msg {
+        WebSocketMessage::Text(ref text) => (KIND_TEXT, text.as_bytes()),
+        WebSocketMessage::Binary(ref data) => (KIND_BINARY, &data[..]),
+        WebSocketMessage::SharedText(ref text) => (KIND_TEXT, text.as_bytes()),
+        WebSocketMessage::SharedBinary(ref data) => (KIND_BINARY, &data[..]),
+        _ => return None
+    };
+
+    let mut buf = vec![0; 4 + room.len() + 1 + 4 + payload.len()];
+    BigEndian::write_u32(&mut buf[0..4], room.len() as u32);
+    buf[4..4 + room.len()].copy_from_slice(room.as_bytes());
+
+    let pos = 4 + room.len();
+    buf[pos] = kind;
+    BigEndian::write_u32(&mut buf[pos + 1..pos + 5], payload.len() as u32);
+    buf[pos + 5..].copy_from_slice(payload);
+
+    Some(buf)
+}
+
+fn read_chunk(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    try!(stream.read_exact(&mut len));
+
+    let mut chunk = vec![0; BigEndian::read_u32(&len) as usize];
+    try!(stream.read_exact(&mut chunk));
+    Ok(chunk)
+}
+
+fn decode(stream: &mut TcpStream) -> io::Result<(String, WebSocketMessage)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let room = try!(String::from_utf8(try!(read_chunk(stream))).map_err(|_| invalid("room name is not valid UTF-8")));
+
+    let mut kind = [0; 1];
+    try!(stream.read_exact(&mut kind));
+    let payload = try!(read_chunk(stream));
+
+    let msg = match kind[0] {
+        KIND_TEXT => WebSocketMessage::Text(try!(String::from_utf8(payload).map_err(|_| invalid("text is not valid UTF-8")))),
+        KIND_BINARY => WebSocketMessage::Binary(payload),
+        _ => return Err(invalid("unknown message kind"))
+    };
+
+    Ok((room, msg))
+}
+
+/// Connects to a peer and writes broadcasts to it, reconnecting whenever the connection breaks.
+/// Broadcasts published while the peer is unreachable are lost.
+fn run_peer(addr: SocketAddr, queue: Receiver<Vec<u8>>) {
+    let mut stream: Option<TcpStream> = None;
+
+    for buf in queue.iter() {
+        if stream.is_none() {
+            stream = match TcpStream::connect(&addr) {
+                Ok(stream) => {
+                    debug!("connected to cluster peer {}", addr);
+                    Some(stream)
+                },
+                Err(e) => {
+                    warn!("cluster peer {} is unreachable: {}", addr, e);
+                    thread::sleep(Duration::from_millis(RECONNECT_DELAY_MS));
+                    // Don't deliver the broadcasts queued in the meantime late, once the peer is back.
+                    let dropped = queue.try_iter().count();
+                    if dropped > 0 {
+                        debug!("dropped {} broadcasts for unreachable cluster peer {}", dropped, addr);
+                    }
+                    continue;
+                }
+            };
+        }
+
+        let failed = stream.as_mut().map(|stream| stream.write_all(&buf).is_err()).unwrap_or(false);
+        if failed {
+            warn!("lost connection to cluster peer {}", addr);
+            stream = None;
+        }
+    }
+}
+
+/// Reads broadcasts sent by a peer and publishes them to local clients.
+fn run_inbound(mut stream: TcpStream, local: WsSender) {
+    loop {
+        match decode(&mut stream) {
+            Ok((room, msg)) => local.publish_local(&room, msg),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    warn!("cluster peer error: {}", e);
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn spawn_peer(addr: SocketAddr) -> SyncSender<Vec<u8>> {
+    let (tx, rx) = mpsc::sync_channel(PEER_QUEUE_LEN);
+    thread::spawn(move || run_peer(addr, rx));
+    tx
+}
+
+pub struct Relay {
+    peers: Vec<(SocketAddr, Mutex<SyncSender<Vec<u8>>>)>
+}
+
+impl Relay {
+    /// Starts accepting connections from peers on `listen` and spawns writers for `peers`.
+    /// `local` delivers broadcasts received from peers to the clients of this node.
+    pub fn start(listen: SocketAddr, peers: &[SocketAddr], local: WsSender) -> io::Result<Relay> {
+        let listener = try!(TcpListener::bind(&listen));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let local = local.clone();
+                        thread::spawn(move || run_inbound(stream, local));
+                    },
+                    Err(e) => error!("cluster accept error: {}", e)
+                }
+            }
+        });
+
+        let peers = peers.iter().map(|&addr| (addr, Mutex::new(spawn_peer(addr)))).collect();
+
+        Ok(Relay {
+            peers: peers
+        })
+    }
+
+    /// Forwards a broadcast to all peers. Only text and binary messages are relayed.
+    /// Broadcasts are dropped for peers that lag behind by more than `PEER_QUEUE_LEN` of them.
+    pub fn forward(&self, room: &str, msg: &WebSocketMessage) {
+        let buf = match encode(room, msg) {
+            Some(buf) => buf,
+            None => return
+        };
+        for &(addr, ref peer) in self.peers.iter() {
+            let mut peer = peer.lock().unwrap();
+            match peer.try_send(buf.clone()) {
+                Ok(()) => {},
+                Err(TrySendError::Full(_)) => trace!("queue of cluster peer {} is full, dropping a broadcast", addr),
+                Err(TrySendError::Disconnected(buf)) => {
+                    error!("writer of cluster peer {} has died, restarting it", addr);
+                    *peer = spawn_peer(addr);
+                    let _ = peer.try_send(buf);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use extension::Extension;
+use middleware::HandshakeMiddleware;
+use route::Route;
+use interface::{CloseCause, ConnectionRecord, FdExhaustion, Heartbeat, LoadShedding, OverflowPolicy, Strictness, SupervisionPolicy};
+
+const DEFAULT_MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+const DEFAULT_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Server settings assembled by `WebSocketBuilder`. The server shares them with all of its clients.
+pub struct Config {
+    pub extensions: Vec<Box<Extension>>,
+    pub protocols: Vec<String>,
+    // Run on upgrade requests in this order.
+    pub middleware: Vec<Box<HandshakeMiddleware>>,
+    // Paths upgrade requests are accepted on; any path is accepted if the list is empty.
+    pub routes: Vec<Route>,
+    pub backpressure: Option<(usize, usize)>,
+    pub event_queue: Option<(usize, OverflowPolicy)>,
+    pub notify_capacity: Option<usize>,
+    pub supervision: SupervisionPolicy,
+    // Address to accept cluster peers on and the addresses of peers.
+    pub cluster: Option<(SocketAddr, Vec<SocketAddr>)>,
+    // Number of messages buffered for a detached session and the time the session is kept for.
+    pub sessions: Option<(usize, Duration)>,
+    // Name of the handshake response header carrying the ID of this server instance, and the ID.
+    pub affinity: Option<(String, String)>,
+    // Token protecting the admin endpoint; the endpoint is disabled without it.
+    pub admin_token: Option<String>,
+    pub max_handshake_size: usize,
+    // Length of the queue of connections waiting to be accepted; the system default if not set.
+    pub listen_backlog: Option<usize>,
+    // Sizes of the kernel receive and send buffers of the listening socket, inherited by accepted sockets.
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    // Memory buffers of a single connection may take.
+    pub max_connection_memory: Option<usize>,
+    // Numbers of data and control frames a connection may have queued.
+    pub max_queued_frames: Option<(usize, usize)>,
+    // Memory connection buffers may take in total and what to do once it's exceeded.
+    pub memory_budget: Option<(usize, LoadShedding)>,
+    // Time clients have to send a heartbeat in, and what counts as one.
+    pub heartbeat: Option<(Duration, Heartbeat)>,
+    // Time a connection with pending output may go without writing anything.
+    pub write_timeout: Option<Duration>,
+    // Number of connections accepted per second.
+    pub max_accept_rate: Option<usize>,
+    // What to do when accepting fails for the lack of file descriptors.
+    pub fd_exhaustion: FdExhaustion,
+    // Length of the TCP Fast Open queue of the listening socket; TFO is disabled if not set.
+    pub fast_open: Option<usize>,
+    // DSCP value accepted connections are marked with.
+    pub dscp: Option<u8>,
+    pub max_message_size: Option<usize>,
+    // Interval of the Stats events.
+    pub stats_interval: Option<Duration>,
+    // Number of bytes read from a socket at a time.
+    pub read_buffer_size: usize,
+    // Bytes of a connection's messages waiting for the application at which reading from it stops and resumes.
+    pub read_watermarks: Option<(usize, usize)>,
+    // Bytes per second written to a connection.
+    pub max_send_rate: Option<usize>,
+    // Bytes per second written to all connections together.
+    pub total_send_rate: Option<usize>,
+    // Messages are delivered as SharedText and SharedBinary events.
+    pub shared_payloads: bool,
+    // Messages are delivered along with their frame metadata.
+    pub message_metadata: bool,
+    // Text and binary messages sent to connections are prefixed with sequence numbers.
+    pub sequence_numbers: bool,
+    // Protocol checks clients are held to.
+    pub strictness: Strictness,
+    // Close frames from clients aren't answered until the application closes the connection.
+    pub manual_close: bool,
+    // Status codes and reasons overriding the defaults of `close_status`.
+    pub close_statuses: HashMap<CloseCause, (u16, String)>,
+    // Directory to record the traffic received on connections to.
+    pub record_dir: Option<PathBuf>,
+    // Content types and bodies of the responses to plain GET requests on the given paths.
+    pub static_responses: HashMap<String, (String, Vec<u8>)>,
+    // Values of the Host header accepted in handshakes; any host is accepted if the list is empty.
+    pub allowed_hosts: Vec<String>,
+    // Receives the records of closed connections.
+    pub audit_log: Option<Box<Fn(ConnectionRecord) + Send>>
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            extensions: Vec::new(),
+            protocols: Vec::new(),
+            middleware: Vec::new(),
+            routes: Vec::new(),
+            backpressure: None,
+            event_queue: None,
+            notify_capacity: None,
+            supervision: SupervisionPolicy::Stop,
+            cluster: None,
+            sessions: None,
+            affinity: None,
+            admin_token: None,
+            max_handshake_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            listen_backlog: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            max_accept_rate: None,
+            fd_exhaustion: FdExhaustion::PauseAccepting,
+            memory_budget: None,
+            heartbeat: None,
+            write_timeout: None,
+            max_connection_memory: None,
+            max_queued_frames: None,
+            fast_open: None,
+            dscp: None,
+            max_message_size: None,
+            stats_interval: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            read_watermarks: None,
+            max_send_rate: None,
+            total_send_rate: None,
+            manual_close: false,
+            strictness: Strictness::Lenient,
+            shared_payloads: false,
+            message_metadata: false,
+            sequence_numbers: false,
+            close_statuses: HashMap::new(),
+            record_dir: None,
+            static_responses: HashMap::new(),
+            allowed_hosts: Vec::new(),
+            audit_log: None
+        }
+    }
+
+    /// Status code and reason to close connections with for the given cause.
+    pub fn close_status(&self, cause: CloseCause) -> (u16, &str) {
+        match self.close_statuses.get(&cause) {
+            Some(&(code, ref reason)) => (code, reason),
+            None => match cause {
+                CloseCause::Shutdown => (1001, ""),
+                CloseCause::Overload => (1013, "")
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+/// WebSocket protocol extensions (RFC 6455, section 9)
+
+use std::fmt;
+
+use frame::OpCode;
+
+/// Parameter of an extension as it appears in the `Sec-WebSocket-Extensions` header,
+/// e.g. `client_max_window_bits=10`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtensionParam {
+    pub name: String,
+    pub value: Option<String>
+}
+
+impl ExtensionParam {
+    pub fn new(name: &str, value: Option<&str>) -> ExtensionParam {
+        ExtensionParam {
+            name: name.to_string(),
+            value: value.map(|v| v.to_string())
+        }
+    }
+}
+
+impl fmt::Display for ExtensionParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Some(ref value) => write!(f, "{}={}", self.name, value),
+            None => write!(f, "{}", self.name)
+        }
+    }
+}
+
+/// Single extension entry of the `Sec-WebSocket-Extensions` header: a name followed by parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtensionOffer {
+    pub name: String,
+    pub params: Vec<ExtensionParam>
+}
+
+impl fmt::Display for ExtensionOffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.name));
+        for param in self.params.iter() {
+            try!(write!(f, "; {}", param));
+        }
+        Ok(())
+    }
+}
+
+/// Parses the value of a `Sec-WebSocket-Extensions` header into the list of offers,
+/// preserving the order in which the peer has listed them.
+pub fn parse_extensions(header: &str) -> Vec<ExtensionOffer> {
+    let mut offers = Vec::new();
+
+    for entry in header.split(',') {
+        let mut parts = entry.split(';').map(|part| part.trim());
+
+        let name = match parts.next() {
+            Some(name) if name.len() > 0 => name.to_string(),
+            _ => continue
+        };
+
+        let params = parts.filter(|part| part.len() > 0).map(|part| {
+            let mut kv = part.splitn(2, '=');
+            let key = kv.next().unwrap().trim();
+            let value = kv.next().map(|v| v.trim().trim_matches('"'));
+            ExtensionParam::new(key, value)
+        }).collect();
+
+        offers.push(ExtensionOffer {
+            name: name,
+            params: params
+        });
+    }
+
+    offers
+}
+
+/// Formats the negotiated extensions as a `Sec-WebSocket-Extensions` header value.
+pub fn format_extensions(offers: &[ExtensionOffer]) -> String {
+    offers.iter().map(|offer| offer.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Data frame as seen by extensions. Only Text and Binary frames are passed through the extensions.
+pub struct ExtensionFrame {
+    pub opcode: OpCode,
+    pub rsv: (bool, bool, bool),
+    pub payload: Vec<u8>
+}
+
+/// Protocol extension, such as `permessage-deflate`.
+///
+/// An instance registered on the builder serves as a prototype: every connection gets its own copy
+/// produced by `box_clone`, so extensions are free to keep per-connection state (e.g. compression contexts).
+pub trait Extension: Send {
+    /// Extension token used in the `Sec-WebSocket-Extensions` header.
+    fn name(&self) -> &str;
+
+    /// Examines parameters offered by the client. Returns the parameters to send back in the handshake response
+    /// if the extension is accepted, or `None` to decline the offer.
+    fn negotiate(&mut self, params: &[ExtensionParam]) -> Option<Vec<ExtensionParam>>;
+
+    /// RSV bits reserved by the extension. Frames with RSV bits that are not claimed by any
+    /// of the negotiated extensions are rejected.
+    fn rsv_flags(&self) -> (bool, bool, bool) {
+        (false, false, false)
+    }
+
+    /// Transforms an incoming frame. Extensions are applied in the reverse order of negotiation.
+    fn decode(&mut self, _frame: &mut ExtensionFrame) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Transforms an outgoing frame. Extensions are applied in the order of negotiation.
+    fn encode(&mut self, _frame: &mut ExtensionFrame) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Creates a fresh instance of the extension for a new connection.
+    fn box_clone(&self) -> Box<Extension>;
+}
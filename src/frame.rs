@@ -0,0 +1,515 @@
+/// WebSocket frames (RFC 6455, section 5)
+
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::usize;
+
+use bytes::Buf;
+use byteorder::{ByteOrder, BigEndian};
+
+// Largest payload buffer allocated up front; longer payloads grow as they arrive.
+const MAX_INITIAL_CAPACITY: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpCode {
+    ContinuationFrame,
+    TextFrame,
+    BinaryFrame,
+    ConnectionClose,
+    Ping,
+    Pong
+}
+
+impl OpCode {
+    pub fn from_bits(bits: u8) -> Option<OpCode> {
+        match bits {
+            0x0 => Some(OpCode::ContinuationFrame),
+            0x1 => Some(OpCode::TextFrame),
+            0x2 => Some(OpCode::BinaryFrame),
+            0x8 => Some(OpCode::ConnectionClose),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        match *self {
+            OpCode::ContinuationFrame => 0x0,
+            OpCode::TextFrame => 0x1,
+            OpCode::BinaryFrame => 0x2,
+            OpCode::ConnectionClose => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        match *self {
+            OpCode::ConnectionClose | OpCode::Ping | OpCode::Pong => true,
+            _ => false
+        }
+    }
+}
+
+/// Status code of a Close frame (RFC 6455, section 7.4).
+#[derive(Clone, Debug, PartialEq)]
+pub enum StatusCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnsupportedData,
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    MandatoryExtension,
+    InternalError,
+    Custom(u16)
+}
+
+impl StatusCode {
+    pub fn code(&self) -> u16 {
+        match *self {
+            StatusCode::Normal => 1000,
+            StatusCode::GoingAway => 1001,
+            StatusCode::ProtocolError => 1002,
+            StatusCode::UnsupportedData => 1003,
+            StatusCode::InvalidPayload => 1007,
+            StatusCode::PolicyViolation => 1008,
+            StatusCode::MessageTooBig => 1009,
+            StatusCode::MandatoryExtension => 1010,
+            StatusCode::InternalError => 1011,
+            StatusCode::Custom(code) => code
+        }
+    }
+}
+
+impl From<u16> for StatusCode {
+    fn from(code: u16) -> StatusCode {
+        match code {
+            1000 => StatusCode::Normal,
+            1001 => StatusCode::GoingAway,
+            1002 => StatusCode::ProtocolError,
+            1003 => StatusCode::UnsupportedData,
+            1007 => StatusCode::InvalidPayload,
+            1008 => StatusCode::PolicyViolation,
+            1009 => StatusCode::MessageTooBig,
+            1010 => StatusCode::MandatoryExtension,
+            1011 => StatusCode::InternalError,
+            code => StatusCode::Custom(code)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    InvalidOpCode(u8),
+    // Payload length with the most significant bit set, or one that doesn't fit into memory.
+    InvalidLength(u64),
+    // Payload length exceeding the limit of the reader.
+    PayloadTooLarge(u64),
+    InvalidCloseFrame
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::InvalidOpCode(bits) => write!(f, "invalid opcode {:#x}", bits),
+            ParseError::InvalidLength(len) => write!(f, "invalid payload length {}", len),
+            ParseError::PayloadTooLarge(len) => write!(f, "frame payload of {} bytes exceeds the limit", len),
+            ParseError::InvalidCloseFrame => write!(f, "Close frame with a truncated status code")
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseError::InvalidOpCode(..) => "invalid opcode",
+            ParseError::InvalidLength(..) => "invalid payload length",
+            ParseError::PayloadTooLarge(..) => "frame payload is too large",
+            ParseError::InvalidCloseFrame => "invalid Close frame"
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    fin: bool,
+    rsv: (bool, bool, bool),
+    opcode: OpCode,
+    masked: bool,
+    payload: Vec<u8>
+}
+
+impl Frame {
+    fn new(opcode: OpCode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            rsv: (false, false, false),
+            opcode: opcode,
+            masked: false,
+            payload: payload
+        }
+    }
+
+    pub fn close(code: StatusCode) -> Frame {
+        let mut payload = vec![0; 2];
+        BigEndian::write_u16(&mut payload, code.code());
+        Frame::new(OpCode::ConnectionClose, payload)
+    }
+
+    pub fn ping(payload: &[u8]) -> Frame {
+        Frame::new(OpCode::Ping, payload.to_vec())
+    }
+
+    pub fn pong(ping: &Frame) -> Frame {
+        Frame::new(OpCode::Pong, ping.payload.clone())
+    }
+
+    /// Close frame answering the one received, with the same status code.
+    pub fn close_from(frame: &Frame) -> Result<Frame, ParseError> {
+        match frame.payload.len() {
+            0 => Ok(Frame::new(OpCode::ConnectionClose, Vec::new())),
+            1 => Err(ParseError::InvalidCloseFrame),
+            _ => Ok(Frame::new(OpCode::ConnectionClose, frame.payload[..2].to_vec()))
+        }
+    }
+
+    /// Writes the frame unmasked, as servers send them. Returns the number of bytes written.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<usize> {
+        let (rsv1, rsv2, rsv3) = self.rsv;
+        let mut header = [0; 10];
+        header[0] = ((self.fin as u8) << 7) | ((rsv1 as u8) << 6) | ((rsv2 as u8) << 5) | ((rsv3 as u8) << 4) |
+            self.opcode.bits();
+        let header_len = if self.payload.len() < 126 {
+            header[1] = self.payload.len() as u8;
+            2
+        } else if self.payload.len() <= 0xFFFF {
+            header[1] = 126;
+            BigEndian::write_u16(&mut header[2..4], self.payload.len() as u16);
+            4
+        } else {
+            header[1] = 127;
+            BigEndian::write_u64(&mut header[2..10], self.payload.len() as u64);
+            10
+        };
+        try!(out.write_all(&header[..header_len]));
+        try!(out.write_all(&self.payload));
+        Ok(header_len + self.payload.len())
+    }
+
+    pub fn is_final(&self) -> bool {
+        self.fin
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.masked
+    }
+
+    pub fn get_rsv_flags(&self) -> (bool, bool, bool) {
+        self.rsv
+    }
+
+    pub fn get_opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    pub fn payload(&self) -> &Vec<u8> {
+        &self.payload
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+impl From<String> for Frame {
+    fn from(text: String) -> Frame {
+        Frame::new(OpCode::TextFrame, text.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for Frame {
+    fn from(data: Vec<u8>) -> Frame {
+        Frame::new(OpCode::BinaryFrame, data)
+    }
+}
+
+/// Length of the frame header, known once its first two bytes have been read.
+fn header_len(header: &[u8]) -> usize {
+    let mask_len = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    match header[1] & 0x7F {
+        126 => 4 + mask_len,
+        127 => 10 + mask_len,
+        _ => 2 + mask_len
+    }
+}
+
+/// Parses frames from the data read from a socket. Frames can be split across any number of reads;
+/// the reader keeps the part of a frame it has consumed until the rest arrives.
+pub struct BufferedFrameReader {
+    header: Vec<u8>,
+    mask: Option<[u8; 4]>,
+    max_payload: Option<usize>,
+    // Frame whose payload is being read, along with the number of payload bytes still to come.
+    frame: Option<(Frame, usize)>
+}
+
+impl BufferedFrameReader {
+    /// The reader fails with `PayloadTooLarge` on frames declaring a longer payload, before any of it is buffered.
+    pub fn new(max_payload: Option<usize>) -> BufferedFrameReader {
+        BufferedFrameReader {
+            header: Vec::with_capacity(14),
+            mask: None,
+            max_payload: max_payload,
+            frame: None
+        }
+    }
+
+    /// Reads a frame from the buffer. The buffer is consumed up to the end of the frame, so the next frame
+    /// can be read from the rest of it. Returns `None` once the buffer is exhausted without completing a frame.
+    pub fn read<B: Buf>(&mut self, buf: &mut B) -> Result<Option<Frame>, ParseError> {
+        if self.frame.is_none() && !try!(self.read_header(buf)) {
+            return Ok(None);
+        }
+
+        let remaining = {
+            let &mut (ref mut frame, ref mut remaining) = self.frame.as_mut().unwrap();
+            while *remaining > 0 && buf.has_remaining() {
+                let len = {
+                    let bytes = Buf::bytes(buf);
+                    let len = cmp::min(bytes.len(), *remaining);
+                    let offset = frame.payload.len();
+                    frame.payload.extend_from_slice(&bytes[..len]);
+                    if let Some(mask) = self.mask {
+                        for (i, byte) in frame.payload[offset..].iter_mut().enumerate() {
+                            *byte ^= mask[(offset + i) % 4];
+                        }
+                    }
+                    len
+                };
+                Buf::advance(buf, len);
+                *remaining -= len;
+            }
+            *remaining
+        };
+
+        if remaining > 0 {
+            return Ok(None);
+        }
+        Ok(self.frame.take().map(|(frame, _)| frame))
+    }
+
+    /// Reads the header of the next frame. Returns `false` if it hasn't been read entirely.
+    fn read_header<B: Buf>(&mut self, buf: &mut B) -> Result<bool, ParseError> {
+        loop {
+            let needed = if self.header.len() < 2 { 2 } else { header_len(&self.header) };
+            if self.header.len() == needed {
+                break;
+            }
+            if !buf.has_remaining() {
+                return Ok(false);
+            }
+            let len = {
+                let bytes = Buf::bytes(buf);
+                let len = cmp::min(bytes.len(), needed - self.header.len());
+                self.header.extend_from_slice(&bytes[..len]);
+                len
+            };
+            Buf::advance(buf, len);
+        }
+
+        let header = mem::replace(&mut self.header, Vec::with_capacity(14));
+        let opcode = match OpCode::from_bits(header[0] & 0x0F) {
+            Some(opcode) => opcode,
+            None => return Err(ParseError::InvalidOpCode(header[0] & 0x0F))
+        };
+        let masked = header[1] & 0x80 != 0;
+        let (len, mask_pos) = match header[1] & 0x7F {
+            126 => (BigEndian::read_u16(&header[2..4]) as u64, 4),
+            127 => (BigEndian::read_u64(&header[2..10]), 10),
+            len => (len as u64, 2)
+        };
+        // The most significant bit of a 64-bit length must be 0 (RFC 6455, section 5.2).
+        if len & (1 << 63) != 0 || len > usize::MAX as u64 {
+            return Err(ParseError::InvalidLength(len));
+        }
+        if let Some(max_payload) = self.max_payload {
+            if len > max_payload as u64 {
+                return Err(ParseError::PayloadTooLarge(len));
+            }
+        }
+        self.mask = if masked {
+            Some([header[mask_pos], header[mask_pos + 1], header[mask_pos + 2], header[mask_pos + 3]])
+        } else {
+            None
+        };
+
+        let len = len as usize;
+        let frame = Frame {
+            fin: header[0] & 0x80 != 0,
+            rsv: (header[0] & 0x40 != 0, header[0] & 0x20 != 0, header[0] & 0x10 != 0),
+            opcode: opcode,
+            masked: masked,
+            payload: Vec::with_capacity(cmp::min(len, MAX_INITIAL_CAPACITY))
+        };
+        self.frame = Some((frame, len));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, ByteBuf};
+
+    use super::{BufferedFrameReader, Frame, OpCode, ParseError};
+
+    const MASK: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+
+    /// Serializes a final binary frame masked the way clients send them.
+    fn client_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x82];
+        let len = payload.len() as u64;
+        if len < 126 {
+            frame.push(0x80 | len as u8);
+        } else if len <= 0xFFFF {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+        } else {
+            frame.push(0x80 | 127);
+            frame.extend((0..8).rev().map(|i| (len >> (i * 8)) as u8));
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        frame
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    /// Feeds the data in chunks of the given sizes, returning the frame once it's complete.
+    fn read_in_chunks(data: &[u8], chunks: &[usize]) -> Frame {
+        let mut reader = BufferedFrameReader::new(None);
+        let mut pos = 0;
+        for &len in chunks {
+            let mut buf = ByteBuf::from_slice(&data[pos..pos + len]);
+            pos += len;
+            let frame = reader.read(&mut buf).unwrap();
+            assert_eq!(buf.remaining(), 0);
+            if pos == data.len() {
+                return frame.expect("frame is complete");
+            }
+            assert!(frame.is_none(), "frame is complete after {} of {} bytes", pos, data.len());
+        }
+        panic!("chunks don't cover the frame");
+    }
+
+    #[test]
+    fn lengths() {
+        for &len in &[0, 5, 125, 126, 300, 0xFFFF, 0x10000, 70000] {
+            let data = client_frame(&payload(len));
+            let frame = read_in_chunks(&data, &[data.len()]);
+            assert_eq!(frame.get_opcode(), OpCode::BinaryFrame);
+            assert!(frame.is_final());
+            assert!(frame.is_masked());
+            assert_eq!(*frame.payload(), payload(len));
+        }
+    }
+
+    #[test]
+    fn length_with_msb_set() {
+        let mut buf = ByteBuf::from_slice(&[0x82, 0x7F, 0x80, 0, 0, 0, 0, 0, 0, 0x01]);
+        match BufferedFrameReader::new(None).read(&mut buf) {
+            Err(ParseError::InvalidLength(len)) => assert_eq!(len, 0x8000000000000001),
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn payload_too_large() {
+        let data = client_frame(&payload(1000));
+        let mut reader = BufferedFrameReader::new(Some(999));
+        let mut buf = ByteBuf::from_slice(&data);
+        match reader.read(&mut buf) {
+            Err(ParseError::PayloadTooLarge(len)) => assert_eq!(len, 1000),
+            other => panic!("unexpected result {:?}", other)
+        }
+        // Only the header has been consumed.
+        assert_eq!(buf.remaining(), 1000);
+
+        let data = client_frame(&payload(999));
+        let mut buf = ByteBuf::from_slice(&data);
+        let frame = BufferedFrameReader::new(Some(999)).read(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.payload().len(), 999);
+    }
+
+    #[test]
+    fn payload_split_across_reads() {
+        // The 6-byte header is followed by chunks starting at payload offsets 3, 8, 9 and 14.
+        let data = client_frame(&payload(20));
+        let frame = read_in_chunks(&data, &[6, 3, 5, 1, 5, 6]);
+        assert_eq!(*frame.payload(), payload(20));
+    }
+
+    #[test]
+    fn header_split_across_reads() {
+        // Splits within the extended length and the mask.
+        let data = client_frame(&payload(300));
+        let frame = read_in_chunks(&data, &[1, 2, 1, 3, 1, 300]);
+        assert_eq!(*frame.payload(), payload(300));
+
+        let data = client_frame(&payload(70000));
+        let frame = read_in_chunks(&data, &[1, 1, 5, 4, 3, 70000]);
+        assert_eq!(*frame.payload(), payload(70000));
+    }
+
+    #[test]
+    fn two_frames_in_one_buffer() {
+        let mut data = client_frame(b"first");
+        data.extend_from_slice(&[0x89, 0x80, 0, 0, 0, 0]);
+        data.extend(client_frame(b"second"));
+
+        let mut reader = BufferedFrameReader::new(None);
+        let mut buf = ByteBuf::from_slice(&data);
+        assert_eq!(*reader.read(&mut buf).unwrap().unwrap().payload(), b"first");
+        let ping = reader.read(&mut buf).unwrap().unwrap();
+        assert_eq!(ping.get_opcode(), OpCode::Ping);
+        assert!(ping.payload().is_empty());
+        assert_eq!(*reader.read(&mut buf).unwrap().unwrap().payload(), b"second");
+        assert_eq!(buf.remaining(), 0);
+        assert!(reader.read(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        for &bits in &[0x3, 0x7, 0xB, 0xF] {
+            let mut buf = ByteBuf::from_slice(&[0x80 | bits, 0x00]);
+            match BufferedFrameReader::new(None).read(&mut buf) {
+                Err(ParseError::InvalidOpCode(opcode)) => assert_eq!(opcode, bits),
+                other => panic!("unexpected result {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn write_round_trip() {
+        for &(len, len_byte, header_len) in &[(125, 125, 2), (126, 126, 4), (0xFFFF, 126, 4), (0x10000, 127, 10)] {
+            let mut out = Vec::new();
+            assert_eq!(Frame::from(payload(len)).write(&mut out).unwrap(), header_len + len);
+            assert_eq!(out.len(), header_len + len);
+            assert_eq!(out[0], 0x82);
+            assert_eq!(out[1], len_byte);
+
+            let mut buf = ByteBuf::from_slice(&out);
+            let frame = BufferedFrameReader::new(None).read(&mut buf).unwrap().unwrap();
+            assert_eq!(frame.get_opcode(), OpCode::BinaryFrame);
+            assert!(!frame.is_masked());
+            assert_eq!(*frame.payload(), payload(len));
+        }
+    }
+}
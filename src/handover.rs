@@ -0,0 +1,81 @@
+/// Passing the listening socket between processes, so that a new version of the server can take over
+/// accepting connections without closing the socket and losing its backlog.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::net;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use libc;
+
+// First descriptor passed according to the systemd socket activation protocol.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Checks that a descriptor is a socket in the listening state.
+fn is_listening_socket(fd: RawFd) -> bool {
+    let mut accepting: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN,
+                         &mut accepting as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    res == 0 && accepting != 0
+}
+
+/// Takes a listening socket passed by systemd socket activation (`sd_listen_fds`): descriptors starting from 3,
+/// their number in `LISTEN_FDS` and their names in `LISTEN_FDNAMES`. `LISTEN_PID`, if it's set,
+/// must match the current process. With `name`, the socket with that name is taken; otherwise the first one.
+pub fn listen_fds(name: Option<&str>) -> Option<net::TcpListener> {
+    let count = match env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<RawFd>().ok()) {
+        Some(count) if count > 0 => count,
+        _ => return None
+    };
+
+    if let Ok(pid) = env::var("LISTEN_PID") {
+        if pid.parse::<libc::pid_t>().ok() != Some(unsafe { libc::getpid() }) {
+            return None;
+        }
+    }
+
+    let names = env::var("LISTEN_FDNAMES").unwrap_or(String::new());
+    let names = names.split(':').collect::<Vec<_>>();
+
+    // Children shouldn't take the sockets over again.
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let index = match name {
+        Some(name) => match names.iter().position(|fd_name| *fd_name == name) {
+            Some(index) if (index as RawFd) < count => index as RawFd,
+            _ => {
+                warn!("no socket named {} has been passed", name);
+                return None;
+            }
+        },
+        None => 0
+    };
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // As sd_listen_fds does, keep the passed sockets from leaking into child processes.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    let fd = LISTEN_FDS_START + index;
+    if !is_listening_socket(fd) {
+        warn!("descriptor {} is not a listening socket", fd);
+        return None;
+    }
+
+    Some(unsafe { net::TcpListener::from_raw_fd(fd) })
+}
+
+/// Duplicates a socket descriptor. The duplicate is inherited by child processes.
+pub fn export(fd: RawFd) -> io::Result<RawFd> {
+    let exported = unsafe { libc::dup(fd) };
+    if exported < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(exported)
+}
@@ -0,0 +1,94 @@
+//! Computation and validation of the keys of the opening handshake (RFC 6455, section 4).
+
+use sha1::Sha1;
+
+const WEBSOCKET_KEY: &'static [u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Computes the value of `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`.
+pub fn gen_key(key: &str) -> String {
+    let mut m = Sha1::new();
+    let mut buf = [0u8; 20];
+
+    m.update(key.as_bytes());
+    m.update(WEBSOCKET_KEY);
+
+    m.output(&mut buf);
+
+    base64_encode(&buf)
+}
+
+/// Checks that a `Sec-WebSocket-Key` is a base64-encoded 16-byte nonce: 22 characters followed by `==`.
+pub fn is_valid_key(key: &str) -> bool {
+    let key = key.as_bytes();
+    if key.len() != 24 || &key[22..] != b"==" {
+        return false;
+    }
+    match base64_value(key[21]) {
+        // The last character encodes the remaining 2 bits of the nonce, padded with zeros.
+        Some(value) => value & 0x0F == 0 && key[..21].iter().all(|&c| base64_value(c).is_some()),
+        None => false
+    }
+}
+
+/// Standard base64 encoding with padding (RFC 4648, section 4).
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+    BASE64_ALPHABET.iter().position(|&a| a == c).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64_encode, gen_key, is_valid_key};
+
+    #[test]
+    fn base64_vectors() {
+        // RFC 4648, section 10.
+        let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="),
+                       ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for &(data, encoded) in &vectors {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn accept_key() {
+        // RFC 6455, section 1.3.
+        assert_eq!(gen_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn valid_keys() {
+        assert!(is_valid_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(is_valid_key("x3JJHMbDL1EzLkh9GBhXDw=="));
+    }
+
+    #[test]
+    fn invalid_keys() {
+        // Wrong lengths.
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25jZQ"));
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25jZQ==dGhl"));
+        // Characters outside of the alphabet.
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25j*Q=="));
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25jZQ=!"));
+        // 24 characters that don't decode to 16 bytes: 18 bytes without padding, and trailing bits set.
+        assert!(!is_valid_key("c2FtcGxlIG5vbmNlIG9mIDE4"));
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25jZR=="));
+    }
+}
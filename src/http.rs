@@ -1,29 +1,191 @@
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::str;
 
 use http_muncher::ParserHandler;
 
+struct Header {
+    values: Vec<String>,
+    combined: String
+}
+
+/// Request headers. Names are case-insensitive. All values of a repeated header are kept,
+/// and are also available combined into a comma-separated list (RFC 7230, section 3.2.2);
+/// `Cookie` values are combined with semicolons instead (RFC 6265, section 5.4).
+pub struct Headers {
+    headers: HashMap<String, Header>
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers {
+            headers: HashMap::new()
+        }
+    }
+
+    /// Returns the combined value of a header.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|header| &header.combined[..])
+    }
+
+    /// Returns all values of a header in the order they've been received.
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.headers.get(&name.to_lowercase()).map(|header| &header.values[..]).unwrap_or(&[])
+    }
+
+    /// Appends a piece of a header value, starting a new value of the header if `new_value` is set.
+    fn push(&mut self, name: String, piece: &str, new_value: bool) {
+        let separator = if name == "cookie" { "; " } else { ", " };
+        let header = self.headers.entry(name).or_insert_with(|| Header {
+            values: Vec::new(),
+            combined: String::new()
+        });
+        if new_value || header.values.is_empty() {
+            if !header.values.is_empty() {
+                header.combined.push_str(separator);
+            }
+            header.values.push(String::new());
+        }
+        header.values.last_mut().unwrap().push_str(piece);
+        header.combined.push_str(piece);
+    }
+}
+
+/// Handshake request parser state. Header names are stored in lower case.
 pub struct HttpParser {
     pub current_key: Option<String>,
-    pub headers: Rc<RefCell<HashMap<String, String>>>
+    // Header names and values may be split between reads and come in several pieces.
+    // A piece of a name following a value starts a new header.
+    pub value_started: bool,
+    pub headers: Rc<RefCell<Headers>>,
+    pub url: Rc<RefCell<String>>,
+    pub headers_complete: Rc<Cell<bool>>
+}
+
+/// Checks whether a comma-separated header value, such as `Connection: keep-alive, Upgrade`,
+/// contains a token. Tokens are compared case-insensitively.
+pub fn has_token(value: &str, token: &str) -> bool {
+    let token = token.to_lowercase();
+    value.split(',').any(|item| item.trim().to_lowercase() == token)
+}
+
+/// Strips the port from a `Host` header value, e.g. `example.com:8080` or `[::1]:8080`.
+pub fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..end + 1],
+            None => host
+        };
+    }
+    match host.rfind(':') {
+        Some(pos) => &host[..pos],
+        None => host
+    }
+}
+
+/// Extracts a percent-decoded parameter from the query string of a request URL. See `query_params`.
+pub fn query_param(url: &str, name: &str) -> Option<String> {
+    query_params(url).remove(name)
+}
+
+/// Path of a request URL without the query string.
+pub fn path(url: &str) -> &str {
+    url.split('?').next().unwrap_or("")
+}
+
+/// Decodes a percent-encoded query string component; `+` stands for a space. Malformed escapes are kept as is,
+/// and invalid UTF-8 is replaced.
+pub fn percent_decode(s: &str) -> String {
+    decode(s, true)
+}
+
+/// Decodes a percent-encoded path segment, where `+` is just a plus.
+pub fn percent_decode_path(s: &str) -> String {
+    decode(s, false)
+}
+
+fn decode(s: &str, plus_as_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => decoded.push(b' '),
+            b'%' => {
+                match (hex_digit(bytes.get(i + 1)), hex_digit(bytes.get(i + 2))) {
+                    (Some(high), Some(low)) => {
+                        decoded.push(high << 4 | low);
+                        i += 2;
+                    },
+                    _ => decoded.push(b'%')
+                }
+            },
+            byte => decoded.push(byte)
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_digit(c: Option<&u8>) -> Option<u8> {
+    match c {
+        Some(&c @ b'0'..=b'9') => Some(c - b'0'),
+        Some(&c @ b'a'..=b'f') => Some(c - b'a' + 10),
+        Some(&c @ b'A'..=b'F') => Some(c - b'A' + 10),
+        _ => None
+    }
+}
+
+/// Parses the query string of a request URL into percent-decoded names and values.
+/// A parameter without a value has an empty one; of repeated parameters, the first one is kept.
+pub fn query_params(url: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let query = match url.find('?') {
+        Some(pos) => &url[pos + 1..],
+        None => return params
+    };
+    // The fragment isn't sent by browsers, but may be by other clients.
+    let query = query.split('#').next().unwrap_or("");
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        let name = percent_decode(kv.next().unwrap_or(""));
+        let value = percent_decode(kv.next().unwrap_or(""));
+        params.entry(name).or_insert(value);
+    }
+    params
 }
 
 impl ParserHandler for HttpParser {
+    fn on_url(&mut self, s: &[u8]) -> bool {
+        self.url.borrow_mut().push_str(&String::from_utf8_lossy(s));
+        true
+    }
+
     fn on_header_field(&mut self, s: &[u8]) -> bool {
-        self.current_key = Some(str::from_utf8(s).unwrap().to_string());
+        let piece = String::from_utf8_lossy(s);
+        if self.value_started || self.current_key.is_none() {
+            self.current_key = Some(piece.into_owned());
+            self.value_started = false;
+        } else if let Some(ref mut key) = self.current_key {
+            key.push_str(&piece);
+        }
         true
     }
 
     fn on_header_value(&mut self, s: &[u8]) -> bool {
-        let mut headers = self.headers.borrow_mut();
-        headers.insert(self.current_key.clone().unwrap(),
-                        str::from_utf8(s).unwrap().to_string());
+        let key = match self.current_key {
+            Some(ref key) => key.to_lowercase(),
+            None => return false
+        };
+        let piece = String::from_utf8_lossy(s);
+
+        self.headers.borrow_mut().push(key, &piece, !self.value_started);
+        self.value_started = true;
         true
     }
 
     fn on_headers_complete(&mut self) -> bool {
+        self.headers_complete.set(true);
         false
     }
 }
@@ -1,15 +1,84 @@
 /// High-level WebSocket library interface
 
-use std::net::SocketAddr;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{self, IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
 use std::thread;
-use std::sync::mpsc;
-use std::time::Duration;
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use mio::{Token, EventLoop, EventSet, PollOpt, Sender, NotifyError};
+use mio::{Token, EventLoop, EventLoopConfig, Evented, EventSet, PollOpt, Sender, NotifyError};
 use mio::tcp::{TcpListener};
-use websocket_essentials::{StatusCode};
+pub use frame::{OpCode, StatusCode};
 
-use server::{WebSocketServer, SERVER_TOKEN};
+use bus::EventBus;
+use clock;
+use cluster::Relay;
+use channel::{self, EventSender, EventReceiver};
+use config::Config;
+#[cfg(unix)]
+use handover;
+use extension::{Extension, ExtensionOffer};
+use middleware::HandshakeMiddleware;
+use route::Route;
+use notify::NotifyGate;
+use server::{self, Timer, WebSocketServer, SERVER_TOKEN};
+#[cfg(unix)]
+use sockopt;
+use source::SharedSource;
+#[cfg(feature = "testing")]
+use testing::TestConnection;
+
+// Number of events that can be queued for a dispatcher worker.
+const WORKER_QUEUE_SIZE: usize = 256;
+
+/// Identifies a connection. Unlike event loop tokens, which are reused after clients disconnect,
+/// identifiers are never reused while the server is running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId {
+    token: Token,
+    generation: u64
+}
+
+impl ConnectionId {
+    #[doc(hidden)]
+    pub fn new(token: Token, generation: u64) -> ConnectionId {
+        ConnectionId {
+            token: token,
+            generation: generation
+        }
+    }
+
+    /// Id used for events that aren't related to any particular connection, such as `ServerError`.
+    pub fn server() -> ConnectionId {
+        ConnectionId::new(SERVER_TOKEN, 0)
+    }
+
+    pub fn is_server(&self) -> bool {
+        self.token == SERVER_TOKEN
+    }
+
+    #[doc(hidden)]
+    pub fn token(&self) -> Token {
+        self.token
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.token.as_usize(), self.generation)
+    }
+}
 
 #[derive(Clone)]
 pub enum WebSocketEvent {
@@ -17,68 +86,1509 @@ pub enum WebSocketEvent {
     Close(StatusCode),
     Ping(Box<[u8]>),
     Pong(Box<[u8]>),
+    /// A message sent with `send_with_ttl` hasn't been written to the connection in time and has been discarded.
+    Dropped(WebSocketMessage),
+    /// A client has answered a ping sent by the server; carries the measured round-trip time.
+    Heartbeat(Duration),
     TextMessage(String),
-    BinaryMessage(Vec<u8>)
+    BinaryMessage(Vec<u8>),
+    /// Messages delivered instead of `TextMessage` and `BinaryMessage` when shared payloads are enabled.
+    /// They can be sent on to any number of connections as `WebSocketMessage::SharedText` and `SharedBinary`
+    /// without copying the payload for each of them.
+    SharedText(Arc<str>),
+    SharedBinary(Arc<[u8]>),
+    /// Message event along with the way the message has arrived, delivered instead of the message event
+    /// when enabled with `WebSocketBuilder::message_metadata`.
+    WithMetadata(MessageMetadata, Box<WebSocketEvent>),
+    /// Data frame received from a connection in the raw mode.
+    Frame(RawFrame),
+    /// The connection has been terminated abnormally.
+    Error(WsError),
+    /// The connection has been torn down. This is the last event delivered for a connection.
+    Disconnected {
+        reason: DisconnectReason,
+        /// Status code of the closing handshake, if there was one.
+        code: Option<StatusCode>
+    },
+    /// An external source added with `WsSender::add_source` has become ready. It's reported with
+    /// `ConnectionId::server()` along with the token the source has been added with.
+    SourceReady(usize, EventSet),
+    /// Server counters reported periodically if enabled with `WebSocketBuilder::stats_interval`.
+    /// It's reported with `ConnectionId::server()`.
+    Stats(ServerStats),
+    /// The event loop has failed and all connections have been dropped. It's reported with `ConnectionId::server()`
+    /// to all subscribers regardless of their filters. Unless the server is restarted according to
+    /// the supervision policy, no more events follow.
+    ServerError(String)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The client has initiated the closing handshake.
+    Clean,
+    /// The server has initiated the closing handshake.
+    ServerInitiated,
+    /// The client has violated the protocol.
+    ProtocolError,
+    /// The connection has been dropped without the closing handshake.
+    Reset,
+    /// The connection has timed out.
+    Timeout,
+    /// The client hasn't read the data sent to it in time. See `WebSocketBuilder::write_timeout`.
+    SlowConsumer
+}
+
+#[derive(Clone, Debug)]
+pub enum WsError {
+    /// Reading from or writing to the socket has failed, or the connection has been dropped
+    /// without the closing handshake.
+    Io(io::ErrorKind, String),
+    Handshake(String),
+    /// The client has violated the protocol. The connection is closed with an appropriate status code.
+    Protocol(String)
+}
+
+impl From<io::Error> for WsError {
+    fn from(err: io::Error) -> WsError {
+        WsError::Io(err.kind(), err.to_string())
+    }
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WsError::Io(_, ref msg) => write!(f, "I/O error: {}", msg),
+            WsError::Handshake(ref msg) => write!(f, "Handshake error: {}", msg),
+            WsError::Protocol(ref msg) => write!(f, "Protocol error: {}", msg)
+        }
+    }
+}
+
+impl Error for WsError {
+    fn description(&self) -> &str {
+        match *self {
+            WsError::Io(_, ref msg) | WsError::Handshake(ref msg) | WsError::Protocol(ref msg) => msg
+        }
+    }
+}
+
+/// Selects the kinds of events delivered to a subscriber.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventFilter {
+    All,
+    /// Text and binary messages, and raw frames.
+    Messages,
+    TextMessages,
+    BinaryMessages,
+    /// Connect, Close, Error and Disconnected events.
+    Connections,
+    /// Readiness of external sources.
+    Sources,
+    /// Periodic server stats.
+    Stats
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &WebSocketEvent) -> bool {
+        match (*self, event) {
+            (EventFilter::All, _) => true,
+            (_, &WebSocketEvent::ServerError(_)) => true,
+            (filter, &WebSocketEvent::WithMetadata(_, ref event)) => filter.matches(event),
+            (EventFilter::Messages, &WebSocketEvent::TextMessage(_)) |
+            (EventFilter::Messages, &WebSocketEvent::BinaryMessage(_)) |
+            (EventFilter::Messages, &WebSocketEvent::SharedText(_)) |
+            (EventFilter::Messages, &WebSocketEvent::SharedBinary(_)) |
+            (EventFilter::Messages, &WebSocketEvent::Frame(_)) => true,
+            (EventFilter::TextMessages, &WebSocketEvent::TextMessage(_)) |
+            (EventFilter::TextMessages, &WebSocketEvent::SharedText(_)) => true,
+            (EventFilter::BinaryMessages, &WebSocketEvent::BinaryMessage(_)) |
+            (EventFilter::BinaryMessages, &WebSocketEvent::SharedBinary(_)) => true,
+            (EventFilter::Connections, &WebSocketEvent::Connect) |
+            (EventFilter::Connections, &WebSocketEvent::Close(_)) |
+            (EventFilter::Connections, &WebSocketEvent::Error(_)) |
+            (EventFilter::Connections, &WebSocketEvent::Disconnected { .. }) => true,
+            (EventFilter::Sources, &WebSocketEvent::SourceReady(..)) => true,
+            (EventFilter::Stats, &WebSocketEvent::Stats(_)) => true,
+            _ => false
+        }
+    }
+}
+
+/// Determines what happens to an event that doesn't fit into a full events queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Stop reading from sockets until the application drains the queue to half of its capacity.
+    Block,
+    /// Discard the oldest queued event.
+    DropOldest,
+    /// Discard the new event.
+    DropNewest,
+    /// Close the connection that has produced the event with the status of `CloseCause::Overload`.
+    CloseConnection
+}
+
+/// Determines how the server sheds load once connections hold more memory than the budget allows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadShedding {
+    /// Stop accepting connections until the memory taken goes down.
+    StopAccepting,
+    /// Stop accepting connections and close the connections holding the most memory
+    /// with the status of `CloseCause::Overload`, discarding the messages queued for them.
+    CloseHeaviest
+}
+
+/// How closely clients are held to RFC 6455. Violations of the checks in effect fail the connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strictness {
+    /// All checks, including the one most clients get away with: frames must be masked.
+    /// This is the profile tested with the Autobahn test suite.
+    Strict,
+    /// The default. Text messages must be valid UTF-8, RSV bits must be claimed by negotiated extensions,
+    /// control frames must be final and carry at most 125 bytes, and Close frames must be empty or carry
+    /// a valid status code and a UTF-8 reason.
+    Lenient,
+    /// Only the checks needed to make sense of the stream, for legacy clients. Invalid UTF-8 in text messages
+    /// is replaced, unclaimed RSV bits are ignored, and control frames are taken as they come.
+    Permissive
+}
+
+/// Determines what the server does when it runs out of file descriptors for new connections.
+/// In either case, accepting is paused and retried periodically until it succeeds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FdExhaustion {
+    /// Only wait for connections to go away.
+    PauseAccepting,
+    /// Also close the connection that has been idle for the longest time, on every retry,
+    /// with the status of `CloseCause::Overload`.
+    CloseIdlest
+}
+
+/// What counts as a heartbeat of a client. See `WebSocketBuilder::require_heartbeat`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Heartbeat {
+    /// Any frame, control frames included.
+    AnyFrame,
+    /// A text message with exactly this content, e.g. an application-level `ping`.
+    /// It's delivered to the application like any other message.
+    Message(String)
+}
+
+/// Reasons for the server to close connections on its own. The status code and the reason sent
+/// for each of them can be changed with `WebSocketBuilder::close_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CloseCause {
+    /// The server is shutting down. 1001 (Going Away) by default.
+    Shutdown,
+    /// The connection has overflowed the events queue. 1013 (Try Again Later) by default.
+    Overload
+}
+
+/// Scheduling class of a connection or a message.
+///
+/// Messages of higher priority are written before the messages of lower priority queued for the same connection.
+/// High priority connections can write regardless of the server-wide send budget, while low priority connections
+/// write in smaller portions, so that bulk transfers don't hold up the event loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High,
+    Normal,
+    Low
+}
+
+/// Determines what happens when the event loop panics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SupervisionPolicy {
+    /// Stop the server. Receivers get disconnected once they have received the remaining events.
+    Stop,
+    /// Drop all connections and restart the event loop, binding the listening socket again.
+    /// Gives up after `max_restarts` restarts.
+    Restart { max_restarts: usize }
+}
+
+fn panic_message(payload: Box<Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "event loop has panicked".to_string()
+    }
+}
+
+/// Message to be sent to a client.
+#[derive(Clone)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Box<[u8]>),
+    Close(StatusCode),
+    /// Text and binary messages with payloads shared between recipients; the payload isn't copied
+    /// for each connection the message is sent to.
+    SharedText(Arc<str>),
+    SharedBinary(Arc<[u8]>),
+    /// Frame sent as is, bypassing extensions.
+    Frame(RawFrame),
+    /// One or more complete frames, already serialized. They're written to the connection verbatim.
+    Serialized(Vec<u8>)
+}
+
+/// A single frame, as it's been sent over the wire (without the masking).
+#[derive(Clone)]
+pub struct RawFrame {
+    pub fin: bool,
+    pub opcode: OpCode,
+    pub rsv: (bool, bool, bool),
+    pub payload: Vec<u8>
+}
+
+/// Frame metadata of a received message.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageMetadata {
+    /// Opcode of the first frame of the message.
+    pub opcode: OpCode,
+    /// RSV bits of the first frame, before the message has been decoded by extensions.
+    pub rsv: (bool, bool, bool),
+    /// Number of frames the message has been sent in. It's 1 if the first frame had the FIN bit set.
+    pub frames: usize
+}
+
+/// Traffic counters of a connection. Only text and binary messages are counted as messages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionStats {
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Payload bytes of the messages passed through the negotiated extensions, before and after them:
+    /// with `permessage-deflate`, the compressed and the uncompressed sizes. Messages are only counted
+    /// if the connection has negotiated extensions.
+    pub compressed_bytes_received: u64,
+    pub uncompressed_bytes_received: u64,
+    pub compressed_bytes_sent: u64,
+    pub uncompressed_bytes_sent: u64,
+    /// Round-trip time measured with the latest answered ping. It's not summed up in server totals.
+    pub rtt: Option<Duration>
+}
+
+impl ConnectionStats {
+    /// Adds the traffic counters of another connection to these ones.
+    pub fn add(&mut self, other: &ConnectionStats) {
+        self.messages_received += other.messages_received;
+        self.messages_sent += other.messages_sent;
+        self.bytes_received += other.bytes_received;
+        self.bytes_sent += other.bytes_sent;
+        self.compressed_bytes_received += other.compressed_bytes_received;
+        self.uncompressed_bytes_received += other.uncompressed_bytes_received;
+        self.compressed_bytes_sent += other.compressed_bytes_sent;
+        self.uncompressed_bytes_sent += other.uncompressed_bytes_sent;
+    }
+}
+
+/// Counters of the whole server. Traffic is counted since the server has started,
+/// so throughput is the difference between two reports divided by the time between them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerStats {
+    pub connections_accepted: u64,
+    pub connections_open: u64,
+    pub traffic: ConnectionStats
+}
+
+/// Details of a connection agreed upon during the handshake.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub peer_addr: SocketAddr,
+    /// Time when the connection has been accepted.
+    pub connected_since: SystemTime,
+    /// Subprotocol selected from the client's `Sec-WebSocket-Protocol` list.
+    pub protocol: Option<String>,
+    /// Accepted extensions along with the parameters sent back to the client.
+    pub extensions: Vec<ExtensionOffer>,
+    /// Session token presented by the client, if sessions are enabled.
+    pub session: Option<String>,
+    /// ID of the server instance the client has been connected to before, as presented by the client
+    /// if session affinity is enabled. See `WebSocketBuilder::session_affinity`.
+    pub affinity: Option<String>,
+    /// Pattern of the route the connection has been accepted on, if routes are configured.
+    pub route: Option<String>,
+    /// Parameters captured by the route.
+    pub route_params: HashMap<String, String>,
+    /// Percent-decoded parameters of the query string of the request URL, e.g. `?token=...&room=...`.
+    pub query: HashMap<String, String>,
+    /// Attributes attached by the handshake middleware.
+    pub attributes: HashMap<String, String>,
+    /// Tags attached by the application. See `WsSender::tag`.
+    pub tags: HashSet<String>,
+    /// Sequence number of the last message written to the connection, if sequence numbers are enabled.
+    pub delivered_sequence: Option<u64>,
+    pub stats: ConnectionStats
+}
+
+/// Summary of a connection passed to the audit log once the connection is torn down.
+/// See `WebSocketBuilder::audit_log`.
+#[derive(Clone, Debug)]
+pub struct ConnectionRecord {
+    pub id: ConnectionId,
+    pub peer_addr: SocketAddr,
+    pub connected_since: SystemTime,
+    /// Time the connection has lasted for.
+    pub duration: Duration,
+    /// Pattern of the route the connection has been accepted on, if routes are configured.
+    pub route: Option<String>,
+    pub reason: DisconnectReason,
+    /// Status code of the closing handshake, if there was one.
+    pub code: Option<StatusCode>,
+    pub stats: ConnectionStats
 }
 
 pub enum WebSocketInternalMessage {
-    GetPeers(mpsc::Sender<Vec<Token>>),
-    SendMessage((Token,WebSocketEvent)),
-    Reregister(Token)
+    GetPeers(mpsc::Sender<Vec<ConnectionInfo>>),
+    GetConnectionInfo(ConnectionId, mpsc::Sender<Option<ConnectionInfo>>),
+    Flush(ConnectionId, mpsc::Sender<bool>),
+    Subscribe(EventFilter, Option<String>, EventSender),
+    SendMessage((ConnectionId,WebSocketMessage)),
+    SendExpiring(ConnectionId, WebSocketMessage, Instant),
+    SendPrioritized(ConnectionId, WebSocketMessage, Priority),
+    SetPriority(ConnectionId, Priority),
+    SendToSession(String, WebSocketMessage),
+    Pause(ConnectionId),
+    Resume(ConnectionId),
+    SetRawMode(ConnectionId, bool),
+    #[cfg(unix)]
+    SetSocketBuffers(ConnectionId, Option<usize>, Option<usize>),
+    #[cfg(unix)]
+    SetDscp(ConnectionId, u8),
+    Pipe(ConnectionId, ConnectionId),
+    Unpipe(ConnectionId),
+    AddSource(usize, Box<Evented + Send>, EventSet),
+    RemoveSource(usize),
+    // Sent by a receiver that has caught up with the events queue after the event loop stopped reading.
+    ResumeReading,
+    JoinRoom(ConnectionId, String),
+    LeaveRoom(ConnectionId, String),
+    Publish(String, WebSocketMessage),
+    Tag(ConnectionId, String),
+    Untag(ConnectionId, String),
+    SendToTag(String, WebSocketMessage),
+    BroadcastExcept(Vec<ConnectionId>, WebSocketMessage),
+    // Close the connections from an address, and optionally stop accepting new ones from it.
+    KickAddr(IpAddr, StatusCode, bool),
+    UnblockAddr(IpAddr),
+    #[cfg(unix)]
+    ExportListener(mpsc::Sender<io::Result<RawFd>>),
+    // Close all connections and stop the event loop.
+    Shutdown
 }
 
+/// Dropping the interface shuts the server down, unless its receiving half has been split off.
+/// See `WsEvents`.
 pub struct WebSocket {
-    events: mpsc::Receiver<(Token,WebSocketEvent)>,
-    event_loop_tx: Sender<WebSocketInternalMessage>
+    events: WsEvents,
+    sender: WsSender
 }
 
-impl WebSocket {
-    pub fn new(address: SocketAddr) -> WebSocket {
-        let (tx, rx) = mpsc::channel();
+pub struct WebSocketBuilder {
+    address: SocketAddr,
+    // Already bound listening socket to use instead of binding to the address.
+    listener: Option<net::TcpListener>,
+    config: Config
+}
+
+impl WebSocketBuilder {
+    pub fn new(address: SocketAddr) -> WebSocketBuilder {
+        WebSocketBuilder {
+            address: address,
+            listener: None,
+            config: Config::new()
+        }
+    }
+
+    /// Accepts connections on an already bound socket instead of binding to the address.
+    pub fn from_listener(mut self, listener: net::TcpListener) -> WebSocketBuilder {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Takes over the listening socket passed by systemd socket activation, if there's one.
+    /// Otherwise the server binds to the address as usual.
+    ///
+    /// With socket activation, systemd binds the socket itself, so the server can listen on a privileged port
+    /// without running as root. If several sockets are passed, the first one is used.
+    #[cfg(unix)]
+    pub fn listen_fds(mut self) -> WebSocketBuilder {
+        if let Some(listener) = handover::listen_fds(None) {
+            self.listener = Some(listener);
+        }
+        self
+    }
+
+    /// Takes over the socket with the given name (`FileDescriptorName=` in the socket unit) passed by
+    /// systemd socket activation. Otherwise the server binds to the address as usual.
+    #[cfg(unix)]
+    pub fn listen_fd_named(mut self, name: &str) -> WebSocketBuilder {
+        if let Some(listener) = handover::listen_fds(Some(name)) {
+            self.listener = Some(listener);
+        }
+        self
+    }
+
+    /// Registers an extension that can be negotiated with clients.
+    /// Client offers are matched against the registered extensions in the order they're listed by a client.
+    pub fn extension<E: Extension + 'static>(mut self, extension: E) -> WebSocketBuilder {
+        self.config.extensions.push(Box::new(extension));
+        self
+    }
+
+    /// Adds a middleware to the end of the handshake middleware chain. See the `middleware` module.
+    pub fn middleware<M: HandshakeMiddleware + 'static>(mut self, middleware: M) -> WebSocketBuilder {
+        self.config.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Adds a path pattern upgrade requests are accepted on, e.g. `/game/:id/ws`. Segments starting with `:`
+    /// match any segment and capture it as a parameter (see `ConnectionInfo::route_params`). Once a route is added,
+    /// upgrade requests on paths not matching any route are refused with 404 Not Found.
+    /// Routes are tried in the order they've been added. Events of a route can be received separately
+    /// with `WsSender::subscribe_route`.
+    pub fn route(mut self, pattern: &str) -> WebSocketBuilder {
+        self.config.routes.push(Route::new(pattern));
+        self
+    }
+
+    /// Responds to plain GET requests on the path with 200 OK and the given body, e.g. `ok` for `/healthz`
+    /// requested by load balancers, or a page for visitors of `/`. Other plain requests get 426 Upgrade Required.
+    pub fn static_response(mut self, path: &str, content_type: &str, body: &[u8]) -> WebSocketBuilder {
+        self.config.static_responses.insert(path.to_string(), (content_type.to_string(), body.to_vec()));
+        self
+    }
+
+    /// Adds a subprotocol supported by the server. The first protocol from the client's
+    /// `Sec-WebSocket-Protocol` list that's supported by the server is selected.
+    pub fn protocol(mut self, protocol: &str) -> WebSocketBuilder {
+        self.config.protocols.push(protocol.to_string());
+        self
+    }
 
-        let mut event_loop = EventLoop::new().unwrap();
+    /// Limits the number of events waiting to be received by the application.
+    /// When any receiver falls `high_water` events behind, the server stops reading from sockets
+    /// until the receiver drains its queue down to `low_water` events.
+    pub fn backpressure(mut self, high_water: usize, low_water: usize) -> WebSocketBuilder {
+        self.config.backpressure = Some((high_water, low_water));
+        self
+    }
+
+    /// Limits the number of events each receiver can have queued.
+    pub fn event_queue(mut self, capacity: usize, policy: OverflowPolicy) -> WebSocketBuilder {
+        self.config.event_queue = Some((capacity, policy));
+        self
+    }
+
+    /// Sets the capacity of the queue of messages sent to the event loop (e.g. by `WsSender::send`).
+    /// Senders block while the queue is full.
+    pub fn notify_capacity(mut self, capacity: usize) -> WebSocketBuilder {
+        self.config.notify_capacity = Some(capacity);
+        self
+    }
+
+    /// Sets the policy for handling event loop panics. The server stops by default.
+    pub fn supervision(mut self, policy: SupervisionPolicy) -> WebSocketBuilder {
+        self.config.supervision = policy;
+        self
+    }
+
+    /// Relays room broadcasts to other server instances. The server accepts peers on `listen`
+    /// and connects to every address in `peers`.
+    pub fn cluster(mut self, listen: SocketAddr, peers: &[SocketAddr]) -> WebSocketBuilder {
+        self.config.cluster = Some((listen, peers.to_vec()));
+        self
+    }
+
+    /// Enables resumable sessions. A client presents a session token in the `session` query parameter
+    /// of the request URL, e.g. `/?session=f81d4fae`. Up to `buffer_size` messages sent to the session
+    /// while the client is away are replayed when it reconnects with the same token.
+    /// A detached session is discarded after `ttl`.
+    pub fn sessions(mut self, buffer_size: usize, ttl: Duration) -> WebSocketBuilder {
+        self.config.sessions = Some((buffer_size, ttl));
+        self
+    }
+
+    /// Sends the ID of this server instance to clients in a handshake response header, e.g. `X-Instance-Id`,
+    /// so that load balancers and clients can route reconnects to the same instance, where their sessions are.
+    /// Clients present the ID back in the same header or in the `instance` query parameter;
+    /// see `ConnectionInfo::affinity`.
+    pub fn session_affinity(mut self, header: &str, instance_id: &str) -> WebSocketBuilder {
+        assert!(!header.contains(|c| c == '\r' || c == '\n') && !instance_id.contains(|c| c == '\r' || c == '\n'),
+                "affinity header must not contain line breaks");
+        self.config.affinity = Some((header.to_string(), instance_id.to_string()));
+        self
+    }
+
+    /// Enables the admin endpoint at `/_ws/admin`. It responds to plain HTTP requests carrying the token
+    /// (as `Authorization: Bearer <token>` or in the `token` query parameter) with a JSON report
+    /// of live connections and server totals.
+    pub fn admin_endpoint(mut self, token: &str) -> WebSocketBuilder {
+        self.config.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// Limits the size of a handshake request, 16 KB by default. Clients sending larger requests
+    /// are refused with 431 Request Header Fields Too Large.
+    pub fn max_handshake_size(mut self, size: usize) -> WebSocketBuilder {
+        self.config.max_handshake_size = size;
+        self
+    }
+
+    /// Limits the size of a message received from a client, after it's been decoded by extensions.
+    /// Connections sending larger messages are closed with status 1009 (Message Too Big). There's no limit by default.
+    /// A frame declaring a larger payload is rejected by its header, before any of the payload is buffered.
+    pub fn max_message_size(mut self, size: usize) -> WebSocketBuilder {
+        self.config.max_message_size = Some(size);
+        self
+    }
+
+    /// Limits the number of bytes per second written to each connection, so that a few clients receiving
+    /// lots of data can't take up the whole uplink. There's no limit by default.
+    pub fn max_send_rate(mut self, bytes_per_second: usize) -> WebSocketBuilder {
+        assert!(bytes_per_second > 0, "send rate must be positive");
+        self.config.max_send_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Limits the number of bytes per second written to all connections together. The budget is shared
+    /// between connections in turns, so each of them gets an equal part of it when there's lots to send.
+    /// Can be combined with `max_send_rate`. There's no limit by default.
+    pub fn total_send_rate(mut self, bytes_per_second: usize) -> WebSocketBuilder {
+        assert!(bytes_per_second > 0, "send rate must be positive");
+        self.config.total_send_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Sets the status code and the reason of Close frames sent when the server closes connections
+    /// for the given cause, e.g. a custom 4xxx code. The reason can't be longer than 123 bytes.
+    pub fn close_status(mut self, cause: CloseCause, code: u16, reason: &str) -> WebSocketBuilder {
+        assert!(reason.len() <= 123, "close reason must fit into a control frame");
+        self.config.close_statuses.insert(cause, (code, reason.to_string()));
+        self
+    }
+
+    /// Delivers received messages as `WebSocketEvent::SharedText` and `SharedBinary`, so that broadcasting
+    /// them to many connections doesn't copy the payload for every recipient.
+    pub fn shared_payloads(mut self) -> WebSocketBuilder {
+        self.config.shared_payloads = true;
+        self
+    }
+
+    /// Delivers received messages wrapped into `WebSocketEvent::WithMetadata`, telling their opcode,
+    /// RSV bits and the number of frames they've been sent in.
+    pub fn message_metadata(mut self) -> WebSocketBuilder {
+        self.config.message_metadata = true;
+        self
+    }
+
+    /// Prefixes the payload of every text and binary message sent to a connection with its sequence number
+    /// followed by a colon, e.g. `42:hello`, so that clients can detect lost messages by gaps. Messages are
+    /// numbered from 1 in the order they're queued; a client resuming a session continues its numbering.
+    /// Shared payloads are copied to be prefixed. See `WsSender::delivered_sequence`.
+    pub fn sequence_numbers(mut self) -> WebSocketBuilder {
+        self.config.sequence_numbers = true;
+        self
+    }
+
+    /// Leaves completing the closing handshake to the application. When a client sends a Close frame,
+    /// the server only delivers `WebSocketEvent::Close` and stops reading from the client. The application
+    /// can still send messages to it and has to respond with `close` to finish the handshake.
+    pub fn manual_close(mut self) -> WebSocketBuilder {
+        self.config.manual_close = true;
+        self
+    }
+
+    /// Accepts handshakes only with the given `Host` header, e.g. `localhost` or `example.com:8080`.
+    /// A host without a port matches any port. Handshakes with other hosts are refused with 400 Bad Request,
+    /// which protects servers running locally from DNS rebinding attacks. Any host is accepted by default.
+    pub fn allow_host(mut self, host: &str) -> WebSocketBuilder {
+        self.config.allowed_hosts.push(host.to_lowercase());
+        self
+    }
+
+    /// Sets the length of the kernel queue of connections waiting to be accepted (Unix only).
+    /// Raising it helps to weather bursts of connections, e.g. clients reconnecting after a restart.
+    /// The system caps it, on Linux with `net.core.somaxconn`.
+    pub fn listen_backlog(mut self, backlog: usize) -> WebSocketBuilder {
+        self.config.listen_backlog = Some(backlog);
+        self
+    }
+
+    /// Sets the size of the kernel receive buffer of the sockets (Unix only). It's set on the listening socket
+    /// and inherited by accepted connections; set it before the window scale is negotiated, that is before
+    /// connections are accepted. Large buffers help to saturate links with a high bandwidth-delay product.
+    pub fn recv_buffer_size(mut self, bytes: usize) -> WebSocketBuilder {
+        self.config.recv_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Sets the size of the kernel send buffer of the sockets (Unix only). See `recv_buffer_size`.
+    pub fn send_buffer_size(mut self, bytes: usize) -> WebSocketBuilder {
+        self.config.send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Sets the budget of memory taken by connection buffers: frames being received, queued messages
+    /// and unwritten output. The usage is checked several times a second; over the budget,
+    /// the server sheds load according to the policy.
+    pub fn memory_budget(mut self, bytes: usize, shedding: LoadShedding) -> WebSocketBuilder {
+        self.config.memory_budget = Some((bytes, shedding));
+        self
+    }
+
+    /// Sets how closely clients are held to the protocol, `Strictness::Lenient` by default.
+    pub fn strictness(mut self, strictness: Strictness) -> WebSocketBuilder {
+        self.config.strictness = strictness;
+        self
+    }
+
+    /// Enables all protocol validations, the same as `strictness(Strictness::Strict)`.
+    /// This is the configuration tested with the Autobahn test suite; see `examples/fuzzingserver.rs`.
+    pub fn strict(self) -> WebSocketBuilder {
+        self.strictness(Strictness::Strict)
+    }
+
+    /// Limits the memory buffers of a single connection may take. A connection sending a message that
+    /// doesn't fit is closed with status 1009 (Message Too Big); one that doesn't read the messages
+    /// sent to it fast enough is closed with status 1008 (Policy Violation), discarding them.
+    pub fn max_connection_memory(mut self, bytes: usize) -> WebSocketBuilder {
+        self.config.max_connection_memory = Some(bytes);
+        self
+    }
+
+    /// Limits the numbers of data and control frames queued for a connection, regardless of their size,
+    /// so that lots of tiny messages can't pile up. A connection exceeding either limit, by not reading
+    /// the messages sent to it or by sending pings faster than it reads the pongs, is closed with
+    /// status 1008 (Policy Violation), discarding the queued frames.
+    pub fn max_queued_frames(mut self, data: usize, control: usize) -> WebSocketBuilder {
+        self.config.max_queued_frames = Some((data, control));
+        self
+    }
+
+    /// Limits the number of connections accepted per second. Connections over the limit wait in the backlog
+    /// (see `listen_backlog`) and are accepted as the rate allows, which smooths out reconnection storms.
+    pub fn max_accept_rate(mut self, connections_per_second: usize) -> WebSocketBuilder {
+        self.config.max_accept_rate = Some(cmp::max(connections_per_second, 1));
+        self
+    }
+
+    /// Sets what to do when the server runs out of file descriptors for new connections.
+    /// By default accepting is paused until connections go away.
+    pub fn on_fd_exhaustion(mut self, policy: FdExhaustion) -> WebSocketBuilder {
+        self.config.fd_exhaustion = policy;
+        self
+    }
+
+    /// Requires clients to send heartbeats, for protocols where keeping connections alive is up to clients.
+    /// A client that hasn't sent one within `window` since the handshake or its previous heartbeat
+    /// is closed with status 1008 (Policy Violation) and disconnected with `DisconnectReason::Timeout`.
+    pub fn require_heartbeat(mut self, window: Duration, heartbeat: Heartbeat) -> WebSocketBuilder {
+        self.config.heartbeat = Some((window, heartbeat));
+        self
+    }
+
+    /// Drops connections that haven't made any write progress for `timeout` while they have data to write,
+    /// that is, clients that have stopped reading. They're disconnected with `DisconnectReason::SlowConsumer`
+    /// without the closing handshake, since the Close frame couldn't be written either.
+    pub fn write_timeout(mut self, timeout: Duration) -> WebSocketBuilder {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables TCP Fast Open on the listening socket (Linux only), saving a round trip for clients
+    /// reconnecting to the server. `queue_len` limits the connections with pending Fast Open requests.
+    /// TFO has to be enabled for servers by the system as well: see the `net.ipv4.tcp_fastopen` sysctl.
+    pub fn tcp_fast_open(mut self, queue_len: usize) -> WebSocketBuilder {
+        self.config.fast_open = Some(queue_len);
+        self
+    }
+
+    /// Marks the packets of accepted connections with a DSCP value, 0 to 63 (Unix only),
+    /// e.g. 46 (Expedited Forwarding) for real-time traffic. It can be changed per connection
+    /// with `WsSender::set_dscp` or through the admin endpoint.
+    pub fn dscp(mut self, dscp: u8) -> WebSocketBuilder {
+        self.config.dscp = Some(dscp & 0x3F);
+        self
+    }
+
+    /// Reports server counters to the application every `interval` as `WebSocketEvent::Stats`.
+    pub fn stats_interval(mut self, interval: Duration) -> WebSocketBuilder {
+        self.config.stats_interval = Some(interval);
+        self
+    }
+
+    /// Passes a record of every WebSocket connection, including the ones refused during the handshake,
+    /// to the function once the connection is torn down, for feeding audit or billing systems.
+    /// The function is called by the event loop, so it should hand the record over, e.g. to a channel,
+    /// rather than do any work with it.
+    pub fn audit_log<F>(mut self, log: F) -> WebSocketBuilder
+        where F: Fn(ConnectionRecord) + Send + 'static {
+        self.config.audit_log = Some(Box::new(log));
+        self
+    }
+
+    /// Size of the buffer data is read from sockets into, 16 KB by default.
+    pub fn read_buffer_size(mut self, size: usize) -> WebSocketBuilder {
+        self.config.read_buffer_size = cmp::max(size, 1);
+        self
+    }
+
+    /// Bounds the memory taken by a connection whose messages the application doesn't keep up with:
+    /// once `high_water` bytes of its messages wait in the event queue, reading from the connection stops
+    /// until the application receives them down to `low_water` bytes. Unlike `backpressure`,
+    /// other connections keep being read.
+    pub fn read_watermarks(mut self, high_water: usize, low_water: usize) -> WebSocketBuilder {
+        self.config.read_watermarks = Some((high_water, cmp::min(low_water, high_water)));
+        self
+    }
+
+    /// Records everything received on connections to files in the directory, one file per connection.
+    /// Recordings can be replayed with `TestConnection::replay` to reproduce problems.
+    /// Meant for debugging: files are written synchronously by the event loop.
+    pub fn record_traffic<P: AsRef<Path>>(mut self, dir: P) -> WebSocketBuilder {
+        self.config.record_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Creates a connection with the settings of the builder that isn't bound to a socket.
+    /// The test drives it by feeding it input and taking its output. See `testing::TestConnection`.
+    #[cfg(feature = "testing")]
+    pub fn test_connection(self) -> TestConnection {
+        TestConnection::new(self.config)
+    }
+
+    pub fn build(self) -> WebSocket {
+        let (tx, rx) = channel::channel();
+        let connected = Arc::new(RwLock::new(HashSet::new()));
+        let gate = Arc::new(NotifyGate::new());
+
+        let mut events = EventBus::new(&self.config);
+        events.subscribe(EventFilter::All, None, tx);
+
+        let mut loop_config = EventLoopConfig::new();
+        if let Some(capacity) = self.config.notify_capacity {
+            loop_config.notify_capacity(capacity);
+        }
+
+        let mut event_loop = EventLoop::configured(loop_config).unwrap();
         let event_loop_tx = event_loop.channel();
 
-        thread::spawn(move || {
-            let server_socket = TcpListener::bind(&address).unwrap();
-            let mut server = WebSocketServer::new(server_socket, tx);
+        let address = self.address;
+        let listener = self.listener;
+        let cluster = self.config.cluster.clone();
+        let config = self.config;
+        let server_connected = connected.clone();
+        let server_gate = gate.clone();
+
+        let thread = thread::spawn(move || {
+            let events = Rc::new(RefCell::new(events));
+            let config = Rc::new(config);
+            let generation = Rc::new(Cell::new(0));
+            let mut restarts = 0;
+
+            // The timers are rescheduled by the server every time they fire, restarted servers included.
+            if let Some(interval) = config.stats_interval {
+                if let Err(e) = event_loop.timeout_ms(Timer::Stats, server::duration_ms(interval)) {
+                    error!("Failed to set stats timeout: {:?}", e);
+                }
+            }
+            if config.memory_budget.is_some() {
+                if let Err(e) = event_loop.timeout_ms(Timer::MemoryCheck, server::MEMORY_CHECK_INTERVAL_MS) {
+                    error!("Failed to set memory check timeout: {:?}", e);
+                }
+            }
+            if let Some((window, _)) = config.heartbeat {
+                if let Err(e) = event_loop.timeout_ms(Timer::HeartbeatCheck, server::timeout_check_interval_ms(window)) {
+                    error!("Failed to set heartbeat check timeout: {:?}", e);
+                }
+            }
+            if let Some(timeout) = config.write_timeout {
+                if let Err(e) = event_loop.timeout_ms(Timer::WriteCheck, server::timeout_check_interval_ms(timeout)) {
+                    error!("Failed to set write check timeout: {:?}", e);
+                }
+            }
+
+            loop {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| -> io::Result<()> {
+                    let server_socket = match listener {
+                        // The listener is cloned, so that it survives restarts.
+                        Some(ref listener) => try!(TcpListener::from_listener(try!(listener.try_clone()),
+                                                                               &try!(listener.local_addr()))),
+                        None => try!(TcpListener::bind(&address))
+                    };
+                    #[cfg(unix)]
+                    {
+                        let fd = server_socket.as_raw_fd();
+                        if let Some(size) = config.recv_buffer_size {
+                            try!(sockopt::set_recv_buffer(fd, size));
+                        }
+                        if let Some(size) = config.send_buffer_size {
+                            try!(sockopt::set_send_buffer(fd, size));
+                        }
+                        if let Some(backlog) = config.listen_backlog {
+                            try!(sockopt::set_backlog(fd, backlog));
+                        }
+                        #[cfg(target_os = "linux")]
+                        {
+                            if let Some(queue_len) = config.fast_open {
+                                try!(sockopt::set_fast_open(fd, queue_len));
+                            }
+                        }
+                    }
+                    let mut server = WebSocketServer::new(server_socket, events.clone(), server_connected.clone(),
+                                                          server_gate.clone(), generation.clone(), config.clone());
+
+                    try!(event_loop.register(&server.socket,
+                                             SERVER_TOKEN,
+                                             EventSet::readable(),
+                                             PollOpt::edge()));
+
+                    event_loop.run(&mut server)
+                }));
 
-            event_loop.register(&server.socket,
-                                SERVER_TOKEN,
-                                EventSet::readable(),
-                                PollOpt::edge()).unwrap();
+                let err = match result {
+                    Ok(Ok(())) => break,
+                    Ok(Err(e)) => e.to_string(),
+                    Err(payload) => panic_message(payload)
+                };
+                error!("event loop has failed: {}", err);
 
-            event_loop.run(&mut server).unwrap();
+                // Clients have been dropped along with the server.
+                server_connected.write().unwrap().clear();
+                events.borrow_mut().publish(ConnectionId::server(), WebSocketEvent::ServerError(err));
+
+                match config.supervision {
+                    SupervisionPolicy::Restart { max_restarts } if restarts < max_restarts => {
+                        restarts += 1;
+                        warn!("restarting event loop ({} of {})", restarts, max_restarts);
+                    },
+                    _ => break
+                }
+            }
+
+            // Senders waiting for room in the notify queue would block forever once the event loop is gone.
+            server_gate.close();
         });
 
-        WebSocket {
+        let mut sender = WsSender {
             event_loop_tx: event_loop_tx,
-            events: rx
+            connected: connected,
+            gate: gate,
+            relay: None
+        };
+
+        if let Some((listen, peers)) = cluster {
+            let relay = Relay::start(listen, &peers, sender.clone()).expect("failed to start cluster relay");
+            sender.relay = Some(Arc::new(relay));
+        }
+
+        WebSocket {
+            events: WsEvents {
+                events: rx,
+                sender: sender.clone(),
+                _handle: Some(ServerHandle {
+                    sender: sender.clone(),
+                    thread: Some(thread)
+                })
+            },
+            sender: sender
         }
     }
+}
 
-    pub fn next(&mut self) -> (Token,WebSocketEvent) {
-        self.events.recv().unwrap()
+impl WebSocket {
+    pub fn new(address: SocketAddr) -> WebSocket {
+        WebSocketBuilder::new(address).build()
     }
 
-    pub fn get_connected(&mut self) -> Result<Vec<Token>, mpsc::RecvError> {
+    pub fn next(&mut self) -> (ConnectionId,WebSocketEvent) {
+        self.events.next()
+    }
+
+    /// Waits for events and returns up to `max` of them at once. See `WsEvents::drain_events`.
+    pub fn drain_events(&mut self, max: usize) -> Option<Vec<(ConnectionId,WebSocketEvent)>> {
+        self.events.drain_events(max)
+    }
+
+    /// Returns a handle that can be used to send messages from other threads.
+    pub fn sender(&self) -> WsSender {
+        self.sender.clone()
+    }
+
+    /// Creates an additional receiver of events matching the filter.
+    pub fn subscribe(&self, filter: EventFilter) -> WsEvents {
+        self.sender.subscribe(filter)
+    }
+
+    /// Creates a receiver of the events of connections accepted on a route. See `WsSender::subscribe_route`.
+    pub fn subscribe_route(&self, pattern: &str, filter: EventFilter) -> WsEvents {
+        self.sender.subscribe_route(pattern, filter)
+    }
+
+    /// Splits the interface into the receiving and sending halves,
+    /// so that they can be moved to different threads.
+    pub fn split(self) -> (WsEvents, WsSender) {
+        (self.events, self.sender)
+    }
+
+    /// Handles events on a pool of worker threads. See `WsEvents::dispatch`.
+    pub fn dispatch<H>(self, workers: usize, handler: H) -> Dispatcher
+        where H: Fn(ConnectionId, WebSocketEvent) + Send + Sync + 'static {
+        self.events.dispatch(workers, handler)
+    }
+
+    /// Returns the details of all connections.
+    pub fn get_connected(&mut self) -> Result<Vec<ConnectionInfo>, mpsc::RecvError> {
+        self.sender.get_connected()
+    }
+
+    /// Checks whether a connection is still open.
+    pub fn is_connected(&self, id: ConnectionId) -> bool {
+        self.sender.is_connected(id)
+    }
+
+    /// Number of open connections. See `WsSender::connection_count`.
+    pub fn connection_count(&self) -> usize {
+        self.sender.connection_count()
+    }
+
+    /// Returns the handshake details of a connection, or `None` if there's no such connection.
+    pub fn connection_info(&mut self, id: ConnectionId) -> Result<Option<ConnectionInfo>, mpsc::RecvError> {
+        self.sender.connection_info(id)
+    }
+
+    /// Waits until a connection has written all its output. See `WsSender::flush`.
+    pub fn flush(&mut self, id: ConnectionId, timeout: Duration) -> bool {
+        self.sender.flush(id, timeout)
+    }
+
+    /// Sequence number of the last message written to a connection. See `WsSender::delivered_sequence`.
+    pub fn delivered_sequence(&mut self, id: ConnectionId) -> Result<Option<u64>, mpsc::RecvError> {
+        self.sender.delivered_sequence(id)
+    }
+
+    pub fn send(&mut self, msg: (ConnectionId,WebSocketMessage)) {
+        self.sender.send(msg);
+    }
+
+    /// Sends a message that is discarded if it can't be delivered in time. See `WsSender::send_with_ttl`.
+    pub fn send_with_ttl(&mut self, id: ConnectionId, msg: WebSocketMessage, ttl: Duration) {
+        self.sender.send_with_ttl(id, msg, ttl);
+    }
+
+    /// Sends a message ahead of the queued messages of lower priority, or after them.
+    pub fn send_with_priority(&mut self, id: ConnectionId, msg: WebSocketMessage, priority: Priority) {
+        self.sender.send_with_priority(id, msg, priority);
+    }
+
+    /// Sets the scheduling class of a connection. See `Priority`.
+    pub fn set_priority(&mut self, id: ConnectionId, priority: Priority) {
+        self.sender.set_priority(id, priority);
+    }
+
+    /// Stops reading from a client until `resume` is called.
+    pub fn pause(&mut self, id: ConnectionId) {
+        self.sender.pause(id);
+    }
+
+    pub fn resume(&mut self, id: ConnectionId) {
+        self.sender.resume(id);
+    }
+
+    pub fn send_text(&mut self, id: ConnectionId, text: &str) {
+        self.sender.send_text(id, text);
+    }
+
+    pub fn send_binary(&mut self, id: ConnectionId, data: &[u8]) {
+        self.sender.send_binary(id, data);
+    }
+
+    pub fn ping(&mut self, id: ConnectionId, payload: &[u8]) {
+        self.sender.ping(id, payload);
+    }
+
+    /// Switches a connection to the raw mode and back. See `WsSender::set_raw_mode`.
+    pub fn set_raw_mode(&mut self, id: ConnectionId, enabled: bool) {
+        self.sender.set_raw_mode(id, enabled);
+    }
+
+    /// Sends a frame as is. See `WsSender::send_frame`.
+    pub fn send_frame(&mut self, id: ConnectionId, frame: RawFrame) {
+        self.sender.send_frame(id, frame);
+    }
+
+    /// Sets the kernel buffer sizes of a connection. See `WsSender::set_socket_buffers`.
+    #[cfg(unix)]
+    pub fn set_socket_buffers(&mut self, id: ConnectionId, recv: Option<usize>, send: Option<usize>) {
+        self.sender.set_socket_buffers(id, recv, send);
+    }
+
+    #[cfg(unix)]
+    pub fn set_dscp(&mut self, id: ConnectionId, dscp: u8) {
+        self.sender.set_dscp(id, dscp);
+    }
+
+    /// Forwards frames between two clients inside the event loop. See `WsSender::pipe`.
+    pub fn pipe(&mut self, a: ConnectionId, b: ConnectionId) {
+        self.sender.pipe(a, b);
+    }
+
+    pub fn unpipe(&mut self, id: ConnectionId) {
+        self.sender.unpipe(id);
+    }
+
+    /// Writes already serialized frames to a connection. See `WsSender::send_raw`.
+    pub fn send_raw(&mut self, id: ConnectionId, data: Vec<u8>) {
+        self.sender.send_raw(id, data);
+    }
+
+    /// Starts the closing handshake with a client.
+    pub fn close(&mut self, id: ConnectionId, status: StatusCode) {
+        self.sender.close(id, status);
+    }
+
+    /// Closes all connections from an address, optionally blocking it. See `WsSender::kick_addr`.
+    pub fn kick_addr(&mut self, ip: IpAddr, status: StatusCode, block: bool) {
+        self.sender.kick_addr(ip, status, block);
+    }
+
+    pub fn unblock_addr(&mut self, ip: IpAddr) {
+        self.sender.unblock_addr(ip);
+    }
+
+    /// Duplicates the listening socket for a new version of the server. See `WsSender::export_listener`.
+    #[cfg(unix)]
+    pub fn export_listener(&self) -> io::Result<RawFd> {
+        self.sender.export_listener()
+    }
+
+    /// Sends a message to the current connection of a session, or buffers it until the client reconnects.
+    pub fn send_to_session(&mut self, token: &str, msg: WebSocketMessage) {
+        self.sender.send_to_session(token, msg);
+    }
+
+    /// Adds a connection to a room. Connections leave all rooms when they're closed.
+    pub fn join(&mut self, id: ConnectionId, room: &str) {
+        self.sender.join(id, room);
+    }
+
+    pub fn leave(&mut self, id: ConnectionId, room: &str) {
+        self.sender.leave(id, room);
+    }
+
+    /// Sends a message to all members of a room, including the members connected to other cluster nodes.
+    pub fn publish(&mut self, room: &str, msg: WebSocketMessage) {
+        self.sender.publish(room, msg);
+    }
+
+    /// Attaches a tag to a connection. See `WsSender::tag`.
+    pub fn tag(&mut self, id: ConnectionId, tag: &str) {
+        self.sender.tag(id, tag);
+    }
+
+    pub fn untag(&mut self, id: ConnectionId, tag: &str) {
+        self.sender.untag(id, tag);
+    }
+
+    /// Sends a message to all connections with a tag. See `WsSender::send_to_tag`.
+    pub fn send_to_tag(&mut self, tag: &str, msg: WebSocketMessage) {
+        self.sender.send_to_tag(tag, msg);
+    }
+
+    /// Sends a message to all connections but the given ones. See `WsSender::broadcast_except`.
+    pub fn broadcast_except(&mut self, except: &[ConnectionId], msg: WebSocketMessage) {
+        self.sender.broadcast_except(except, msg);
+    }
+}
+
+/// Stops the server when dropped: closes client connections, stops the event loop and waits for its thread.
+struct ServerHandle {
+    sender: WsSender,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.sender.send_internal(WebSocketInternalMessage::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Receiving half of the WebSocket interface.
+///
+/// The receiver obtained from `WebSocket::split` owns the server: once it's dropped, the server closes all connections
+/// and stops. Receivers created by `subscribe` don't affect the server lifetime.
+pub struct WsEvents {
+    events: EventReceiver,
+    sender: WsSender,
+    _handle: Option<ServerHandle>
+}
+
+impl WsEvents {
+    pub fn next(&mut self) -> (ConnectionId,WebSocketEvent) {
+        self.try_next().unwrap()
+    }
+
+    /// Waits for an event. Returns `None` once the server has stopped and all events have been received.
+    pub fn try_next(&mut self) -> Option<(ConnectionId,WebSocketEvent)> {
+        let (event, drained) = match self.events.recv() {
+            Ok(received) => received,
+            Err(_) => return None
+        };
+        if drained {
+            self.sender.send_internal(WebSocketInternalMessage::ResumeReading);
+        }
+        Some(event)
+    }
+
+    /// Waits for events and returns up to `max` of them at once. Returns `None` once the server has stopped
+    /// and all events have been received.
+    pub fn drain_events(&mut self, max: usize) -> Option<Vec<(ConnectionId,WebSocketEvent)>> {
+        let (events, drained) = match self.events.recv_many(max) {
+            Ok(received) => received,
+            Err(_) => return None
+        };
+        if drained {
+            self.sender.send_internal(WebSocketInternalMessage::ResumeReading);
+        }
+        Some(events)
+    }
+
+    /// Handles events on a pool of `workers` threads. All events of a connection are handled
+    /// by the same worker, so they're processed in the order they have occurred.
+    ///
+    /// If the receiver owns the server, the returned dispatcher takes over: dropping it shuts the server down.
+    pub fn dispatch<H>(mut self, workers: usize, handler: H) -> Dispatcher
+        where H: Fn(ConnectionId, WebSocketEvent) + Send + Sync + 'static {
+        assert!(workers > 0, "dispatcher needs at least one worker");
+
+        let handle = self._handle.take();
+
+        let handler = Arc::new(handler);
+        let mut threads = Vec::with_capacity(workers + 1);
+        let mut queues = Vec::with_capacity(workers);
+
+        for _ in 0..workers {
+            // Queues are bounded, so a slow worker holds the dispatcher back and back-pressure still applies.
+            let (tx, rx) = mpsc::sync_channel::<(ConnectionId,WebSocketEvent)>(WORKER_QUEUE_SIZE);
+            let handler = handler.clone();
+
+            threads.push(thread::spawn(move || {
+                for (id, event) in rx.iter() {
+                    handler(id, event);
+                }
+            }));
+            queues.push(tx);
+        }
+
+        threads.push(thread::spawn(move || {
+            while let Some((id, event)) = self.try_next() {
+                let worker = id.token().as_usize() % queues.len();
+                if queues[worker].send((id, event)).is_err() {
+                    error!("dispatcher worker {} has stopped", worker);
+                    break;
+                }
+            }
+        }));
+
+        Dispatcher {
+            threads: threads,
+            _handle: handle
+        }
+    }
+}
+
+/// Pool of threads handling events, created by `WsEvents::dispatch`.
+pub struct Dispatcher {
+    threads: Vec<thread::JoinHandle<()>>,
+    _handle: Option<ServerHandle>
+}
+
+impl Dispatcher {
+    /// Blocks until the event loop stops and the workers handle all remaining events.
+    pub fn join(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Sending half of the WebSocket interface. It can be cloned and shared between threads.
+#[derive(Clone)]
+pub struct WsSender {
+    event_loop_tx: Sender<WebSocketInternalMessage>,
+    // Ids of open connections, maintained by the event loop.
+    connected: Arc<RwLock<HashSet<ConnectionId>>>,
+    gate: Arc<NotifyGate>,
+    relay: Option<Arc<Relay>>
+}
+
+impl WsSender {
+    /// Returns the details of all connections.
+    pub fn get_connected(&self) -> Result<Vec<ConnectionInfo>, mpsc::RecvError> {
         let (tx, rx) = mpsc::channel();
         self.send_internal(WebSocketInternalMessage::GetPeers(tx));
         rx.recv()
     }
 
-    pub fn send(&mut self, msg: (Token,WebSocketEvent)) {
+    /// Checks whether a connection is still open. Unlike `get_connected`, it doesn't wait for the event loop.
+    pub fn is_connected(&self, id: ConnectionId) -> bool {
+        self.connected.read().unwrap().contains(&id)
+    }
+
+    /// Number of open connections, including the ones that haven't completed the handshake yet.
+    /// Like `is_connected`, it doesn't wait for the event loop.
+    pub fn connection_count(&self) -> usize {
+        self.connected.read().unwrap().len()
+    }
+
+    /// Returns the handshake details of a connection, or `None` if there's no such connection.
+    pub fn connection_info(&self, id: ConnectionId) -> Result<Option<ConnectionInfo>, mpsc::RecvError> {
+        let (tx, rx) = mpsc::channel();
+        self.send_internal(WebSocketInternalMessage::GetConnectionInfo(id, tx));
+        rx.recv()
+    }
+
+    /// Blocks until the messages sent to a connection so far have been written to its socket, e.g. before closing
+    /// the connection after a final message. Returns `false` if that doesn't happen within the timeout
+    /// or the connection goes away first.
+    pub fn flush(&self, id: ConnectionId, timeout: Duration) -> bool {
+        let (tx, rx) = mpsc::channel();
+        self.send_internal(WebSocketInternalMessage::Flush(id, tx));
+        rx.recv_timeout(timeout).unwrap_or(false)
+    }
+
+    /// Returns the sequence number of the last message that has been written to a connection, if sequence numbers
+    /// are enabled with `WebSocketBuilder::sequence_numbers`. A message written to the socket may still be lost
+    /// with the connection; a resuming client tells which messages it has got by the numbers.
+    pub fn delivered_sequence(&self, id: ConnectionId) -> Result<Option<u64>, mpsc::RecvError> {
+        self.connection_info(id).map(|info| info.and_then(|info| info.delivered_sequence))
+    }
+
+    /// Creates an additional receiver of events matching the filter.
+    /// Only events that occur after the subscription has been processed by the event loop are delivered.
+    pub fn subscribe(&self, filter: EventFilter) -> WsEvents {
+        self.subscribe_to(filter, None)
+    }
+
+    /// Creates a receiver of the events of connections accepted on a route, given by its pattern
+    /// as passed to `WebSocketBuilder::route`. Events of the route are then delivered to its receivers only,
+    /// so that each route can be handled on a thread of its own. Once all receivers of a route are dropped,
+    /// its events go to the other receivers again.
+    pub fn subscribe_route(&self, pattern: &str, filter: EventFilter) -> WsEvents {
+        self.subscribe_to(filter, Some(pattern.to_string()))
+    }
+
+    fn subscribe_to(&self, filter: EventFilter, route: Option<String>) -> WsEvents {
+        let (tx, rx) = channel::channel();
+        self.send_internal(WebSocketInternalMessage::Subscribe(filter, route, tx));
+        WsEvents {
+            events: rx,
+            sender: self.clone(),
+            _handle: None
+        }
+    }
+
+    pub fn send(&self, msg: (ConnectionId,WebSocketMessage)) {
         self.send_internal(WebSocketInternalMessage::SendMessage(msg));
     }
 
-    fn send_internal(&mut self, msg: WebSocketInternalMessage) -> Result<(), NotifyError<WebSocketInternalMessage>> {
+    /// Sends a message that is only worth delivering within `ttl`. If the client is too slow to receive it
+    /// in time, the message is discarded and reported with `WebSocketEvent::Dropped`.
+    pub fn send_with_ttl(&self, id: ConnectionId, msg: WebSocketMessage, ttl: Duration) {
+        self.send_internal(WebSocketInternalMessage::SendExpiring(id, msg, clock::now() + ttl));
+    }
+
+    /// Sends a message ahead of the queued messages of lower priority, or after them.
+    pub fn send_with_priority(&self, id: ConnectionId, msg: WebSocketMessage, priority: Priority) {
+        self.send_internal(WebSocketInternalMessage::SendPrioritized(id, msg, priority));
+    }
+
+    /// Sets the scheduling class of a connection, `Priority::Normal` by default.
+    pub fn set_priority(&self, id: ConnectionId, priority: Priority) {
+        self.send_internal(WebSocketInternalMessage::SetPriority(id, priority));
+    }
+
+    /// Stops reading from a client until `resume` is called.
+    /// Data sent by the client is left in the socket buffer, so TCP flow control slows the client down.
+    pub fn pause(&self, id: ConnectionId) {
+        self.send_internal(WebSocketInternalMessage::Pause(id));
+    }
+
+    pub fn resume(&self, id: ConnectionId) {
+        self.send_internal(WebSocketInternalMessage::Resume(id));
+    }
+
+    pub fn send_text(&self, id: ConnectionId, text: &str) {
+        self.send((id, WebSocketMessage::Text(text.to_string())));
+    }
+
+    pub fn send_binary(&self, id: ConnectionId, data: &[u8]) {
+        self.send((id, WebSocketMessage::Binary(data.to_vec())));
+    }
+
+    pub fn ping(&self, id: ConnectionId, payload: &[u8]) {
+        self.send((id, WebSocketMessage::Ping(payload.to_vec().into_boxed_slice())));
+    }
+
+    /// Switches a connection to the raw mode and back. In the raw mode, data frames received from the client
+    /// are delivered as `WebSocketEvent::Frame` instead of being assembled into messages and decoded by extensions.
+    /// Control frames are still handled by the server.
+    pub fn set_raw_mode(&self, id: ConnectionId, enabled: bool) {
+        self.send_internal(WebSocketInternalMessage::SetRawMode(id, enabled));
+    }
+
+    /// Sends a frame as is. The frame isn't checked against the protocol or passed through extensions,
+    /// so it's up to the application to keep the stream of frames valid.
+    pub fn send_frame(&self, id: ConnectionId, frame: RawFrame) {
+        self.send((id, WebSocketMessage::Frame(frame)));
+    }
+
+    /// Registers an evented handle, such as a pipe or another socket, with the server's event loop.
+    /// Its readiness is delivered as `WebSocketEvent::SourceReady` with the given token, so the application
+    /// can wait for it along with WebSocket events. The source is registered as edge-triggered: after getting
+    /// an event, the application should read or write until the operation would block.
+    /// A source added with a token that's already in use replaces the previous one.
+    /// Sources are dropped if the event loop is restarted.
+    pub fn add_source<E>(&self, token: usize, source: Arc<E>, interest: EventSet)
+        where E: Evented + Send + Sync + 'static {
+        self.send_internal(WebSocketInternalMessage::AddSource(token, Box::new(SharedSource(source)), interest));
+    }
+
+    pub fn remove_source(&self, token: usize) {
+        self.send_internal(WebSocketInternalMessage::RemoveSource(token));
+    }
+
+    /// Overrides the sizes of the kernel receive and send buffers of a connection's socket (Unix only).
+    /// `None` keeps the current size. The receive buffer can't be grown beyond the window scale
+    /// negotiated when the connection has been accepted; see `WebSocketBuilder::recv_buffer_size`.
+    #[cfg(unix)]
+    pub fn set_socket_buffers(&self, id: ConnectionId, recv: Option<usize>, send: Option<usize>) {
+        self.send_internal(WebSocketInternalMessage::SetSocketBuffers(id, recv, send));
+    }
+
+    /// Marks the packets of a connection with a DSCP value, 0 to 63 (Unix only). See `WebSocketBuilder::dscp`.
+    #[cfg(unix)]
+    pub fn set_dscp(&self, id: ConnectionId, dscp: u8) {
+        self.send_internal(WebSocketInternalMessage::SetDscp(id, dscp & 0x3F));
+    }
+
+    /// Connects two clients: data frames received from either of them are sent to the other one
+    /// inside the event loop, without being delivered to the application. Frames are forwarded as they are,
+    /// including the RSV bits, so both clients should have negotiated the same extensions.
+    /// When one of the connections is closed, the other one is closed with status 1001 (Going Away).
+    pub fn pipe(&self, a: ConnectionId, b: ConnectionId) {
+        self.send_internal(WebSocketInternalMessage::Pipe(a, b));
+    }
+
+    /// Disconnects a piped client from its peer; both of them go back to delivering messages to the application.
+    pub fn unpipe(&self, id: ConnectionId) {
+        self.send_internal(WebSocketInternalMessage::Unpipe(id));
+    }
+
+    /// Writes already serialized frames to a connection verbatim, e.g. frames received by a proxy.
+    /// Server frames must not be masked. As with `send_frame`, the frames aren't checked in any way.
+    pub fn send_raw(&self, id: ConnectionId, data: Vec<u8>) {
+        self.send((id, WebSocketMessage::Serialized(data)));
+    }
+
+    /// Starts the closing handshake with a client.
+    pub fn close(&self, id: ConnectionId, status: StatusCode) {
+        self.send((id, WebSocketMessage::Close(status)));
+    }
+
+    /// Starts the closing handshake with all clients connected from an address; connections that haven't
+    /// completed the handshake yet are dropped right away. With `block`, the address
+    /// is also added to the blocklist: connections from it are closed right after being accepted,
+    /// until it's unblocked with `unblock_addr`. The blocklist isn't kept across restarts of the server.
+    pub fn kick_addr(&self, ip: IpAddr, status: StatusCode, block: bool) {
+        self.send_internal(WebSocketInternalMessage::KickAddr(ip, status, block));
+    }
+
+    pub fn unblock_addr(&self, ip: IpAddr) {
+        self.send_internal(WebSocketInternalMessage::UnblockAddr(ip));
+    }
+
+    /// Duplicates the listening socket for a new version of the server. The returned descriptor is
+    /// inherited by child processes, so the new version can take over accepting connections with
+    /// `WebSocketBuilder::from_listener` (or `listen_fds`, if it's passed as descriptor 3) while this one
+    /// finishes serving its clients.
+    #[cfg(unix)]
+    pub fn export_listener(&self) -> io::Result<RawFd> {
+        let (tx, rx) = mpsc::channel();
+        self.send_internal(WebSocketInternalMessage::ExportListener(tx));
+        match rx.recv() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "server has stopped"))
+        }
+    }
+
+    /// Sends a message to the current connection of a session, or buffers it until the client reconnects.
+    pub fn send_to_session(&self, token: &str, msg: WebSocketMessage) {
+        self.send_internal(WebSocketInternalMessage::SendToSession(token.to_string(), msg));
+    }
+
+    /// Adds a connection to a room. Connections leave all rooms when they're closed.
+    pub fn join(&self, id: ConnectionId, room: &str) {
+        self.send_internal(WebSocketInternalMessage::JoinRoom(id, room.to_string()));
+    }
+
+    pub fn leave(&self, id: ConnectionId, room: &str) {
+        self.send_internal(WebSocketInternalMessage::LeaveRoom(id, room.to_string()));
+    }
+
+    /// Sends a message to all members of a room, including the members connected to other cluster nodes.
+    pub fn publish(&self, room: &str, msg: WebSocketMessage) {
+        if let Some(ref relay) = self.relay {
+            relay.forward(room, &msg);
+        }
+        self.publish_local(room, msg);
+    }
+
+    /// Sends a message to the members of a room connected to this server only.
+    pub fn publish_local(&self, room: &str, msg: WebSocketMessage) {
+        self.send_internal(WebSocketInternalMessage::Publish(room.to_string(), msg));
+    }
+
+    /// Attaches a tag to a connection, e.g. `premium`, for sending messages to all connections with the tag.
+    /// Unlike rooms, tags are kept with the connections and aren't shared with cluster nodes;
+    /// they're listed in `ConnectionInfo::tags`.
+    pub fn tag(&self, id: ConnectionId, tag: &str) {
+        self.send_internal(WebSocketInternalMessage::Tag(id, tag.to_string()));
+    }
+
+    pub fn untag(&self, id: ConnectionId, tag: &str) {
+        self.send_internal(WebSocketInternalMessage::Untag(id, tag.to_string()));
+    }
+
+    /// Sends a message to all connections of this server with a tag.
+    pub fn send_to_tag(&self, tag: &str, msg: WebSocketMessage) {
+        self.send_internal(WebSocketInternalMessage::SendToTag(tag.to_string(), msg));
+    }
+
+    /// Sends a message to all open connections of this server but the given ones, e.g. relays a chat message
+    /// to everyone but its sender. The payload is shared between the recipients instead of being copied.
+    pub fn broadcast_except(&self, except: &[ConnectionId], msg: WebSocketMessage) {
+        self.send_internal(WebSocketInternalMessage::BroadcastExcept(except.to_vec(), msg));
+    }
+
+    fn send_internal(&self, msg: WebSocketInternalMessage) -> Result<(), NotifyError<WebSocketInternalMessage>> {
         let mut val = msg;
         loop {
+            let ticket = self.gate.ticket();
             match self.event_loop_tx.send(val) {
                 Err(NotifyError::Full(ret)) => {
-                    // The notify queue is full, retry after some time.
+                    // The notify queue is full, wait until the event loop processes some messages.
+                    if !self.gate.wait(ticket) {
+                        return Err(NotifyError::Closed(Some(ret)));
+                    }
                     val = ret;
-                    thread::sleep(Duration::from_millis(10));
                 },
                 result @ _ => return result,
             }
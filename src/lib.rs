@@ -1,14 +1,42 @@
 extern crate mio;
 extern crate http_muncher;
 extern crate sha1;
-extern crate rustc_serialize;
 extern crate bytes;
 extern crate byteorder;
-extern crate websocket_essentials;
+extern crate libc;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "redis")]
+extern crate redis;
+#[cfg(feature = "lockfree")]
+extern crate crossbeam;
 
+mod admin;
+mod bus;
+mod channel;
 mod client;
+mod clock;
+mod cluster;
+mod config;
+mod frame;
+#[cfg(unix)]
+mod handover;
+mod handshake;
 mod http;
+mod notify;
+mod record;
+mod route;
 mod server;
+mod session;
+#[cfg(unix)]
+mod sockopt;
+mod source;
+mod throttle;
+mod transport;
+pub mod extension;
 pub mod interface;
+pub mod middleware;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "redis")]
+pub mod redis_bridge;
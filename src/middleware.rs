@@ -0,0 +1,127 @@
+/// Handshake middleware: checks and adjustments of WebSocket upgrade requests.
+///
+/// Middleware registered with `WebSocketBuilder::middleware` runs on every upgrade request that has passed
+/// the protocol checks, in the order of registration. Each middleware can add headers to the response,
+/// attach attributes to the connection for the ones that follow and for the application (see
+/// `ConnectionInfo::attributes`), or reject the handshake, in which case the rest of the chain isn't run.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use http::{self, Headers};
+
+/// Upgrade request being handled by the middleware chain, along with the parts of the response built so far.
+pub struct Handshake<'a> {
+    url: &'a str,
+    headers: &'a Headers,
+    route_params: &'a HashMap<String, String>,
+    peer_addr: SocketAddr,
+    response_headers: Vec<(String, String)>,
+    attributes: HashMap<String, String>
+}
+
+impl<'a> Handshake<'a> {
+    #[doc(hidden)]
+    pub fn new(url: &'a str, headers: &'a Headers, route_params: &'a HashMap<String, String>,
+               peer_addr: SocketAddr) -> Handshake<'a> {
+        Handshake {
+            url: url,
+            headers: headers,
+            route_params: route_params,
+            peer_addr: peer_addr,
+            response_headers: Vec::new(),
+            attributes: HashMap::new()
+        }
+    }
+
+    /// Request URL, including the query string.
+    pub fn url(&self) -> &str {
+        self.url
+    }
+
+    /// Percent-decoded parameters of the query string of the URL.
+    pub fn query_params(&self) -> HashMap<String, String> {
+        http::query_params(self.url)
+    }
+
+    /// Returns a parameter captured by the route the request has matched. See `WebSocketBuilder::route`.
+    pub fn route_param(&self, name: &str) -> Option<&str> {
+        self.route_params.get(name).map(|value| &value[..])
+    }
+
+    /// Returns the value of a request header. Names are case-insensitive; values of repeated headers
+    /// are combined into a comma-separated list, or a semicolon-separated one for `Cookie`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Returns all values of a request header in the order they've been received.
+    pub fn header_values(&self, name: &str) -> &[String] {
+        self.headers.get_all(name)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Adds a header to the `101 Switching Protocols` response.
+    pub fn add_response_header(&mut self, name: &str, value: &str) {
+        assert!(!name.contains(|c| c == '\r' || c == '\n') && !value.contains(|c| c == '\r' || c == '\n'),
+                "response header must not contain line breaks");
+        self.response_headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Returns an attribute attached by the middleware that has run before.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|value| &value[..])
+    }
+
+    /// Attaches an attribute to the connection, replacing the previous value.
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        self.attributes.insert(name.to_string(), value.to_string());
+    }
+
+    /// Response headers and attributes collected from the chain.
+    #[doc(hidden)]
+    pub fn into_parts(self) -> (Vec<(String, String)>, HashMap<String, String>) {
+        (self.response_headers, self.attributes)
+    }
+}
+
+/// Refusal of a handshake. The client gets an HTTP response with the status and the reason as the body.
+#[derive(Clone, Debug)]
+pub struct Rejection {
+    /// Status line, e.g. `403 Forbidden`.
+    pub status: String,
+    pub reason: String
+}
+
+impl Rejection {
+    pub fn new(status: &str, reason: &str) -> Rejection {
+        Rejection {
+            status: status.to_string(),
+            reason: reason.to_string()
+        }
+    }
+}
+
+/// A link in the handshake middleware chain. It's shared by all connections, so any state it keeps
+/// between handshakes has to be kept in cells.
+pub trait HandshakeMiddleware: Send {
+    fn handle(&self, handshake: &mut Handshake) -> Result<(), Rejection>;
+}
+
+impl<F> HandshakeMiddleware for F where F: Fn(&mut Handshake) -> Result<(), Rejection> + Send {
+    fn handle(&self, handshake: &mut Handshake) -> Result<(), Rejection> {
+        self(handshake)
+    }
+}
+
+/// Runs the chain until a middleware rejects the handshake.
+#[doc(hidden)]
+pub fn run(chain: &[Box<HandshakeMiddleware>], handshake: &mut Handshake) -> Result<(), Rejection> {
+    for middleware in chain {
+        try!(middleware.handle(handshake));
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::sync::{Mutex, Condvar};
+
+struct GateState {
+    // Number of times the gate has been opened.
+    generation: u64,
+    // The event loop has stopped and won't open the gate anymore.
+    closed: bool
+}
+
+/// Lets senders wait for room in the event loop notify queue instead of polling it.
+///
+/// The event loop opens the gate after every iteration, when it has had a chance to process queued messages.
+pub struct NotifyGate {
+    state: Mutex<GateState>,
+    opened: Condvar
+}
+
+impl NotifyGate {
+    pub fn new() -> NotifyGate {
+        NotifyGate {
+            state: Mutex::new(GateState {
+                generation: 0,
+                closed: false
+            }),
+            opened: Condvar::new()
+        }
+    }
+
+    /// Returns a ticket to wait on. It should be taken before trying to send,
+    /// so that the gate opened in between isn't missed.
+    pub fn ticket(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    /// Blocks until the gate is opened after the ticket has been taken.
+    /// Returns `false` if the gate has been closed for good.
+    pub fn wait(&self, ticket: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.generation == ticket && !state.closed {
+            state = self.opened.wait(state).unwrap();
+        }
+        !state.closed
+    }
+
+    /// Wakes up all waiting senders.
+    pub fn open(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.generation = state.generation.wrapping_add(1);
+        self.opened.notify_all();
+    }
+
+    /// Wakes up all waiting senders and makes further waits return immediately.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.opened.notify_all();
+    }
+}
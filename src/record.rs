@@ -0,0 +1,73 @@
+/// Recordings of the traffic received on connections, for reproducing problems in tests.
+///
+/// A recording is a sequence of the chunks read from a connection, handshake included. Every chunk is
+/// stored as the time since the connection has been accepted (microseconds, u64), the length (u32)
+/// and the bytes, with integers in the network byte order.
+
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(feature = "testing")]
+use std::io::Read;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "testing")]
+use std::time::Duration;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use clock;
+use interface::ConnectionId;
+
+const CHUNK_HEADER_LEN: usize = 12;
+
+pub struct Recorder {
+    file: File,
+    started: Instant
+}
+
+impl Recorder {
+    /// Starts a recording in the directory. The file is named after the connection and the time it has been accepted.
+    pub fn create(dir: &Path, id: ConnectionId) -> io::Result<Recorder> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        let file = try!(File::create(dir.join(format!("{}-{}.rec", since_epoch, id))));
+        Ok(Recorder {
+            file: file,
+            started: clock::now()
+        })
+    }
+
+    pub fn record(&mut self, data: &[u8]) -> io::Result<()> {
+        let elapsed = clock::elapsed(self.started);
+        let mut chunk = vec![0; CHUNK_HEADER_LEN];
+        BigEndian::write_u64(&mut chunk[..8], elapsed.as_secs() * 1000000 + (elapsed.subsec_nanos() / 1000) as u64);
+        BigEndian::write_u32(&mut chunk[8..], data.len() as u32);
+        chunk.extend_from_slice(data);
+        // Written at once, so that a recording cut short by a crash ends with a whole chunk.
+        self.file.write_all(&chunk)
+    }
+}
+
+/// Reads a recording. Returns the chunks along with the time they have been received at.
+#[cfg(feature = "testing")]
+pub fn read_recording(path: &Path) -> io::Result<Vec<(Duration, Vec<u8>)>> {
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+
+    let mut chunks = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        if rest.len() < CHUNK_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated chunk header"));
+        }
+        let micros = BigEndian::read_u64(&rest[..8]);
+        let len = BigEndian::read_u32(&rest[8..CHUNK_HEADER_LEN]) as usize;
+        rest = &rest[CHUNK_HEADER_LEN..];
+        if rest.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated chunk"));
+        }
+        let at = Duration::new(micros / 1000000, (micros % 1000000) as u32 * 1000);
+        chunks.push((at, rest[..len].to_vec()));
+        rest = &rest[len..];
+    }
+    Ok(chunks)
+}
@@ -0,0 +1,105 @@
+/// Fan-out across server instances through Redis pub/sub
+///
+/// Every instance subscribes to the same Redis channels and delivers messages published to a channel
+/// to its local members of the room with the same name. Client messages can be published to Redis,
+/// so that they reach the clients of all instances, including this one.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use redis::{self, Client, Connection, RedisResult};
+
+use interface::{ConnectionId, WebSocketEvent, WebSocketMessage, WsEvents, WsSender};
+
+// Delay before resubscribing after the subscription connection has failed.
+const RESUBSCRIBE_DELAY_MS: u64 = 1000;
+
+fn run_subscriber(client: &Client, channels: &[String], local: &WsSender) -> RedisResult<()> {
+    let mut connection = try!(client.get_connection());
+    let mut pubsub = connection.as_pubsub();
+
+    for channel in channels.iter() {
+        try!(pubsub.subscribe(&channel[..]));
+    }
+
+    loop {
+        let msg = try!(pubsub.get_message());
+        // Redis doesn't distinguish text from binary data.
+        let message = match String::from_utf8(msg.get_payload_bytes().to_vec()) {
+            Ok(text) => WebSocketMessage::Text(text),
+            Err(e) => WebSocketMessage::Binary(e.into_bytes())
+        };
+        local.publish_local(msg.get_channel_name(), message);
+    }
+}
+
+#[derive(Clone)]
+pub struct RedisBridge {
+    connection: Arc<Mutex<Connection>>
+}
+
+impl RedisBridge {
+    /// Connects to Redis at `url` and subscribes to `channels`, forwarding their messages into rooms of this server.
+    pub fn start(url: &str, channels: &[&str], local: WsSender) -> RedisResult<RedisBridge> {
+        let client = try!(Client::open(url));
+        let connection = try!(client.get_connection());
+        let channels = channels.iter().map(|channel| channel.to_string()).collect::<Vec<_>>();
+
+        thread::spawn(move || {
+            loop {
+                if let Err(e) = run_subscriber(&client, &channels, &local) {
+                    warn!("Redis subscription has failed: {}", e);
+                }
+                thread::sleep(Duration::from_millis(RESUBSCRIBE_DELAY_MS));
+            }
+        });
+
+        Ok(RedisBridge {
+            connection: Arc::new(Mutex::new(connection))
+        })
+    }
+
+    /// Publishes a message to a Redis channel. Only text and binary messages are published.
+    pub fn publish(&self, channel: &str, msg: &WebSocketMessage) -> RedisResult<()> {
+        let payload = match *msg {
+            WebSocketMessage::Text(ref text) => text.as_bytes(),
+            WebSocketMessage::Binary(ref data) => &data[..],
+            WebSocketMessage::SharedText(ref text) => text.as_bytes(),
+            WebSocketMessage::SharedBinary(ref data) => &data[..],
+            _ => return Ok(())
+        };
+
+        let mut connection = self.connection.lock().unwrap();
+        redis::cmd("PUBLISH").arg(channel).arg(payload).query(&mut *connection)
+    }
+
+    /// Publishes client messages received from `events` to Redis. `select` picks the channel
+    /// to publish a message to, or returns `None` to skip it.
+    pub fn forward<F>(&self, mut events: WsEvents, select: F)
+        where F: Fn(ConnectionId, &WebSocketMessage) -> Option<String> + Send + 'static {
+        let bridge = self.clone();
+
+        thread::spawn(move || {
+            while let Some((id, event)) = events.try_next() {
+                let event = match event {
+                    WebSocketEvent::WithMetadata(_, event) => *event,
+                    event => event
+                };
+                let msg = match event {
+                    WebSocketEvent::TextMessage(text) => WebSocketMessage::Text(text),
+                    WebSocketEvent::BinaryMessage(data) => WebSocketMessage::Binary(data),
+                    WebSocketEvent::SharedText(text) => WebSocketMessage::SharedText(text),
+                    WebSocketEvent::SharedBinary(data) => WebSocketMessage::SharedBinary(data),
+                    _ => continue
+                };
+
+                if let Some(channel) = select(id, &msg) {
+                    if let Err(e) = bridge.publish(&channel, &msg) {
+                        error!("Failed to publish to Redis channel {}: {}", channel, e);
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use http;
+
+enum Segment {
+    Literal(String),
+    // Named segment, written as `:name` in the pattern.
+    Param(String)
+}
+
+/// Path pattern such as `/game/:id/ws`. Named segments match any non-empty segment of a path
+/// and capture it, percent-decoded, as a parameter. Empty segments are ignored, so trailing slashes don't matter.
+pub struct Route {
+    pattern: String,
+    segments: Vec<Segment>
+}
+
+impl Route {
+    pub fn new(pattern: &str) -> Route {
+        let segments = pattern.split('/').filter(|segment| !segment.is_empty()).map(|segment| {
+            if segment.starts_with(':') && segment.len() > 1 {
+                Segment::Param(segment[1..].to_string())
+            } else {
+                Segment::Literal(segment.to_string())
+            }
+        }).collect();
+
+        Route {
+            pattern: pattern.to_string(),
+            segments: segments
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Matches the path of a request URL. Returns the captured parameters if it matches.
+    pub fn matches(&self, url: &str) -> Option<HashMap<String, String>> {
+        let path: Vec<&str> = http::path(url).split('/').filter(|segment| !segment.is_empty()).collect();
+        if path.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (segment, value) in self.segments.iter().zip(path) {
+            match *segment {
+                Segment::Literal(ref literal) if literal == value => {},
+                Segment::Literal(..) => return None,
+                Segment::Param(ref name) => {
+                    params.insert(name.clone(), http::percent_decode_path(value));
+                }
+            }
+        }
+        Some(params)
+    }
+}
@@ -1,119 +1,1159 @@
-use std::collections::HashMap;
-use std::sync::mpsc;
+use std::cmp;
+use std::io;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::usize;
+use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::sync::{mpsc, Arc, RwLock};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 
+use libc;
 use mio::*;
 use mio::tcp::*;
+use frame::StatusCode;
 
+use admin;
+use http;
+use bus::EventBus;
 use client::WebSocketClient;
-use interface::{WebSocketEvent, WebSocketInternalMessage};
+use config::Config;
+#[cfg(unix)]
+use handover;
+use notify::NotifyGate;
+use session::SessionStore;
+#[cfg(unix)]
+use sockopt;
+use throttle::Throttle;
+use interface::{WebSocketMessage, WebSocketEvent, WebSocketInternalMessage, ConnectionInfo, ConnectionId,
+                ConnectionStats, FdExhaustion, LoadShedding, Priority, ServerStats};
 
 pub const SERVER_TOKEN: Token = Token(0);
 
+// Time given to clients to complete the closing handshake when the server shuts down.
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+// Interval of checking the memory taken by connections against the budget.
+pub const MEMORY_CHECK_INTERVAL_MS: u64 = 200;
+
+// Interval of retrying to accept connections after running out of file descriptors.
+const FD_RETRY_INTERVAL_MS: u64 = 100;
+
+// Smallest part of the server-wide send budget given to a connection at a time.
+const MIN_EGRESS_QUANTUM: usize = 1024;
+
+// Number of bytes a low priority connection writes per writable event.
+const LOW_PRIORITY_QUANTUM: usize = 64 * 1024;
+
+pub enum Timer {
+    Shutdown,
+    // A throttled connection can write again.
+    Throttle(ConnectionId),
+    // The server-wide send budget has been renewed.
+    Egress,
+    // Server stats are due to be reported.
+    Stats,
+    // Deferred connections can be accepted.
+    Accept,
+    // Memory taken by connections is due to be checked against the budget.
+    MemoryCheck,
+    // Clients are due to be checked for missed heartbeats.
+    HeartbeatCheck,
+    // Connections are due to be checked for stalled writes.
+    WriteCheck
+}
+
+pub fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000000) as u64
+}
+
+/// Timeouts are checked several times per window, so that a connection is closed soon after the timeout.
+pub fn timeout_check_interval_ms(window: Duration) -> u64 {
+    cmp::max(duration_ms(window) / 4, 1)
+}
+
+/// Server-wide send budget, shared by connections in turns.
+struct Egress {
+    throttle: Throttle,
+    // Connections waiting for the budget to be renewed, in the order they've run out of it.
+    waiting: VecDeque<ConnectionId>,
+    timer_set: bool
+}
+
+fn is_fd_exhaustion(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => true,
+        _ => false
+    }
+}
+
+fn reregister(event_loop: &mut EventLoop<WebSocketServer>, client: &WebSocketClient, token: Token,
+              reading_suspended: bool) {
+    let mut interest = client.effective_interest();
+    if reading_suspended {
+        interest.remove(EventSet::readable());
+    }
+    event_loop.reregister(&client.socket, token, interest,
+                          PollOpt::edge() | PollOpt::oneshot()).unwrap();
+}
+
 pub struct WebSocketServer {
     pub socket: TcpListener,
-    tx: mpsc::Sender<(Token,WebSocketEvent)>,
+    events: Rc<RefCell<EventBus>>,
     clients: HashMap<Token, WebSocketClient>,
-    token_counter: usize
+    // External sources added by the application along with their application tokens.
+    sources: HashMap<Token, (usize, Box<Evented + Send>)>,
+    // Piped connections; every pair is stored in both directions.
+    pipes: HashMap<ConnectionId, ConnectionId>,
+    // Addresses connections aren't accepted from, added with `WsSender::kick_addr`.
+    blocked_addrs: HashSet<IpAddr>,
+    // Members of rooms by room name. Empty rooms are removed.
+    rooms: HashMap<String, HashSet<ConnectionId>>,
+    sessions: Option<SessionStore>,
+    connections_accepted: u64,
+    // Traffic of closed connections.
+    closed_traffic: ConnectionStats,
+    // Ids of the open connections shared with the application for cheap liveness checks.
+    connected: Arc<RwLock<HashSet<ConnectionId>>>,
+    // Senders waiting for room in the notify queue.
+    gate: Arc<NotifyGate>,
+    // Callers of `WsSender::flush` waiting for connections to write all their output.
+    flush_waiters: HashMap<ConnectionId, Vec<mpsc::Sender<bool>>>,
+    // Connections to reregister once the current message or event loop iteration is handled, shared with the clients.
+    reregistrations: Rc<RefCell<Vec<ConnectionId>>>,
+    token_counter: usize,
+    // Tokens of closed connections available for reuse. Every reuse bumps the generation,
+    // so the ids of closed connections never match the new ones.
+    free_tokens: Vec<Token>,
+    // Tokens released during the current event loop iteration; they may still have pending readiness events.
+    released_tokens: Vec<Token>,
+    // Shared with the supervisor, so that connection ids aren't reused after the event loop restarts.
+    generation: Rc<Cell<u64>>,
+    config: Rc<Config>,
+    // Reading from all clients is suspended while the application lags behind with receiving events.
+    reading_suspended: bool,
+    // The server is waiting for clients to close connections before stopping the event loop.
+    shutting_down: bool,
+    egress: Option<Egress>,
+    // Limits the number of connections accepted per second.
+    accept_throttle: Option<Throttle>,
+    // Accepting is deferred until the accept rate allows it or the memory usage goes down.
+    accept_deferred: bool,
+    // Connections take more memory than the budget allows.
+    over_budget: bool,
+    // Accepting has failed for the lack of file descriptors and is being retried.
+    fds_exhausted: bool
 }
 
 impl WebSocketServer {
-    pub fn new(socket: TcpListener, tx: mpsc::Sender<(Token,WebSocketEvent)>) -> WebSocketServer {
+    pub fn new(socket: TcpListener, events: Rc<RefCell<EventBus>>, connected: Arc<RwLock<HashSet<ConnectionId>>>,
+               gate: Arc<NotifyGate>, generation: Rc<Cell<u64>>, config: Rc<Config>) -> WebSocketServer {
+        let egress = config.total_send_rate.map(|rate| Egress {
+            throttle: Throttle::new(rate),
+            waiting: VecDeque::new(),
+            timer_set: false
+        });
+        let accept_throttle = config.max_accept_rate.map(Throttle::new);
+
         WebSocketServer {
             socket: socket,
-            tx: tx,
+            events: events,
             token_counter: 1,
-            clients: HashMap::new()
+            free_tokens: Vec::new(),
+            released_tokens: Vec::new(),
+            generation: generation,
+            clients: HashMap::new(),
+            sources: HashMap::new(),
+            pipes: HashMap::new(),
+            blocked_addrs: HashSet::new(),
+            rooms: HashMap::new(),
+            connections_accepted: 0,
+            closed_traffic: ConnectionStats::default(),
+            sessions: config.sessions.map(|(buffer_size, ttl)| SessionStore::new(buffer_size, ttl)),
+            connected: connected,
+            gate: gate,
+            flush_waiters: HashMap::new(),
+            reregistrations: Rc::new(RefCell::new(Vec::new())),
+            config: config,
+            reading_suspended: false,
+            shutting_down: false,
+            egress: egress,
+            accept_throttle: accept_throttle,
+            accept_deferred: false,
+            over_budget: false,
+            fds_exhausted: false
+        }
+    }
+
+    fn next_token(&mut self) -> Token {
+        match self.free_tokens.pop() {
+            Some(token) => token,
+            None => {
+                let token = Token(self.token_counter);
+                self.token_counter += 1;
+                token
+            }
         }
     }
 
-    fn add_client(&mut self, client_socket: TcpStream, tx: mpsc::Sender<(Token,WebSocketEvent)>,
-                  event_loop_tx: Sender<WebSocketInternalMessage>) -> Token {
-        let new_token = Token(self.token_counter);
-        self.token_counter += 1;
+    fn add_client(&mut self, client_socket: TcpStream, peer_addr: SocketAddr) -> Token {
+        let new_token = self.next_token();
+        let generation = self.generation.get() + 1;
+        self.generation.set(generation);
 
-        self.clients.insert(new_token, WebSocketClient::new(client_socket, new_token, tx.clone(), event_loop_tx));
+        let id = ConnectionId::new(new_token, generation);
+        self.connections_accepted += 1;
+        self.connected.write().unwrap().insert(id);
+        self.clients.insert(new_token, WebSocketClient::new(client_socket, peer_addr, id, self.events.clone(),
+                                                            self.reregistrations.clone(), self.config.clone()));
+        #[cfg(unix)]
+        {
+            if let Some(dscp) = self.config.dscp {
+                self.set_dscp(id, dscp);
+            }
+        }
         new_token
     }
 
-    pub fn get_peers(&self) -> Vec<Token> {
-        self.clients.keys().cloned().collect::<Vec<_>>()
+    fn get_client(&self, id: &ConnectionId) -> Option<&WebSocketClient> {
+        self.clients.get(&id.token()).and_then(|client| if client.id() == *id { Some(client) } else { None })
+    }
+
+    fn get_client_mut(&mut self, id: &ConnectionId) -> Option<&mut WebSocketClient> {
+        self.clients.get_mut(&id.token()).and_then(|client| if client.id() == *id { Some(client) } else { None })
+    }
+
+    pub fn get_peers(&self) -> Vec<ConnectionInfo> {
+        self.clients.values().map(|client| client.connection_info()).collect::<Vec<_>>()
+    }
+
+    pub fn get_connection_info(&self, id: &ConnectionId) -> Option<ConnectionInfo> {
+        self.get_client(id).map(|client| client.connection_info())
     }
 
     fn remove_client(&mut self, tkn: &Token) -> Option<WebSocketClient> {
-        self.clients.remove(tkn)
+        let client = self.clients.remove(tkn);
+        if let Some(ref client) = client {
+            self.closed_traffic.add(&client.stats());
+
+            let id = client.id();
+            self.connected.write().unwrap().remove(&id);
+            // The final messages may have been written before the connection went away.
+            for tx in self.flush_waiters.remove(&id).unwrap_or(Vec::new()) {
+                let _ = tx.send(!client.has_pending_writes());
+            }
+            let rooms = self.rooms.keys().cloned().collect::<Vec<_>>();
+            for room in rooms {
+                self.leave_room(id, &room);
+            }
+            if let Some(peer) = self.unpipe(id) {
+                // The other end has gone away.
+                self.send_message((peer, WebSocketMessage::Close(StatusCode::from(1001))));
+            }
+            self.released_tokens.push(*tkn);
+        }
+        client
     }
 
-    pub fn send_message(&mut self, msg: (Token,WebSocketEvent)) {
-        let (tkn, message) = msg;
-        let client = self.clients.get_mut(&tkn).unwrap();
+    /// Answers a flush request right away if the connection has nothing to write, or once it has written everything.
+    fn flush(&mut self, id: ConnectionId, tx: mpsc::Sender<bool>) {
+        match self.get_client(&id).map(|client| client.has_pending_writes()) {
+            Some(true) => self.flush_waiters.entry(id).or_insert_with(Vec::new).push(tx),
+            Some(false) => { let _ = tx.send(true); },
+            None => { let _ = tx.send(false); }
+        }
+    }
+
+    /// Wakes up the callers waiting for the connection to write all its output.
+    fn notify_flushed(&mut self, tkn: &Token) {
+        let id = match self.clients.get(tkn) {
+            Some(client) if !client.has_pending_writes() => client.id(),
+            _ => return
+        };
+        for tx in self.flush_waiters.remove(&id).unwrap_or(Vec::new()) {
+            let _ = tx.send(true);
+        }
+    }
+
+    pub fn send_message(&mut self, msg: (ConnectionId,WebSocketMessage)) {
+        let (id, message) = msg;
+        if self.get_client(&id).is_none() {
+            // The connection has been closed already. If it has belonged to a session,
+            // the message is kept until the client reconnects.
+            if let Some(ref mut sessions) = self.sessions {
+                if let Some(token) = sessions.token(&id).map(|token| token.to_string()) {
+                    sessions.buffer(&token, message);
+                    return;
+                }
+            }
+            debug!("{} is not connected, dropping message", id);
+            return;
+        }
+
+        let client = self.get_client_mut(&id).unwrap();
         if let Err(e) = client.send_message(message) {
-            error!("Error while sending msg to client: {}", e);
+            error!("{} error while sending msg to client: {}", client.log_context(), e);
         }
         // TODO: return Result here
     }
+
+    fn send_to_session(&mut self, token: &str, message: WebSocketMessage) {
+        let connection = match self.sessions {
+            Some(ref mut sessions) => {
+                match sessions.connection(token) {
+                    Some(id) => id,
+                    None => {
+                        if !sessions.buffer(token, message) {
+                            debug!("no session {}, dropping message", token);
+                        }
+                        return;
+                    }
+                }
+            },
+            None => {
+                warn!("sessions are disabled, dropping message");
+                return;
+            }
+        };
+        self.send_message((connection, message));
+    }
+
+    /// Reregisters the connections whose interest has changed outside of their readiness events, e.g. by sending
+    /// them messages. This is done by the event loop itself rather than through the notify queue, which may be full.
+    fn apply_reregistrations(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        let reregistrations = self.reregistrations.borrow_mut().drain(..).collect::<Vec<_>>();
+        for id in reregistrations {
+            if let Some(client) = self.get_client(&id) {
+                reregister(event_loop, client, id.token(), self.reading_suspended);
+            }
+        }
+    }
+
+    /// Binds a client that has completed the handshake to its session and replays the messages
+    /// sent while it was away.
+    fn resume_session(&mut self, tkn: &Token) {
+        let (id, token, affinity, log) = match self.clients.get(tkn) {
+            Some(client) => match client.session_token() {
+                Some(token) => (client.id(), token, client.affinity(), client.log_context().to_string()),
+                None => return
+            },
+            None => return
+        };
+
+        if let Some(affinity) = affinity {
+            if self.config.affinity.as_ref().map_or(false, |&(_, ref instance_id)| *instance_id != affinity) {
+                debug!("{} session {} has moved from instance {}", log, token, affinity);
+            }
+        }
+
+        let (replay, next_sequence) = match self.sessions {
+            Some(ref mut sessions) => sessions.attach(&token, id),
+            None => return
+        };
+        if let Some(client) = self.clients.get_mut(tkn) {
+            client.set_next_sequence(next_sequence);
+        }
+        if !replay.is_empty() {
+            debug!("{} resumed session {}, replaying {} messages", log, token, replay.len());
+        }
+        for message in replay {
+            self.send_message((id, message));
+        }
+    }
+
+    fn totals(&self) -> ServerStats {
+        let mut traffic = self.closed_traffic;
+        for client in self.clients.values() {
+            traffic.add(&client.stats());
+        }
+
+        ServerStats {
+            connections_accepted: self.connections_accepted,
+            connections_open: self.clients.len() as u64,
+            traffic: traffic
+        }
+    }
+
+    /// Responds to a plain HTTP request, if the client has sent one.
+    fn serve_http(&mut self, tkn: &Token) {
+        let (method, url, authorization) = match self.clients.get(tkn) {
+            Some(client) => match client.http_request() {
+                Some((method, url)) => (method, url, client.request_header("Authorization")),
+                None => return
+            },
+            None => return
+        };
+
+        let config = self.config.clone();
+        let response = match config.admin_token {
+            Some(ref token) if !admin::is_authorized(token, authorization.as_ref().map(|auth| &auth[..]), &url) &&
+                               (http::path(&url) == admin::ADMIN_PATH || http::path(&url) == admin::DSCP_PATH) => {
+                Some(("401 Unauthorized", "text/plain", "Unauthorized".to_string()))
+            },
+            Some(_) if http::path(&url) == admin::ADMIN_PATH => {
+                let connections = self.clients.values().map(|client| client.connection_info()).collect::<Vec<_>>();
+                Some(("200 OK", "application/json", admin::render(&connections, &self.totals())))
+            },
+            #[cfg(unix)]
+            Some(_) if http::path(&url) == admin::DSCP_PATH => {
+                match admin::dscp_params(&url) {
+                    Some((id, dscp)) if self.get_client(&id).is_some() => {
+                        self.set_dscp(id, dscp);
+                        Some(("200 OK", "text/plain", "OK".to_string()))
+                    },
+                    Some(_) => Some(("404 Not Found", "text/plain", "No such connection".to_string())),
+                    None => Some(("400 Bad Request", "text/plain", "Expected connection and value (0-63)".to_string()))
+                }
+            },
+            _ => None
+        };
+        let response = response.map(|(status, content_type, body)| (status, content_type, body.into_bytes()));
+
+        let response = response.or_else(|| match config.static_responses.get(http::path(&url)) {
+            Some(&(ref content_type, ref body)) if method == "GET" => Some(("200 OK", &content_type[..], body.clone())),
+            Some(_) => Some(("405 Method Not Allowed", "text/plain", b"Only GET requests are served".to_vec())),
+            None => None
+        });
+
+        if let Some(client) = self.clients.get_mut(tkn) {
+            match response {
+                Some((status, content_type, body)) => client.respond_http(status, content_type, &body),
+                None => client.require_upgrade()
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_dscp(&self, id: ConnectionId, dscp: u8) {
+        if let Some(client) = self.get_client(&id) {
+            let ipv6 = match client.peer_addr() {
+                SocketAddr::V6(_) => true,
+                SocketAddr::V4(_) => false
+            };
+            if let Err(e) = sockopt::set_dscp(client.socket.as_raw_fd(), ipv6, dscp) {
+                warn!("{} failed to set DSCP: {}", client.log_context(), e);
+            }
+        }
+    }
+
+    /// Accepts connections until the backlog is empty. The listener is edge-triggered, so connections left
+    /// in the backlog wouldn't be reported again. Once the accept rate is exceeded, pending connections are left
+    /// in the backlog and accepted when the rate allows it.
+    fn accept(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        loop {
+            if self.over_budget {
+                self.accept_deferred = true;
+                return;
+            }
+            if let Some(ref mut throttle) = self.accept_throttle {
+                if throttle.allowance() == 0 {
+                    trace!("accept rate exceeded, deferring accepts");
+                    self.accept_deferred = true;
+                    if let Err(e) = event_loop.timeout_ms(Timer::Accept, throttle.delay_ms()) {
+                        error!("Failed to set accept timeout: {:?}", e);
+                        self.accept_deferred = false;
+                    }
+                    return;
+                }
+            }
+
+            let (client_socket, peer_addr) = match self.socket.accept() {
+                Ok(Some((sock, addr))) => (sock, addr),
+                Ok(None) => {
+                    // The backlog is empty; the next connection is accepted on the readiness event.
+                    self.accept_deferred = false;
+                    return;
+                },
+                Err(ref e) if is_fd_exhaustion(e) => {
+                    self.on_fd_exhaustion(event_loop);
+                    return;
+                },
+                Err(e) => {
+                    error!("Accept error: {}", e);
+                    self.accept_deferred = false;
+                    return;
+                }
+            };
+            if let Some(ref mut throttle) = self.accept_throttle {
+                throttle.consume(1);
+            }
+            if self.fds_exhausted {
+                debug!("file descriptors are available again with {} connections", self.clients.len());
+                self.fds_exhausted = false;
+            }
+
+            if self.blocked_addrs.contains(&peer_addr.ip()) {
+                // Dropping the socket closes the connection.
+                debug!("{} is blocked, closing connection", peer_addr);
+            } else {
+                let new_token = self.add_client(client_socket, peer_addr);
+
+                event_loop.register(&self.clients[&new_token].socket,
+                                    new_token, EventSet::readable(),
+                                    PollOpt::edge() | PollOpt::oneshot()).unwrap();
+            }
+        }
+    }
+
+    /// Handles running out of file descriptors while accepting. The pending connections stay in the backlog,
+    /// and the listener, being edge-triggered, wouldn't report them again until another one arrives,
+    /// so accepting is retried periodically. Meanwhile the idlest connection may be closed to make room.
+    fn on_fd_exhaustion(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        if !self.fds_exhausted {
+            warn!("out of file descriptors with {} connections, pausing accepting", self.clients.len());
+            self.fds_exhausted = true;
+        }
+
+        if self.config.fd_exhaustion == FdExhaustion::CloseIdlest {
+            let idlest = self.clients.iter()
+                .filter(|&(_, client)| client.is_connected())
+                .min_by_key(|&(_, client)| client.last_received())
+                .map(|(tkn, _)| *tkn);
+            if let Some(tkn) = idlest {
+                if let Some(client) = self.clients.get(&tkn) {
+                    warn!("{} is the idlest connection, closing it", client.log_context());
+                }
+                self.shed_client(event_loop, tkn);
+            }
+        }
+
+        self.accept_deferred = true;
+        if let Err(e) = event_loop.timeout_ms(Timer::Accept, FD_RETRY_INTERVAL_MS) {
+            error!("Failed to set accept timeout: {:?}", e);
+            self.accept_deferred = false;
+        }
+    }
+
+    /// Closes a connection with the status of `CloseCause::Overload`, discarding the messages queued for it.
+    fn shed_client(&mut self, event_loop: &mut EventLoop<WebSocketServer>, tkn: Token) {
+        let closing = match self.clients.get_mut(&tkn) {
+            Some(client) => client.shed(),
+            None => return
+        };
+        if closing {
+            reregister(event_loop, &self.clients[&tkn], tkn, self.reading_suspended);
+        } else {
+            self.drop_client(event_loop, &tkn);
+        }
+    }
+
+    /// Closes the clients that haven't sent a heartbeat in time.
+    fn check_heartbeats(&mut self, event_loop: &mut EventLoop<WebSocketServer>, window: Duration) {
+        let tokens = self.clients.keys().cloned().collect::<Vec<_>>();
+        for tkn in tokens {
+            if self.clients.get_mut(&tkn).unwrap().check_heartbeat(window) {
+                reregister(event_loop, &self.clients[&tkn], tkn, self.reading_suspended);
+            }
+        }
+    }
+
+    /// Drops the connections that haven't written anything in time while having output pending.
+    fn check_write_progress(&mut self, event_loop: &mut EventLoop<WebSocketServer>, timeout: Duration) {
+        let tokens = self.clients.keys().cloned().collect::<Vec<_>>();
+        for tkn in tokens {
+            if self.clients.get_mut(&tkn).unwrap().check_write_progress(timeout) {
+                self.drop_client(event_loop, &tkn);
+            }
+        }
+    }
+
+    /// Checks the memory taken by connection buffers against the budget and sheds load if it's exceeded.
+    fn check_memory(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        let (budget, shedding) = match self.config.memory_budget {
+            Some(budget) => budget,
+            None => return
+        };
+
+        let mut usage = self.clients.iter().map(|(tkn, client)| (client.buffered_bytes(), *tkn)).collect::<Vec<_>>();
+        let mut total = usage.iter().map(|&(bytes, _)| bytes).sum::<usize>();
+        if total <= budget {
+            if self.over_budget {
+                debug!("memory usage is back within the budget, resuming accepting");
+                self.over_budget = false;
+                // Connections have been waiting in the backlog.
+                self.accept_deferred = true;
+                self.accept(event_loop);
+            }
+            return;
+        }
+
+        if !self.over_budget {
+            warn!("connections take {} bytes, over the budget of {}; stopping accepting", total, budget);
+            self.over_budget = true;
+        }
+        if shedding != LoadShedding::CloseHeaviest {
+            return;
+        }
+
+        usage.sort_by(|a, b| b.0.cmp(&a.0));
+        for (bytes, tkn) in usage {
+            if total <= budget {
+                break;
+            }
+            match self.clients.get(&tkn) {
+                Some(client) => warn!("{} holds {} bytes, closing connection", client.log_context(), bytes),
+                None => continue
+            }
+            self.shed_client(event_loop, tkn);
+            total -= bytes;
+        }
+    }
+
+    fn join_room(&mut self, id: ConnectionId, room: String) {
+        if self.get_client(&id).is_some() {
+            self.rooms.entry(room).or_insert_with(HashSet::new).insert(id);
+        }
+    }
+
+    fn leave_room(&mut self, id: ConnectionId, room: &str) {
+        let empty = match self.rooms.get_mut(room) {
+            Some(members) => {
+                members.remove(&id);
+                members.is_empty()
+            },
+            None => return
+        };
+        if empty {
+            self.rooms.remove(room);
+        }
+    }
+
+    /// Sends a message to all members of a room.
+    fn publish(&mut self, room: &str, message: WebSocketMessage) {
+        let members = match self.rooms.get(room) {
+            Some(members) => members.iter().cloned().collect::<Vec<_>>(),
+            None => return
+        };
+        for id in members {
+            self.send_message((id, message.clone()));
+        }
+    }
+
+    /// Sends a message to all connections with a tag.
+    fn send_to_tag(&mut self, tag: &str, message: WebSocketMessage) {
+        let tagged = self.clients.values()
+            .filter(|client| client.tags.contains(tag))
+            .map(|client| client.id())
+            .collect::<Vec<_>>();
+        for id in tagged {
+            self.send_message((id, message.clone()));
+        }
+    }
+
+    /// Sends a message to all open connections except the given ones, sharing its payload between them.
+    fn broadcast_except(&mut self, except: &[ConnectionId], message: WebSocketMessage) {
+        let message = match message {
+            WebSocketMessage::Text(text) => WebSocketMessage::SharedText(Arc::from(text)),
+            WebSocketMessage::Binary(data) => WebSocketMessage::SharedBinary(Arc::from(data)),
+            message => message
+        };
+        let recipients = self.clients.values()
+            .filter(|client| client.is_connected() && !except.contains(&client.id()))
+            .map(|client| client.id())
+            .collect::<Vec<_>>();
+        for id in recipients {
+            self.send_message((id, message.clone()));
+        }
+    }
+
+    /// Closes all connections from an address and, with `block`, stops accepting connections from it.
+    /// Open connections get a Close frame, the ones that haven't completed the handshake are dropped,
+    /// and the ones that are already closing are left to finish.
+    fn kick_addr(&mut self, event_loop: &mut EventLoop<WebSocketServer>, ip: IpAddr, status: StatusCode,
+                 block: bool) {
+        let kicked = self.clients.iter()
+            .filter(|&(_, client)| client.peer_addr().ip() == ip)
+            .map(|(tkn, client)| (*tkn, client.id(), client.is_handshaking(), client.is_closing()))
+            .collect::<Vec<_>>();
+        debug!("kicking {} connections from {}{}", kicked.len(), ip, if block { ", blocking it" } else { "" });
+        for (tkn, id, handshaking, closing) in kicked {
+            if handshaking {
+                self.drop_client(event_loop, &tkn);
+            } else if !closing {
+                // Connections that are already closing are left to finish writing their Close frames.
+                self.send_message((id, WebSocketMessage::Close(status.clone())));
+            }
+        }
+        if block {
+            self.blocked_addrs.insert(ip);
+        }
+    }
+
+    fn add_source(&mut self, event_loop: &mut EventLoop<WebSocketServer>, source_token: usize,
+                  source: Box<Evented + Send>, interest: EventSet) {
+        self.remove_source(event_loop, source_token);
+
+        let token = self.next_token();
+        if let Err(e) = event_loop.register(&*source, token, interest, PollOpt::edge()) {
+            error!("failed to register source {}: {}", source_token, e);
+            self.free_tokens.push(token);
+            return;
+        }
+        self.sources.insert(token, (source_token, source));
+    }
+
+    fn remove_source(&mut self, event_loop: &mut EventLoop<WebSocketServer>, source_token: usize) {
+        let token = match self.sources.iter().find(|&(_, &(tkn, _))| tkn == source_token) {
+            Some((token, _)) => *token,
+            None => return
+        };
+        if let Some((_, source)) = self.sources.remove(&token) {
+            event_loop.deregister(&*source);
+            self.released_tokens.push(token);
+        }
+    }
+
+    /// Number of bytes a connection can write now. Connections get equal parts of the server-wide budget,
+    /// and take turns as writable events come in.
+    fn egress_quota(&self) -> usize {
+        match self.egress {
+            Some(ref egress) => {
+                let quantum = cmp::max(egress.throttle.rate() / cmp::max(self.clients.len(), 1), MIN_EGRESS_QUANTUM);
+                cmp::min(egress.throttle.allowance(), quantum)
+            },
+            None => usize::MAX
+        }
+    }
+
+    /// Number of bytes a connection can write now, according to its priority.
+    fn write_quota(&self, token: &Token) -> usize {
+        match self.clients.get(token).map(|client| client.priority) {
+            Some(Priority::High) => usize::MAX,
+            Some(Priority::Low) => cmp::min(self.egress_quota(), LOW_PRIORITY_QUANTUM),
+            _ => self.egress_quota()
+        }
+    }
+
+    /// Makes a connection wait until the server-wide budget is renewed.
+    fn wait_for_egress(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+        let id = match self.clients.get_mut(&token) {
+            Some(client) => {
+                client.egress_blocked = true;
+                client.id()
+            },
+            None => return
+        };
+        if let Some(ref mut egress) = self.egress {
+            egress.waiting.push_back(id);
+            if !egress.timer_set {
+                match event_loop.timeout_ms(Timer::Egress, egress.throttle.delay_ms()) {
+                    Ok(_) => egress.timer_set = true,
+                    Err(e) => error!("Failed to set egress timeout: {:?}", e)
+                }
+            }
+        }
+    }
+
+    fn renew_egress(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        let waiting = match self.egress {
+            Some(ref mut egress) => {
+                egress.timer_set = false;
+                egress.waiting.drain(..).collect::<Vec<_>>()
+            },
+            None => return
+        };
+        let reading_suspended = self.reading_suspended;
+        for id in waiting {
+            if let Some(client) = self.get_client_mut(&id) {
+                client.egress_blocked = false;
+                reregister(event_loop, client, id.token(), reading_suspended);
+            }
+        }
+    }
+
+    fn pipe(&mut self, a: ConnectionId, b: ConnectionId) {
+        if a == b || self.get_client(&a).is_none() || self.get_client(&b).is_none() {
+            return;
+        }
+        self.unpipe(a);
+        self.unpipe(b);
+        for &(id, peer) in [(a, b), (b, a)].iter() {
+            self.pipes.insert(id, peer);
+            if let Some(client) = self.get_client_mut(&id) {
+                client.piped = true;
+            }
+        }
+    }
+
+    /// Disconnects a piped connection from its peer. Returns the peer.
+    fn unpipe(&mut self, id: ConnectionId) -> Option<ConnectionId> {
+        let peer = match self.pipes.remove(&id) {
+            Some(peer) => peer,
+            None => return None
+        };
+        self.pipes.remove(&peer);
+        for id in [id, peer].iter() {
+            if let Some(client) = self.get_client_mut(id) {
+                client.piped = false;
+            }
+        }
+        Some(peer)
+    }
+
+    /// Sends the frames received from a piped connection to its peer.
+    fn forward_piped(&mut self, tkn: &Token) {
+        let (id, frames) = match self.clients.get_mut(tkn) {
+            Some(client) if client.piped => (client.id(), client.take_forwarded()),
+            _ => return
+        };
+        let peer = match self.pipes.get(&id) {
+            Some(peer) => *peer,
+            None => return
+        };
+        for frame in frames {
+            self.send_message((peer, WebSocketMessage::Frame(frame)));
+        }
+    }
+
+    fn set_paused(&mut self, event_loop: &mut EventLoop<WebSocketServer>, id: ConnectionId, paused: bool) {
+        let reading_suspended = self.reading_suspended;
+        if let Some(client) = self.get_client_mut(&id) {
+            client.paused = paused;
+            reregister(event_loop, client, id.token(), reading_suspended);
+        }
+    }
+
+    fn drop_client(&mut self, event_loop: &mut EventLoop<WebSocketServer>, tkn: &Token) {
+        if let Some(mut client) = self.remove_client(tkn) {
+            client.on_disconnect();
+            event_loop.deregister(&client.socket);
+            if let Some(ref mut sessions) = self.sessions {
+                sessions.detach(client.id(), client.next_sequence());
+            }
+        }
+    }
+
+    /// Drops a client that is done with its connection. Returns `true` if it's been dropped.
+    /// The server doesn't wait for a hup event: with edge-triggered registrations it may never come,
+    /// leaving the client behind.
+    fn drop_if_closed(&mut self, event_loop: &mut EventLoop<WebSocketServer>, tkn: Token) -> bool {
+        match self.clients.get(&tkn) {
+            Some(client) if client.is_closed() => trace!("{} connection is closed", client.log_context()),
+            _ => return false
+        }
+        self.drop_client(event_loop, &tkn);
+        true
+    }
+
+    /// Stops accepting connections and closes existing ones with the status of `CloseCause::Shutdown`.
+    fn shutdown(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        if self.shutting_down {
+            return;
+        }
+
+        debug!("shutting down, closing {} connections", self.clients.len());
+        self.shutting_down = true;
+        event_loop.deregister(&self.socket);
+
+        let mut dropped = Vec::new();
+        for (tkn, client) in self.clients.iter_mut() {
+            if client.go_away() {
+                reregister(event_loop, client, *tkn, self.reading_suspended);
+            } else {
+                dropped.push(*tkn);
+            }
+        }
+        for tkn in dropped {
+            self.drop_client(event_loop, &tkn);
+        }
+
+        if let Err(e) = event_loop.timeout_ms(Timer::Shutdown, SHUTDOWN_TIMEOUT_MS) {
+            error!("Failed to set shutdown timeout: {:?}", e);
+            event_loop.shutdown();
+        }
+    }
+
+    fn resume_reading(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        if self.reading_suspended {
+            if self.events.borrow().is_congested() {
+                return;
+            }
+            trace!("event receivers have caught up, resuming reading");
+            self.reading_suspended = false;
+        }
+
+        // Connections suspended by their own watermarks may have caught up as well.
+
+        for (tkn, client) in self.clients.iter() {
+            reregister(event_loop, client, *tkn, false);
+        }
+    }
 }
 
 impl Handler for WebSocketServer {
-    type Timeout = usize;
+    type Timeout = Timer;
     type Message = WebSocketInternalMessage;
 
     fn notify(&mut self, event_loop: &mut EventLoop<WebSocketServer>, msg: WebSocketInternalMessage) {
         match msg {
-            WebSocketInternalMessage::Reregister(tkn) => {
-                let client = self.clients.get(&tkn).unwrap();
-                event_loop.reregister(&client.socket, tkn, client.interest,
-                                      PollOpt::edge() | PollOpt::oneshot()).unwrap();
+            WebSocketInternalMessage::ResumeReading => {
+                self.resume_reading(event_loop);
+            },
+            WebSocketInternalMessage::Pause(id) => {
+                self.set_paused(event_loop, id, true);
+            },
+            WebSocketInternalMessage::Resume(id) => {
+                self.set_paused(event_loop, id, false);
+            },
+            WebSocketInternalMessage::SetRawMode(id, enabled) => {
+                if let Some(client) = self.get_client_mut(&id) {
+                    client.raw_mode = enabled;
+                }
+            },
+            WebSocketInternalMessage::AddSource(source_token, source, interest) => {
+                self.add_source(event_loop, source_token, source, interest);
+            },
+            WebSocketInternalMessage::RemoveSource(source_token) => {
+                self.remove_source(event_loop, source_token);
             },
             WebSocketInternalMessage::SendMessage(msg) => {
                 self.send_message(msg);
             },
+            #[cfg(unix)]
+            WebSocketInternalMessage::SetSocketBuffers(id, recv, send) => {
+                if let Some(client) = self.get_client(&id) {
+                    let fd = client.socket.as_raw_fd();
+                    let result = recv.map_or(Ok(()), |size| sockopt::set_recv_buffer(fd, size))
+                        .and_then(|_| send.map_or(Ok(()), |size| sockopt::set_send_buffer(fd, size)));
+                    if let Err(e) = result {
+                        warn!("{} failed to set socket buffer sizes: {}", client.log_context(), e);
+                    }
+                }
+            },
+            #[cfg(unix)]
+            WebSocketInternalMessage::SetDscp(id, dscp) => {
+                self.set_dscp(id, dscp);
+            },
+            WebSocketInternalMessage::Pipe(a, b) => {
+                self.pipe(a, b);
+            },
+            WebSocketInternalMessage::Unpipe(id) => {
+                self.unpipe(id);
+            },
+            WebSocketInternalMessage::SendPrioritized(id, msg, priority) => {
+                if let Some(client) = self.get_client_mut(&id) {
+                    if let Err(e) = client.send_message_prioritized(msg, priority) {
+                        error!("{} error while sending msg to client: {}", client.log_context(), e);
+                    }
+                }
+            },
+            WebSocketInternalMessage::SetPriority(id, priority) => {
+                if let Some(client) = self.get_client_mut(&id) {
+                    client.priority = priority;
+                }
+            },
+            WebSocketInternalMessage::SendExpiring(id, msg, deadline) => {
+                if let Some(client) = self.get_client_mut(&id) {
+                    if let Err(e) = client.send_message_expiring(msg, deadline) {
+                        error!("{} error while sending msg to client: {}", client.log_context(), e);
+                    }
+                }
+            },
             WebSocketInternalMessage::GetPeers(tx) => {
                 tx.send(self.get_peers());
+            },
+            WebSocketInternalMessage::GetConnectionInfo(id, tx) => {
+                tx.send(self.get_connection_info(&id));
+            },
+            WebSocketInternalMessage::Flush(id, tx) => {
+                self.flush(id, tx);
+            },
+            WebSocketInternalMessage::Subscribe(filter, route, tx) => {
+                self.events.borrow_mut().subscribe(filter, route, tx);
+            },
+            WebSocketInternalMessage::SendToSession(token, msg) => {
+                self.send_to_session(&token, msg);
+            },
+            #[cfg(unix)]
+            WebSocketInternalMessage::ExportListener(tx) => {
+                tx.send(handover::export(self.socket.as_raw_fd()));
+            },
+            WebSocketInternalMessage::JoinRoom(id, room) => {
+                self.join_room(id, room);
+            },
+            WebSocketInternalMessage::LeaveRoom(id, room) => {
+                self.leave_room(id, &room);
+            },
+            WebSocketInternalMessage::Publish(room, msg) => {
+                self.publish(&room, msg);
+            },
+            WebSocketInternalMessage::Tag(id, tag) => {
+                if let Some(client) = self.get_client_mut(&id) {
+                    client.tags.insert(tag);
+                }
+            },
+            WebSocketInternalMessage::Untag(id, tag) => {
+                if let Some(client) = self.get_client_mut(&id) {
+                    client.tags.remove(&tag);
+                }
+            },
+            WebSocketInternalMessage::SendToTag(tag, msg) => {
+                self.send_to_tag(&tag, msg);
+            },
+            WebSocketInternalMessage::BroadcastExcept(except, msg) => {
+                self.broadcast_except(&except, msg);
+            },
+            WebSocketInternalMessage::KickAddr(ip, status, block) => {
+                self.kick_addr(event_loop, ip, status, block);
+            },
+            WebSocketInternalMessage::UnblockAddr(ip) => {
+                self.blocked_addrs.remove(&ip);
+            },
+            WebSocketInternalMessage::Shutdown => {
+                self.shutdown(event_loop);
+            }
+        }
+        // Connections the message has queued frames to start writing right away.
+        self.apply_reregistrations(event_loop);
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<WebSocketServer>, timer: Timer) {
+        match timer {
+            Timer::Shutdown => {
+                warn!("{} clients haven't closed connections in time, dropping them", self.clients.len());
+                let tokens = self.clients.keys().cloned().collect::<Vec<_>>();
+                for tkn in tokens {
+                    self.drop_client(event_loop, &tkn);
+                }
+                event_loop.shutdown();
+            },
+            Timer::Egress => {
+                self.renew_egress(event_loop);
+            },
+            Timer::Accept => {
+                self.accept(event_loop);
+            },
+            Timer::MemoryCheck => {
+                self.check_memory(event_loop);
+                if let Err(e) = event_loop.timeout_ms(Timer::MemoryCheck, MEMORY_CHECK_INTERVAL_MS) {
+                    error!("Failed to set memory check timeout: {:?}", e);
+                }
+            },
+            Timer::HeartbeatCheck => {
+                if let Some((window, _)) = self.config.heartbeat {
+                    self.check_heartbeats(event_loop, window);
+                    if let Err(e) = event_loop.timeout_ms(Timer::HeartbeatCheck, timeout_check_interval_ms(window)) {
+                        error!("Failed to set heartbeat check timeout: {:?}", e);
+                    }
+                }
+            },
+            Timer::WriteCheck => {
+                if let Some(timeout) = self.config.write_timeout {
+                    self.check_write_progress(event_loop, timeout);
+                    if let Err(e) = event_loop.timeout_ms(Timer::WriteCheck, timeout_check_interval_ms(timeout)) {
+                        error!("Failed to set write check timeout: {:?}", e);
+                    }
+                }
+            },
+            Timer::Stats => {
+                let stats = self.totals();
+                self.events.borrow_mut().publish(ConnectionId::server(), WebSocketEvent::Stats(stats));
+                if let Some(interval) = self.config.stats_interval {
+                    if let Err(e) = event_loop.timeout_ms(Timer::Stats, duration_ms(interval)) {
+                        error!("Failed to set stats timeout: {:?}", e);
+                    }
+                }
+            },
+            Timer::Throttle(id) => {
+                if let Some(client) = self.get_client(&id) {
+                    reregister(event_loop, client, id.token(), self.reading_suspended);
+                }
             }
         }
     }
 
+    fn tick(&mut self, event_loop: &mut EventLoop<WebSocketServer>) {
+        self.apply_reregistrations(event_loop);
+
+        self.free_tokens.extend(self.released_tokens.drain(..));
+        self.gate.open();
+
+        if self.shutting_down && self.clients.is_empty() {
+            event_loop.shutdown();
+        }
+    }
+
     fn ready(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token, events: EventSet) {
+        if let Some(&(source_token, _)) = self.sources.get(&token) {
+            self.events.borrow_mut().publish(ConnectionId::server(), WebSocketEvent::SourceReady(source_token, events));
+            return;
+        }
+        if token != SERVER_TOKEN && !self.clients.contains_key(&token) {
+            // The client has been dropped while handling an earlier event.
+            return;
+        }
+
         if events.is_readable() {
             match token {
                 SERVER_TOKEN => {
-                    let client_socket = match self.socket.accept() {
-                        Ok(Some((sock, _))) => sock,
-                        Ok(None) => unreachable!(),
-                        Err(e) => {
-                            error!("Accept error: {}", e);
-                            return;
-                        }
-                    };
-
-                    let tx = self.tx.clone();
-                    let new_token = self.add_client(client_socket, tx, event_loop.channel());
-
-                    event_loop.register(&self.clients[&new_token].socket,
-                                        new_token, EventSet::readable(),
-                                        PollOpt::edge() | PollOpt::oneshot()).unwrap();
+                    if !self.accept_deferred {
+                        self.accept(event_loop);
+                    }
                 },
 	        token => {
-                    let mut client = self.clients.get_mut(&token).unwrap();
-                    client.read();
-                    event_loop.reregister(&client.socket, token, client.interest,
-                                          PollOpt::edge() | PollOpt::oneshot()).unwrap();
+                    if !self.reading_suspended {
+                        self.clients.get_mut(&token).unwrap().read();
+
+                        if self.events.borrow().is_congested() {
+                            trace!("event receivers lag behind, suspending reading");
+                            self.reading_suspended = true;
+                        }
+
+                        self.serve_http(&token);
+                        self.forward_piped(&token);
+                    }
+                    if self.drop_if_closed(event_loop, token) {
+                        return;
+                    }
+                    reregister(event_loop, &self.clients[&token], token, self.reading_suspended);
                 }
             }
         }
 
         if events.is_writable() {
-            let mut client = self.clients.get_mut(&token).unwrap();
-            client.write();
-            event_loop.reregister(&client.socket, token, client.interest,
-                                  PollOpt::edge() | PollOpt::oneshot()).unwrap();
+            let quota = self.write_quota(&token);
+            if quota == 0 {
+                self.wait_for_egress(event_loop, token);
+            }
+
+            let handshake_completed = {
+                let mut client = self.clients.get_mut(&token).unwrap();
+                let was_connected = client.is_connected();
+                let bytes_sent = client.stats().bytes_sent;
+                client.write(quota);
+                if let Some(ref mut egress) = self.egress {
+                    egress.throttle.consume((client.stats().bytes_sent - bytes_sent) as usize);
+                }
+                !was_connected && client.is_connected()
+            };
+            if !self.flush_waiters.is_empty() {
+                self.notify_flushed(&token);
+            }
+            if self.drop_if_closed(event_loop, token) {
+                return;
+            }
+            {
+                let client = &self.clients[&token];
+                reregister(event_loop, client, token, self.reading_suspended);
+                if let Some(delay) = client.throttle_delay_ms() {
+                    if let Err(e) = event_loop.timeout_ms(Timer::Throttle(client.id()), delay) {
+                        error!("Failed to set throttle timeout: {:?}", e);
+                    }
+                }
+            }
+            if handshake_completed {
+                self.resume_session(&token);
+            }
         }
 
-        if events.is_hup() {
+        if events.is_hup() || events.is_error() {
             // Close connection
-            let client = self.remove_client(&token).unwrap();
-            event_loop.deregister(&client.socket);
-            trace!("{:?} hang up connection", token);
+            if let Some(client) = self.clients.get(&token) {
+                trace!("{} hang up connection", client.log_context());
+            }
+            self.drop_client(event_loop, &token);
         }
     }
 }
@@ -0,0 +1,114 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use clock;
+use interface::{ConnectionId, WebSocketMessage};
+
+struct Session {
+    // Current connection of the session, or the last one if the client is away.
+    connection: ConnectionId,
+    attached: bool,
+    // Messages sent while the client was away, waiting to be replayed.
+    pending: VecDeque<WebSocketMessage>,
+    detached_at: Option<Instant>,
+    // Sequence number of the next message sent to the session, if sequence numbers are enabled.
+    next_sequence: u64
+}
+
+/// Sessions that outlive connections. A client presents the session token when it connects,
+/// and gets the messages that have been sent to the session while it was away.
+pub struct SessionStore {
+    sessions: HashMap<String, Session>,
+    // Session tokens by connections, including the last connections of detached sessions.
+    tokens: HashMap<ConnectionId, String>,
+    buffer_size: usize,
+    ttl: Duration
+}
+
+impl SessionStore {
+    pub fn new(buffer_size: usize, ttl: Duration) -> SessionStore {
+        SessionStore {
+            sessions: HashMap::new(),
+            tokens: HashMap::new(),
+            buffer_size: buffer_size,
+            ttl: ttl
+        }
+    }
+
+    /// Binds a session to a new connection, creating the session if necessary.
+    /// Returns the messages to replay and the sequence number to continue with.
+    pub fn attach(&mut self, token: &str, id: ConnectionId) -> (Vec<WebSocketMessage>, u64) {
+        self.expire();
+
+        let session = self.sessions.entry(token.to_string()).or_insert_with(|| Session {
+            connection: id,
+            attached: true,
+            pending: VecDeque::new(),
+            detached_at: None,
+            next_sequence: 1
+        });
+
+        self.tokens.remove(&session.connection);
+        self.tokens.insert(id, token.to_string());
+
+        session.connection = id;
+        session.attached = true;
+        session.detached_at = None;
+        (session.pending.drain(..).collect(), session.next_sequence)
+    }
+
+    /// Marks the session of a closed connection as detached. Messages sent to it are buffered until
+    /// the client reconnects or the session expires.
+    pub fn detach(&mut self, id: ConnectionId, next_sequence: u64) {
+        if let Some(token) = self.tokens.get(&id) {
+            if let Some(session) = self.sessions.get_mut(token) {
+                session.attached = false;
+                session.detached_at = Some(clock::now());
+                session.next_sequence = next_sequence;
+            }
+        }
+        self.expire();
+    }
+
+    pub fn token(&self, id: &ConnectionId) -> Option<&str> {
+        self.tokens.get(id).map(|token| &token[..])
+    }
+
+    /// Returns the connection of an attached session.
+    pub fn connection(&self, token: &str) -> Option<ConnectionId> {
+        self.sessions.get(token).and_then(|session| if session.attached { Some(session.connection) } else { None })
+    }
+
+    /// Buffers a message for a detached session, dropping the oldest message if the buffer is full.
+    /// Returns `false` if there's no such session.
+    pub fn buffer(&mut self, token: &str, msg: WebSocketMessage) -> bool {
+        let buffer_size = self.buffer_size;
+        match self.sessions.get_mut(token) {
+            Some(session) => {
+                if session.pending.len() >= buffer_size {
+                    session.pending.pop_front();
+                }
+                if buffer_size > 0 {
+                    session.pending.push_back(msg);
+                }
+                true
+            },
+            None => false
+        }
+    }
+
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        let expired = self.sessions.iter()
+            .filter(|&(_, session)| session.detached_at.map(|at| clock::elapsed(at) >= ttl).unwrap_or(false))
+            .map(|(token, _)| token.clone())
+            .collect::<Vec<_>>();
+
+        for token in expired {
+            trace!("session {} has expired", token);
+            if let Some(session) = self.sessions.remove(&token) {
+                self.tokens.remove(&session.connection);
+            }
+        }
+    }
+}
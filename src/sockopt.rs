@@ -0,0 +1,56 @@
+/// Tuning sockets beyond what mio exposes
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_int(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    })
+}
+
+/// Sets the size of the kernel receive buffer. Accepted sockets inherit it from the listening socket.
+pub fn set_recv_buffer(fd: RawFd, size: usize) -> io::Result<()> {
+    set_int(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)
+}
+
+/// Sets the size of the kernel send buffer. Accepted sockets inherit it from the listening socket.
+pub fn set_send_buffer(fd: RawFd, size: usize) -> io::Result<()> {
+    set_int(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)
+}
+
+/// Sets the DSCP bits of outgoing packets: the traffic class of IPv6 packets or the type of service of IPv4 ones.
+pub fn set_dscp(fd: RawFd, ipv6: bool, dscp: u8) -> io::Result<()> {
+    // DSCP takes the upper six bits; the lower two are left to ECN.
+    let value = ((dscp & 0x3F) << 2) as libc::c_int;
+    if ipv6 {
+        set_int(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, value)
+    } else {
+        set_int(fd, libc::IPPROTO_IP, libc::IP_TOS, value)
+    }
+}
+
+/// Enables TCP Fast Open on a listening socket: clients that have connected before send data along with SYN.
+/// `queue_len` limits the number of connections that haven't completed the three-way handshake yet.
+/// Linux allows to enable it on a socket that's already listening.
+#[cfg(target_os = "linux")]
+pub fn set_fast_open(fd: RawFd, queue_len: usize) -> io::Result<()> {
+    set_int(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, queue_len as libc::c_int)
+}
+
+/// Sets the length of the queue of connections waiting to be accepted. Listening again on a socket
+/// that's already listening only changes its backlog.
+pub fn set_backlog(fd: RawFd, backlog: usize) -> io::Result<()> {
+    check(unsafe { libc::listen(fd, backlog as libc::c_int) })
+}
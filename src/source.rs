@@ -0,0 +1,23 @@
+/// External evented handles polled by the server's event loop
+
+use std::io;
+use std::sync::Arc;
+
+use mio::{Evented, EventSet, PollOpt, Selector, Token};
+
+/// Registers the handle shared with the application, which keeps using it for reading and writing.
+pub struct SharedSource<E>(pub Arc<E>);
+
+impl<E: Evented> Evented for SharedSource<E> {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
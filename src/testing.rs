@@ -0,0 +1,338 @@
+/// Driving connections in tests without sockets or an event loop thread.
+///
+/// A `TestConnection` runs the same state machine as connections accepted by the server,
+/// on top of an in-memory stream. The test feeds the bytes sent by the client, decides
+/// how many of them every read returns, and inspects the bytes written back and the events delivered.
+/// Faults can be injected into the stream to check how network failures are handled.
+/// `Clock` stops the time on the current thread, so timeouts and rate limits can be tested deterministically.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::usize;
+
+use mio::{Token, Evented, EventSet, PollOpt, Selector};
+
+use bus::EventBus;
+use channel::{self, EventReceiver};
+use client::WebSocketClient;
+use clock;
+use config::Config;
+use record;
+use transport::Transport;
+use interface::{ConnectionId, WebSocketEvent, WebSocketMessage, EventFilter};
+
+/// Upgrade request with the key from RFC 6455.
+const HANDSHAKE_REQUEST: &'static str = "GET / HTTP/1.1\r\n\
+                                             Host: localhost\r\n\
+                                             Upgrade: websocket\r\n\
+                                             Connection: Upgrade\r\n\
+                                             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                                             Sec-WebSocket-Version: 13\r\n\r\n";
+
+/// Network failure injected into a `TestConnection`.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// The next `n` readable events find no data, even though it has arrived.
+    DelayReads(usize),
+    /// The next read returns at most `n` bytes.
+    ShortRead(usize),
+    /// The next read fails.
+    ReadError(io::ErrorKind),
+    /// The next write accepts at most `n` bytes.
+    ShortWrite(usize),
+    /// The next write fails.
+    WriteError(io::ErrorKind),
+    /// The client goes away after sending `n` more bytes of the pending input.
+    Truncate(usize),
+    /// The first two chunks of `n` bytes of the pending input arrive in the reverse order.
+    Reorder(usize)
+}
+
+/// In-memory stream. Reads return the fed bytes, writes are collected.
+pub struct MemoryStream {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    // Largest number of bytes returned by a read or accepted by a write.
+    read_chunk: usize,
+    write_chunk: usize,
+    // The peer has closed its side; reads return 0 once the input is exhausted.
+    eof: bool,
+    read_faults: VecDeque<Fault>,
+    write_faults: VecDeque<Fault>
+}
+
+impl MemoryStream {
+    pub fn new() -> MemoryStream {
+        MemoryStream {
+            input: VecDeque::new(),
+            output: Vec::new(),
+            read_chunk: usize::MAX,
+            write_chunk: usize::MAX,
+            eof: false,
+            read_faults: VecDeque::new(),
+            write_faults: VecDeque::new()
+        }
+    }
+
+    fn inject(&mut self, fault: Fault) {
+        match fault {
+            Fault::DelayReads(0) => {},
+            Fault::DelayReads(..) | Fault::ShortRead(..) | Fault::ReadError(..) => self.read_faults.push_back(fault),
+            Fault::ShortWrite(..) | Fault::WriteError(..) => self.write_faults.push_back(fault),
+            Fault::Truncate(len) => {
+                self.input.truncate(len);
+                self.eof = true;
+            },
+            Fault::Reorder(len) => {
+                if self.input.len() >= len * 2 {
+                    let second: Vec<u8> = self.input.drain(len..len * 2).collect();
+                    for (i, byte) in second.into_iter().enumerate() {
+                        self.input.insert(i, byte);
+                    }
+                }
+            }
+        }
+    }
+
+    fn readable(&self) -> bool {
+        !self.input.is_empty() || self.eof
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut limit = self.read_chunk;
+        match self.read_faults.pop_front() {
+            Some(Fault::DelayReads(n)) => {
+                if n > 1 {
+                    self.read_faults.push_front(Fault::DelayReads(n - 1));
+                }
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "delayed read"));
+            },
+            Some(Fault::ReadError(kind)) => return Err(io::Error::new(kind, "injected read error")),
+            Some(Fault::ShortRead(len)) => limit = cmp::min(limit, cmp::max(len, 1)),
+            _ => {}
+        }
+
+        if self.input.is_empty() {
+            return if self.eof { Ok(0) } else { Err(io::Error::new(io::ErrorKind::WouldBlock, "no input")) };
+        }
+        let len = cmp::min(cmp::min(buf.len(), limit), self.input.len());
+        for (dst, src) in buf.iter_mut().zip(self.input.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut limit = self.write_chunk;
+        match self.write_faults.pop_front() {
+            Some(Fault::WriteError(kind)) => return Err(io::Error::new(kind, "injected write error")),
+            Some(Fault::ShortWrite(len)) => limit = cmp::min(limit, len),
+            _ => {}
+        }
+        let len = cmp::min(buf.len(), limit);
+        self.output.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The stream is never registered with an event loop; `TestConnection` polls it instead.
+impl Evented for MemoryStream {
+    fn register(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _: &mut Selector, _: Token, _: EventSet, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _: &mut Selector) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryStream {
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connection driven by the test. See `WebSocketBuilder::test_connection`.
+pub struct TestConnection {
+    client: WebSocketClient<MemoryStream>,
+    events: EventReceiver,
+    // `run` polls the interest of the connection, so its reregistration requests are only discarded.
+    reregistrations: Rc<RefCell<Vec<ConnectionId>>>
+}
+
+impl TestConnection {
+    pub fn new(config: Config) -> TestConnection {
+        let (tx, rx) = channel::channel();
+        let mut events = EventBus::new(&config);
+        events.subscribe(EventFilter::All, None, tx);
+
+        let peer_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let reregistrations = Rc::new(RefCell::new(Vec::new()));
+        let client = WebSocketClient::new(MemoryStream::new(), peer_addr, ConnectionId::new(Token(1), 0),
+                                          Rc::new(RefCell::new(events)), reregistrations.clone(), Rc::new(config));
+
+        TestConnection {
+            client: client,
+            events: rx,
+            reregistrations: reregistrations
+        }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.client.id()
+    }
+
+    /// Limits the number of bytes returned by every read, to test the handling of partial frames.
+    pub fn set_read_chunk(&mut self, size: usize) {
+        self.client.socket.read_chunk = cmp::max(size, 1);
+    }
+
+    /// Limits the number of bytes accepted by every write.
+    pub fn set_write_chunk(&mut self, size: usize) {
+        self.client.socket.write_chunk = cmp::max(size, 1);
+    }
+
+    /// Injects a fault into the stream. Faults affecting reads and writes are applied in the order
+    /// they've been injected. A delayed read needs another `run` to be retried.
+    pub fn inject(&mut self, fault: Fault) {
+        self.client.socket.inject(fault);
+    }
+
+    /// Passes bytes sent by the client to the connection and runs it until it has nothing to do.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.client.socket.input.extend(data.iter().cloned());
+        self.run();
+    }
+
+    /// Sends the upgrade request and returns the response.
+    pub fn handshake(&mut self) -> Vec<u8> {
+        self.feed(HANDSHAKE_REQUEST.as_bytes());
+        self.take_output()
+    }
+
+    /// Closes the client side of the stream.
+    pub fn close_input(&mut self) {
+        self.client.socket.eof = true;
+        self.run();
+    }
+
+    /// Feeds a recording made with `WebSocketBuilder::record_traffic` chunk by chunk.
+    /// With a clock, it's advanced to the time every chunk has been received at.
+    pub fn replay(&mut self, path: &Path, mut clock: Option<&mut Clock>) -> io::Result<()> {
+        let mut replayed = Duration::from_secs(0);
+        for (at, chunk) in try!(record::read_recording(path)) {
+            if let Some(ref mut clock) = clock {
+                if at > replayed {
+                    clock.advance(at - replayed);
+                    replayed = at;
+                }
+            }
+            self.feed(&chunk);
+        }
+        Ok(())
+    }
+
+    /// Sends a message to the client, as `WsSender::send` does.
+    pub fn send(&mut self, msg: WebSocketMessage) {
+        if let Err(e) = self.client.send_message(msg) {
+            warn!("{:?} failed to send a message: {}", self.client.id(), e);
+        }
+        self.run();
+    }
+
+    /// Switches the connection to the raw mode and back, as `WsSender::set_raw_mode` does.
+    pub fn set_raw_mode(&mut self, enabled: bool) {
+        self.client.raw_mode = enabled;
+    }
+
+    /// Reads and writes as long as the connection is interested in it and makes progress.
+    /// Each round is a separate read or writable event, so limited chunks are seen as separate events too.
+    pub fn run(&mut self) {
+        self.reregistrations.borrow_mut().clear();
+        loop {
+            let interest = self.client.effective_interest();
+            if interest.is_hup() {
+                break;
+            }
+            let before = (self.client.socket.input.len(), self.client.socket.output.len(), interest);
+
+            if interest.is_writable() {
+                self.client.write(usize::MAX);
+            } else if interest.is_readable() && self.client.socket.readable() {
+                self.client.read();
+            }
+
+            let after = (self.client.socket.input.len(), self.client.socket.output.len(),
+                         self.client.effective_interest());
+            if before == after {
+                break;
+            }
+        }
+    }
+
+    /// Takes the bytes written to the client so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.client.socket.output.drain(..).collect()
+    }
+
+    /// Takes the events delivered to the application so far.
+    pub fn events(&mut self) -> Vec<WebSocketEvent> {
+        let mut events = Vec::new();
+        while let Some((_, event)) = self.events.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    /// Checks whether the connection is done and would be removed by the server.
+    pub fn is_closed(&self) -> bool {
+        self.client.is_closed()
+    }
+}
+
+/// Stops the time on the current thread until dropped. Connections driven on the thread see the time
+/// only move when it's advanced.
+pub struct Clock {
+    now: Instant
+}
+
+impl Clock {
+    pub fn stop() -> Clock {
+        let now = Instant::now();
+        clock::stop_at(Some(now));
+        Clock { now: now }
+    }
+
+    pub fn advance(&mut self, duration: Duration) {
+        self.now = self.now + duration;
+        clock::stop_at(Some(self.now));
+    }
+
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+}
+
+impl Drop for Clock {
+    fn drop(&mut self) {
+        clock::stop_at(None);
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::{Duration, Instant};
+
+use clock;
+
+/// Limits the number of bytes written per second. The server uses it for the accept rate as well.
+pub struct Throttle {
+    rate: usize,
+    window_start: Instant,
+    // Bytes written since the start of the current one-second window.
+    written: usize
+}
+
+impl Throttle {
+    pub fn new(rate: usize) -> Throttle {
+        Throttle {
+            rate: rate,
+            window_start: clock::now(),
+            written: 0
+        }
+    }
+
+    pub fn rate(&self) -> usize {
+        self.rate
+    }
+
+    /// Number of bytes that can be written right now.
+    pub fn allowance(&self) -> usize {
+        if clock::elapsed(self.window_start) >= Duration::from_secs(1) {
+            self.rate
+        } else {
+            self.rate.saturating_sub(self.written)
+        }
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        if clock::elapsed(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = clock::now();
+            self.written = 0;
+        }
+        self.written += bytes;
+    }
+
+    /// Time left until the allowance is renewed, in milliseconds.
+    pub fn delay_ms(&self) -> u64 {
+        let elapsed = clock::elapsed(self.window_start);
+        match Duration::from_secs(1).checked_sub(elapsed) {
+            Some(left) => left.as_secs() * 1000 + (left.subsec_nanos() / 1000000) as u64 + 1,
+            None => 0
+        }
+    }
+}
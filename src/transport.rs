@@ -0,0 +1,28 @@
+/// Streams a WebSocket connection can run on top of
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+
+use mio::Evented;
+use mio::tcp::TcpStream;
+#[cfg(unix)]
+use mio::unix::UnixStream;
+
+/// Byte stream carrying a connection: a TCP socket, a Unix domain socket, a TLS stream and so on.
+pub trait Transport: Read + Write + Evented {
+    /// Shuts down the read or write half of the stream. Called after the closing handshake.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
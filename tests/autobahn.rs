@@ -0,0 +1,67 @@
+//! Runs the Autobahn test suite against the strict configuration of the server.
+//! Requires `wstest` (`pip install autobahntestsuite`) in PATH, so it's ignored by default:
+//! run it with `cargo test --test autobahn -- --ignored`.
+//! Cases 12 and 13 test permessage-deflate, which isn't built in, and are excluded.
+
+extern crate mio_websocket;
+extern crate rustc_serialize;
+
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::thread;
+
+use rustc_serialize::json::Json;
+
+use mio_websocket::interface::*;
+
+const CONFIG: &'static str = "examples/fuzzingclient.json";
+const REPORT: &'static str = "target/autobahn/index.json";
+
+fn run_echo_server() {
+    let mut ws = WebSocketBuilder::new("127.0.0.1:9002".parse::<SocketAddr>().unwrap())
+        .strict()
+        .build();
+
+    thread::spawn(move || {
+        loop {
+            match ws.next() {
+                (tkn, WebSocketEvent::TextMessage(text)) => ws.send_text(tkn, &text),
+                (tkn, WebSocketEvent::BinaryMessage(data)) => ws.send_binary(tkn, &data),
+                _ => {}
+            }
+        }
+    });
+}
+
+fn is_passed(behavior: Option<&Json>) -> bool {
+    match behavior.and_then(|behavior| behavior.as_string()) {
+        Some("OK") | Some("NON-STRICT") | Some("INFORMATIONAL") | Some("UNIMPLEMENTED") => true,
+        _ => false
+    }
+}
+
+#[test]
+#[ignore]
+fn autobahn_fuzzingclient() {
+    run_echo_server();
+
+    let status = Command::new("wstest").args(&["-m", "fuzzingclient", "-s", CONFIG]).status()
+        .expect("failed to run wstest");
+    assert!(status.success(), "wstest has failed: {}", status);
+
+    let mut report = String::new();
+    File::open(REPORT).unwrap().read_to_string(&mut report).unwrap();
+    let report = Json::from_str(&report).unwrap();
+
+    let mut failed = Vec::new();
+    for (_, cases) in report.as_object().unwrap() {
+        for (case, result) in cases.as_object().unwrap() {
+            if !is_passed(result.find("behavior")) || !is_passed(result.find("behaviorClose")) {
+                failed.push(case.clone());
+            }
+        }
+    }
+    assert!(failed.is_empty(), "failed cases: {}", failed.join(", "));
+}
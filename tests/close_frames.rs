@@ -0,0 +1,46 @@
+//! Validation of the payloads of Close frames sent by clients (RFC 6455, sections 5.5.1 and 7.4).
+//! Run with `cargo test --features testing`.
+
+#![cfg(feature = "testing")]
+
+extern crate mio_websocket;
+
+mod common;
+
+use mio_websocket::interface::*;
+use common::{close_frame, client_frame, connected};
+
+fn close_output(payload: &[u8]) -> Vec<u8> {
+    let mut conn = connected();
+    conn.feed(&client_frame(OpCode::ConnectionClose, payload));
+    conn.take_output()
+}
+
+#[test]
+fn empty_payload() {
+    assert_eq!(close_output(b""), [0x88, 0x00]);
+}
+
+#[test]
+fn valid_code_and_reason() {
+    assert_eq!(close_output(b"\x03\xE8bye"), close_frame(StatusCode::Normal));
+    assert_eq!(close_output(b"\x0F\xA0"), close_frame(StatusCode::Custom(4000)));
+}
+
+#[test]
+fn truncated_code() {
+    assert_eq!(close_output(b"\x03"), close_frame(StatusCode::ProtocolError));
+}
+
+#[test]
+fn reserved_codes() {
+    for code in &[0u16, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+        let payload = [(code >> 8) as u8, *code as u8];
+        assert_eq!(close_output(&payload), close_frame(StatusCode::ProtocolError), "code {}", code);
+    }
+}
+
+#[test]
+fn invalid_utf8_reason() {
+    assert_eq!(close_output(b"\x03\xE8\xCE\xBA\xE1\xBD\xB9\xED\xA0\x80"), close_frame(StatusCode::ProtocolError));
+}
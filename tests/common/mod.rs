@@ -0,0 +1,50 @@
+//! Fixtures shared by the tests driving `TestConnection`s.
+
+#![allow(dead_code)]
+
+use mio_websocket::interface::*;
+use mio_websocket::testing::TestConnection;
+
+/// Connection with the default settings that hasn't received anything yet.
+pub fn connection() -> TestConnection {
+    WebSocketBuilder::new("127.0.0.1:9002".parse().unwrap()).test_connection()
+}
+
+/// Connection with the default settings that has completed the handshake. The Connect event has been taken.
+pub fn connected() -> TestConnection {
+    let mut conn = connection();
+    conn.handshake();
+    conn.events();
+    conn
+}
+
+/// Close frame with a status code, the way the server sends it.
+pub fn close_frame(status: StatusCode) -> Vec<u8> {
+    let code = status.code();
+    vec![0x88, 0x02, (code >> 8) as u8, code as u8]
+}
+
+/// Serializes a final frame the way clients send them: masked, with a zero mask, so the payload stays as is.
+pub fn client_frame(opcode: OpCode, payload: &[u8]) -> Vec<u8> {
+    client_fragment(opcode, payload, true)
+}
+
+/// Serializes a frame of a fragmented message; the last one has `fin` set. See `client_frame`.
+pub fn client_fragment(opcode: OpCode, payload: &[u8], fin: bool) -> Vec<u8> {
+    let mut frame = vec![((fin as u8) << 7) | opcode.bits()];
+
+    let len = payload.len() as u64;
+    if len < 126 {
+        frame.push(0x80 | len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend((0..8).rev().map(|i| (len >> (i * 8)) as u8));
+    }
+
+    frame.extend_from_slice(&[0, 0, 0, 0]);
+    frame.extend_from_slice(payload);
+    frame
+}
@@ -0,0 +1,126 @@
+//! Fragmented messages with control frames in between their fragments (RFC 6455, section 5.4).
+//! Run with `cargo test --features testing`.
+
+#![cfg(feature = "testing")]
+
+extern crate mio_websocket;
+
+mod common;
+
+use mio_websocket::interface::*;
+use common::{close_frame, client_frame, client_fragment, connected};
+
+fn text_messages(events: &[WebSocketEvent]) -> Vec<String> {
+    events.iter().filter_map(|event| match *event {
+        WebSocketEvent::TextMessage(ref text) => Some(text.clone()),
+        _ => None
+    }).collect()
+}
+
+fn failed(events: &[WebSocketEvent]) -> bool {
+    events.iter().any(|event| match *event {
+        WebSocketEvent::Error(WsError::Protocol(..)) => true,
+        _ => false
+    })
+}
+
+fn ends_with(output: &[u8], tail: &[u8]) -> bool {
+    output.len() >= tail.len() && &output[output.len() - tail.len()..] == tail
+}
+
+#[test]
+fn ping_between_fragments() {
+    let mut conn = connected();
+    conn.feed(&client_fragment(OpCode::TextFrame, b"Hello, ", false));
+    conn.feed(&client_frame(OpCode::Ping, b"ping"));
+    assert_eq!(conn.take_output(), [0x8A, 0x04, b'p', b'i', b'n', b'g']);
+
+    conn.feed(&client_fragment(OpCode::ContinuationFrame, b"fragmented ", false));
+    conn.feed(&client_frame(OpCode::Pong, b""));
+    conn.feed(&client_fragment(OpCode::ContinuationFrame, b"world", true));
+
+    let events = conn.events();
+    assert_eq!(text_messages(&events), ["Hello, fragmented world"]);
+    assert!(!failed(&events));
+    assert!(!conn.is_closed());
+}
+
+#[test]
+fn close_between_fragments() {
+    let mut conn = connected();
+    conn.feed(&client_fragment(OpCode::BinaryFrame, b"abc", false));
+    conn.feed(&client_frame(OpCode::ConnectionClose, &[0x03, 0xE8]));
+
+    assert_eq!(conn.take_output(), close_frame(StatusCode::Normal));
+    let events = conn.events();
+    assert!(events.iter().any(|event| match *event {
+        WebSocketEvent::Close(StatusCode::Normal) => true,
+        _ => false
+    }));
+    assert!(!events.iter().any(|event| match *event {
+        WebSocketEvent::BinaryMessage(..) => true,
+        _ => false
+    }));
+}
+
+#[test]
+fn data_frame_between_fragments() {
+    let mut conn = connected();
+    conn.feed(&client_fragment(OpCode::TextFrame, b"Hello", false));
+    conn.feed(&client_frame(OpCode::TextFrame, b"interleaved"));
+
+    assert!(ends_with(&conn.take_output(), &close_frame(StatusCode::ProtocolError)));
+    let events = conn.events();
+    assert!(failed(&events));
+    assert!(text_messages(&events).is_empty());
+}
+
+#[test]
+fn continuation_without_message() {
+    let mut conn = connected();
+    conn.feed(&client_fragment(OpCode::ContinuationFrame, b"orphan", true));
+
+    assert!(ends_with(&conn.take_output(), &close_frame(StatusCode::ProtocolError)));
+    assert!(failed(&conn.events()));
+}
+
+#[test]
+fn continuation_after_final_fragment() {
+    let mut conn = connected();
+    conn.feed(&client_fragment(OpCode::TextFrame, b"one", false));
+    conn.feed(&client_fragment(OpCode::ContinuationFrame, b"two", true));
+    conn.feed(&client_fragment(OpCode::ContinuationFrame, b"three", true));
+
+    assert!(ends_with(&conn.take_output(), &close_frame(StatusCode::ProtocolError)));
+    let events = conn.events();
+    assert_eq!(text_messages(&events), ["onetwo"]);
+    assert!(failed(&events));
+}
+
+#[test]
+fn fragmented_control_frame() {
+    let mut conn = connected();
+    conn.feed(&client_fragment(OpCode::TextFrame, b"Hello", false));
+    conn.feed(&client_fragment(OpCode::Ping, b"ping", false));
+
+    assert!(ends_with(&conn.take_output(), &close_frame(StatusCode::ProtocolError)));
+    assert!(failed(&conn.events()));
+}
+
+#[test]
+fn fragments_in_raw_mode() {
+    let mut conn = connected();
+    conn.set_raw_mode(true);
+    conn.feed(&client_fragment(OpCode::BinaryFrame, b"abc", false));
+    conn.feed(&client_frame(OpCode::Ping, b""));
+    conn.feed(&client_fragment(OpCode::ContinuationFrame, b"def", true));
+    conn.feed(&client_fragment(OpCode::ContinuationFrame, b"ghi", true));
+
+    let events = conn.events();
+    let frames: Vec<(bool, OpCode)> = events.iter().filter_map(|event| match *event {
+        WebSocketEvent::Frame(ref frame) => Some((frame.fin, frame.opcode)),
+        _ => None
+    }).collect();
+    assert_eq!(frames, [(false, OpCode::BinaryFrame), (true, OpCode::ContinuationFrame)]);
+    assert!(failed(&events));
+}
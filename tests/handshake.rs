@@ -0,0 +1,41 @@
+//! Computation of `Sec-WebSocket-Accept` and validation of `Sec-WebSocket-Key` (RFC 6455, section 4).
+//! Run with `cargo test --features testing`.
+
+#![cfg(feature = "testing")]
+
+extern crate mio_websocket;
+
+mod common;
+
+use common::connection;
+
+fn handshake(key: &str) -> String {
+    let mut conn = connection();
+    conn.feed(format!("GET / HTTP/1.1\r\n\
+                       Host: localhost\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: {}\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n", key).as_bytes());
+    String::from_utf8(conn.take_output()).unwrap()
+}
+
+#[test]
+fn accept_keys() {
+    // Examples from sections 1.2 and 1.3.
+    let response = handshake("dGhlIHNhbXBsZSBub25jZQ==");
+    assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", response);
+
+    let response = handshake("x3JJHMbDL1EzLkh9GBhXDw==");
+    assert!(response.contains("Sec-WebSocket-Accept: HSmrc0sMlYUkAGmm5OPpG2HaGWk=\r\n"), "{}", response);
+}
+
+#[test]
+fn invalid_keys() {
+    for key in &["", "dGhlIHNhbXBsZSBub25jZQ", "dGhlIHNhbXBsZSBub25jZR==", "dGhlIHNhbXBsZSBub25j*Q==",
+                 "dGhlIHNhbXBsZSBub25jZQ==dGhl", "c2FtcGxlIG5vbmNlIG9mIDIwIGJ5dGVz"] {
+        let response = handshake(key);
+        assert!(response.starts_with("HTTP/1.1 400"), "key {:?}: {}", key, response);
+    }
+}